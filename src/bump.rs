@@ -0,0 +1,41 @@
+use crate::rpc::{BitcoinRpc, RPC_INVALID_ADDRESS_OR_KEY};
+use crate::{Error, Result};
+use bitcoin::psbt::Psbt;
+use bitcoin::{Amount, OutPoint, Txid};
+use bitcoincore_rpc::json::GetTransactionResultDetailCategory;
+use bitcoincore_rpc::jsonrpc;
+use std::str::FromStr;
+
+/// Asks the wallet behind `client` for an unsigned replacement of `txid`
+/// paying `new_fee_rate` sat/vB, to be signed by the same wallet.
+///
+/// Purchases spend the seller's inscription input, which the buyer wallet
+/// cannot sign again, so any transaction spending a foreign input is refused
+/// before the node is asked.
+pub fn bump_fee(client: &dyn BitcoinRpc, txid: Txid, new_fee_rate: Amount) -> Result<Psbt> {
+    let tx = client.get_raw_transaction(&txid, None)?;
+    for input in &tx.input {
+        if !is_wallet_output(client, input.previous_output)? {
+            return Err(Error::ForeignInput(txid, input.previous_output));
+        }
+    }
+
+    let bumped = client.psbt_bump_fee(&txid, new_fee_rate)?;
+    Ok(Psbt::from_str(&bumped)?)
+}
+
+/// Whether `outpoint` was received by the wallet behind `client`.
+fn is_wallet_output(client: &dyn BitcoinRpc, outpoint: OutPoint) -> Result<bool> {
+    let wallet_tx = match client.get_transaction(&outpoint.txid, None) {
+        Ok(wallet_tx) => wallet_tx,
+        Err(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(e)))
+            if e.code == RPC_INVALID_ADDRESS_OR_KEY =>
+        {
+            return Ok(false)
+        }
+        Err(e) => return Err(e.into()),
+    };
+    Ok(wallet_tx.details.iter().any(|detail| {
+        detail.vout == outpoint.vout && detail.category != GetTransactionResultDetailCategory::Send
+    }))
+}
@@ -0,0 +1,1259 @@
+use crate::fee::{
+    check_dust, checked_sum, dust_limit, estimate_fee_rate, estimate_vsize, estimate_weight,
+    fee_for_vsize, output_weight, price_for_payout, seller_payout, service_fee_outputs, FeePayer,
+};
+use crate::input::{set_input_utxo, set_key_origin, set_taproot_key, InputKind};
+use crate::selection::select_payment_utxos;
+use crate::signer::process_psbt;
+use crate::utxo::{find_dummy_utxo, get_buyer_spendable_utxos, retrieve_dummy_utxo, SpendableUtxo};
+use crate::verify::{
+    listing_price, validate_seller_listing, verify_sat_offset, verify_seller_signature,
+    SELLER_PAYMENT_OUTPUT,
+};
+use crate::{Error, MarketplaceContext, Result};
+use bitcoin::psbt::Psbt;
+use bitcoin::script::PushBytesBuf;
+use bitcoin::{Address, Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+
+/// Which outputs take the buyer's leftover funds.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum ChangePolicy {
+    /// A fresh dummy for the next purchase followed by change, both to the
+    /// buyer.
+    #[default]
+    CreateNextDummy,
+    /// Change only, to the buyer.
+    SingleChange,
+    /// Change only, to the given address instead of the buyer's.
+    ConsolidateTo(Address),
+    /// A fresh dummy only when the buyer has no spare one besides the dummy
+    /// the purchase spends; otherwise change only, like `SingleChange`.
+    NextDummyIfNone,
+}
+
+/// How the buyer's own inputs and outputs are ordered.
+///
+/// The dummy stays first so its sats and the inscriptions land in output 0,
+/// and every seller input/output pair keeps the index its SINGLE signature
+/// commits to, so sorting only ever moves the payment inputs and the outputs
+/// after the seller pairs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SortPolicy {
+    /// Payment inputs in selection order; service fee, next dummy and change
+    /// outputs in that order.
+    #[default]
+    AsBuilt,
+    /// BIP69 order among the payment inputs, by previous txid then vout, and
+    /// among the outputs after the seller pairs, by value then script. The
+    /// purchase no longer reveals which output is change, but
+    /// [`crate::summary`] can't tell the buyer's outputs apart by position
+    /// either.
+    Bip69,
+}
+
+/// Combines the seller's signed input/output with a dummy input and payment
+/// inputs from the context's buyer wallet, returning the buyer-signed PSBT.
+///
+/// `inscription_utxo`, `inscription_tx_out` and `price` describe the listing
+/// as advertised; the seller PSBT must match them exactly. The inscription
+/// goes to `receive_address`, or the configured receive address if `None`,
+/// whatever address the dummy it is combined with sits at; the next dummy
+/// and change go to the configured dummy and change addresses.
+///
+/// With `rbf` the buyer's own inputs signal replace-by-fee so a stuck purchase
+/// can be bumped; the seller input keeps the sequence its signature commits to.
+/// `change_policy` decides which outputs follow the service fee; change below
+/// the context's `min_change` or the dust limit is left to the miner. `sort`
+/// decides the order of the buyer's payment inputs and outputs.
+#[allow(clippy::too_many_arguments)]
+pub fn create_buyer_psbt(
+    ctx: &MarketplaceContext,
+    seller_psbt: Psbt,
+    inscription_utxo: OutPoint,
+    inscription_tx_out: TxOut,
+    price: Amount,
+    receive_address: Option<Address>,
+    rbf: bool,
+    change_policy: ChangePolicy,
+    sort: SortPolicy,
+) -> Result<Psbt> {
+    validate_seller_listing(
+        &seller_psbt,
+        inscription_utxo,
+        &inscription_tx_out,
+        seller_payout(&ctx.config, price)?,
+    )?;
+    create_buyer_psbt_multi(
+        ctx,
+        vec![(seller_psbt, inscription_tx_out)],
+        receive_address,
+        rbf,
+        change_policy,
+        sort,
+    )
+}
+
+/// Buys every listing in `seller_psbts` in one transaction. Each entry is a
+/// single-inscription seller PSBT with the inscription output it spends; the
+/// inscription and price are taken from the PSBT itself, so callers must
+/// check them against the advertised listings first.
+///
+/// Seller input `i` is placed at index `i + 1`, right behind the dummy, and
+/// its payment output at the same index, which is what its SINGLE signature
+/// commits to. Output 0 therefore receives the dummy and all inscriptions
+/// together, in listing order, topped up to the context's `postage` if set.
+/// Royalty input/output pairs follow the inscriptions', again at matching
+/// indices. A bundle of several inscriptions is bought by passing the
+/// listings [`SellerPsbts::into_listings`](crate::seller::SellerPsbts::into_listings)
+/// splits it into. `receive_address`, `rbf`, `change_policy` and `sort` are
+/// handled as in [`create_buyer_psbt`].
+pub fn create_buyer_psbt_multi(
+    ctx: &MarketplaceContext,
+    seller_psbts: Vec<(Psbt, TxOut)>,
+    receive_address: Option<Address>,
+    rbf: bool,
+    change_policy: ChangePolicy,
+    sort: SortPolicy,
+) -> Result<Psbt> {
+    if seller_psbts.is_empty() {
+        return Err(Error::SellerPsbtMismatch(
+            "no seller psbts to combine".to_string(),
+        ));
+    }
+    for (seller_psbt, inscription_tx_out) in &seller_psbts {
+        let tx = &seller_psbt.unsigned_tx;
+        let (inscription_utxo, payout) =
+            match (tx.input.first(), tx.output.get(SELLER_PAYMENT_OUTPUT)) {
+                (Some(input), Some(output)) => (input.previous_output, output.value),
+                _ => {
+                    return Err(Error::SellerPsbtMismatch(
+                        "seller psbt has no input or output".to_string(),
+                    ))
+                }
+            };
+        validate_seller_listing(
+            seller_psbt,
+            inscription_utxo,
+            inscription_tx_out,
+            listing_price(seller_psbt)?,
+        )?;
+        verify_seller_signature(
+            seller_psbt,
+            &TxOut {
+                value: payout,
+                script_pubkey: inscription_tx_out.script_pubkey.clone(),
+            },
+        )?;
+    }
+    // the seller signatures commit to the locktime, so all listings need the
+    // same one and the purchase has to keep it
+    let lock_time = seller_psbts[0].0.unsigned_tx.lock_time;
+    if seller_psbts
+        .iter()
+        .any(|(seller_psbt, _)| seller_psbt.unsigned_tx.lock_time != lock_time)
+    {
+        return Err(Error::SellerPsbtMismatch(
+            "seller psbts use different locktimes".to_string(),
+        ));
+    }
+    let listings = seller_psbts.len();
+    let seller_inputs = seller_psbts
+        .iter()
+        .map(|(seller_psbt, _)| seller_psbt.inputs.len())
+        .sum::<usize>();
+    let listing_prices = seller_psbts
+        .iter()
+        .map(|(seller_psbt, _)| listing_price(seller_psbt))
+        .collect::<Result<Vec<_>>>()?;
+    let price = checked_sum(listing_prices.iter().copied())?;
+    // a seller-paid fee was taken out of the seller payments, so it is
+    // charged on the prices they were cut from and costs the buyer nothing
+    // on top
+    let sale_prices = match ctx.config.fee_payer {
+        FeePayer::Buyer => listing_prices.clone(),
+        FeePayer::Seller => listing_prices
+            .iter()
+            .map(|&payout| price_for_payout(&ctx.config, payout))
+            .collect::<Result<Vec<_>>>()?,
+    };
+    let service_fee_outputs = service_fee_outputs(&ctx.config, &sale_prices)?;
+    let service_fee = checked_sum(
+        service_fee_outputs
+            .iter()
+            .map(|output| Amount::from_sat(output.value)),
+    )?;
+    let next_dummy_value = match change_policy {
+        ChangePolicy::CreateNextDummy | ChangePolicy::NextDummyIfNone => ctx.dummy_value,
+        ChangePolicy::SingleChange | ChangePolicy::ConsolidateTo(_) => Amount::ZERO,
+    };
+
+    // the mining fee isn't known before the inputs are, nor any postage top-up
+    // before the dummy is, but a balance short of the rest can be turned
+    // down before a dummy is split off
+    let balance = ctx
+        .retry
+        .run(|| ctx.buyer_node.get_balance(Some(ctx.buyer_min_conf), None))?;
+    let required = checked_sum([price, service_fee, next_dummy_value])?;
+    if balance < required {
+        return Err(Error::insufficient_funds(required, balance));
+    }
+    check_max_total(ctx, price, service_fee, Amount::ZERO)?;
+
+    let sorted_spendable_utxos = get_buyer_spendable_utxos(ctx)?;
+
+    if sorted_spendable_utxos.is_empty() {
+        return Err(Error::NoSpendableUtxos);
+    }
+
+    let dummy_utxo = retrieve_dummy_utxo(ctx, &sorted_spendable_utxos)?;
+    // a dummy just split off spent one of the listed UTXOs, which must not
+    // be spent again as payment
+    let dummy_tx = ctx.buyer_transaction(dummy_utxo.outpoint.txid)?;
+    let dummy_tx_inputs = dummy_tx
+        .input
+        .iter()
+        .map(|input| input.previous_output)
+        .collect::<HashSet<_>>();
+    // a dummy left over from earlier purchases makes the next one redundant
+    let spare_dummy = match change_policy {
+        ChangePolicy::NextDummyIfNone => {
+            let others = sorted_spendable_utxos
+                .iter()
+                .filter(|utxo| utxo.outpoint != dummy_utxo.outpoint)
+                .cloned()
+                .collect::<Vec<_>>();
+            find_dummy_utxo(ctx, &others)
+        }
+        _ => None,
+    };
+    if let Some(spare) = &spare_dummy {
+        log::debug!("skipping the next dummy spare_dummy={}", spare.outpoint);
+    }
+    let next_dummy_value = if spare_dummy.is_some() {
+        Amount::ZERO
+    } else {
+        next_dummy_value
+    };
+    let buyer_sequence = if rbf {
+        Sequence::ENABLE_RBF_NO_LOCKTIME
+    } else {
+        Sequence::MAX
+    };
+
+    let inscriptions_value = checked_sum(
+        seller_psbts
+            .iter()
+            .map(|(_, inscription_tx_out)| Amount::from_sat(inscription_tx_out.value)),
+    )?;
+    // the dummy and the inscriptions make up the postage unless more is
+    // asked for, which the payment inputs top up
+    let collected = checked_sum([dummy_utxo.amount, inscriptions_value])?;
+    let postage = ctx.postage.unwrap_or(collected);
+    if postage < collected {
+        return Err(Error::PostageTooLow {
+            postage,
+            minimum: collected,
+        });
+    }
+    let postage_top_up = postage - collected;
+
+    // everything the buyer pays for, the one total all funds checks use
+    let total_cost = |mining_fee: Amount| {
+        checked_sum([
+            price,
+            service_fee,
+            next_dummy_value,
+            postage_top_up,
+            mining_fee,
+        ])
+    };
+
+    let mut purchase_tx = Transaction {
+        version: 2,
+        lock_time,
+        input: vec![TxIn {
+            previous_output: dummy_utxo.outpoint,
+            script_sig: ScriptBuf::new(),
+            sequence: buyer_sequence,
+            witness: Witness::default(),
+        }],
+
+        // the inscription carrier, see `INSCRIPTION_CARRIER_OUTPUT`; each
+        // seller's payment follows at the index of the inscription it sells
+        output: vec![TxOut {
+            value: postage.to_sat(),
+            script_pubkey: receive_address
+                .as_ref()
+                .unwrap_or(&ctx.config.receive_address)
+                .script_pubkey(),
+        }],
+    };
+    let mut input_kinds = vec![InputKind::from_script(&dummy_utxo.script_pubkey)];
+    for (seller_psbt, inscription_tx_out) in &seller_psbts {
+        let seller_input = &seller_psbt.unsigned_tx.input[0];
+        // the seller's script_sig or witness lives in the PSBT input, either
+        // as signatures or already finalized, and is rebuilt from there when
+        // the purchase is finalized
+        purchase_tx.input.push(TxIn {
+            previous_output: seller_input.previous_output,
+            script_sig: ScriptBuf::new(),
+            sequence: seller_input.sequence,
+            witness: Witness::default(),
+        });
+        purchase_tx
+            .output
+            .push(seller_psbt.unsigned_tx.output[SELLER_PAYMENT_OUTPUT].clone());
+        input_kinds.push(InputKind::from_script(&inscription_tx_out.script_pubkey));
+    }
+    for (seller_psbt, _) in &seller_psbts {
+        for (i, seller_input) in seller_psbt.unsigned_tx.input.iter().enumerate().skip(1) {
+            let funding = seller_psbt
+                .spend_utxo(i)
+                .map_err(|_| Error::MissingOutput(seller_input.previous_output))?;
+            purchase_tx.input.push(TxIn {
+                previous_output: seller_input.previous_output,
+                script_sig: ScriptBuf::new(),
+                sequence: seller_input.sequence,
+                witness: Witness::default(),
+            });
+            purchase_tx
+                .output
+                .push(seller_psbt.unsigned_tx.output[i].clone());
+            input_kinds.push(InputKind::from_script(&funding.script_pubkey));
+        }
+    }
+
+    purchase_tx.output.extend(service_fee_outputs);
+    // behind every seller payment, so no SINGLE signature's output moves
+    if let Some(memo) = &ctx.memo {
+        add_op_return(&mut purchase_tx, memo)?;
+    }
+
+    if next_dummy_value > Amount::ZERO {
+        let next_dummy_output = TxOut {
+            value: next_dummy_value.to_sat(),
+            script_pubkey: ctx.config.dummy_address.script_pubkey(),
+        };
+        check_dust(&next_dummy_output)?;
+        purchase_tx.output.push(next_dummy_output);
+    }
+    let change_script = match &change_policy {
+        ChangePolicy::ConsolidateTo(address) => address.script_pubkey(),
+        ChangePolicy::CreateNextDummy
+        | ChangePolicy::SingleChange
+        | ChangePolicy::NextDummyIfNone => ctx.config.change_address.script_pubkey(),
+    };
+
+    // payment
+    let fee_rate = estimate_fee_rate(ctx.buyer_node.as_ref(), &ctx.fee)?;
+    let fixed_fee = fee_for_vsize(fee_rate, estimate_vsize(&purchase_tx, &input_kinds))?;
+    let payment_candidates = sorted_spendable_utxos
+        .iter()
+        .filter(|utxo| utxo.outpoint != dummy_utxo.outpoint)
+        .filter(|utxo| spare_dummy.as_ref() != Some(utxo))
+        .filter(|utxo| !dummy_tx_inputs.contains(&utxo.outpoint))
+        .filter(|utxo| !ctx.is_simulated_spent(utxo.outpoint))
+        .cloned()
+        .collect::<Vec<_>>();
+    // the payment inputs get whatever weight the rest, change included, leaves
+    let max_input_weight = ctx.max_tx_weight.saturating_sub(
+        estimate_weight(&purchase_tx, &input_kinds) + output_weight(&change_script),
+    );
+    let mut selected_payment_utxos = select_payment_utxos(
+        &payment_candidates,
+        total_cost(fixed_fee)?,
+        fee_rate,
+        max_input_weight,
+        ctx.coin_selection,
+    )?;
+
+    if sort == SortPolicy::Bip69 {
+        // BIP69 compares txids in their displayed, byte-reversed form
+        selected_payment_utxos
+            .sort_by_key(|utxo| (utxo.outpoint.txid.to_string(), utxo.outpoint.vout));
+    }
+
+    let mut payment_utxos_value = Amount::ZERO;
+    for utxo in &selected_payment_utxos {
+        purchase_tx.input.push(TxIn {
+            previous_output: utxo.outpoint,
+            script_sig: ScriptBuf::new(),
+            sequence: buyer_sequence,
+            witness: Witness::default(),
+        });
+        input_kinds.push(InputKind::from_script(&utxo.script_pubkey));
+        payment_utxos_value = checked_sum([payment_utxos_value, utxo.amount])?;
+    }
+
+    // change goes last; it is dropped and the excess left to the miner when
+    // it would be dust or below `min_change`, e.g. after a changeless selection
+    purchase_tx.output.push(TxOut {
+        value: 0,
+        script_pubkey: change_script.clone(),
+    });
+    let mining_fee = fee_for_vsize(fee_rate, estimate_vsize(&purchase_tx, &input_kinds))?;
+    let mining_fee = match payment_utxos_value.checked_sub(total_cost(mining_fee)?) {
+        Some(change) if change >= dust_limit(&change_script).max(ctx.min_change) => {
+            purchase_tx
+                .output
+                .last_mut()
+                .expect("change output was pushed")
+                .value = change.to_sat();
+            mining_fee
+        }
+        _ => {
+            log::debug!("dropping small change, leaving the excess to the miner");
+            purchase_tx.output.pop();
+            let mining_fee = fee_for_vsize(fee_rate, estimate_vsize(&purchase_tx, &input_kinds))?;
+            let required = total_cost(mining_fee)?;
+            if payment_utxos_value < required {
+                return Err(Error::insufficient_funds(required, payment_utxos_value));
+            }
+            // the surplus raises the fee rate past the estimate
+            let mining_fee = payment_utxos_value - total_cost(Amount::ZERO)?;
+            ctx.fee
+                .check_fee(mining_fee, estimate_vsize(&purchase_tx, &input_kinds))?;
+            mining_fee
+        }
+    };
+    check_max_total(ctx, price, service_fee, mining_fee)?;
+
+    if sort == SortPolicy::Bip69 {
+        purchase_tx.output[seller_inputs + 1..].sort_by(|a, b| {
+            (a.value, a.script_pubkey.as_bytes()).cmp(&(b.value, b.script_pubkey.as_bytes()))
+        });
+    }
+
+    // where in its output an inscription sits isn't known, so the first and
+    // last sat of every inscription output, and all between, must reach the
+    // buyer
+    let prevouts = std::iter::once(TxOut {
+        value: dummy_utxo.amount.to_sat(),
+        script_pubkey: dummy_utxo.script_pubkey.clone(),
+    })
+    .chain(
+        seller_psbts
+            .iter()
+            .map(|(_, inscription_tx_out)| inscription_tx_out.clone()),
+    )
+    .collect::<Vec<_>>();
+    for (i, (_, inscription_tx_out)) in seller_psbts.iter().enumerate() {
+        verify_sat_offset(&purchase_tx, &prevouts, i + 1, 0)?;
+        verify_sat_offset(
+            &purchase_tx,
+            &prevouts,
+            i + 1,
+            inscription_tx_out.value.saturating_sub(1),
+        )?;
+    }
+
+    check_no_inscriptions_spent(ctx, &dummy_utxo, &selected_payment_utxos)?;
+
+    let mut buyer_psbt = Psbt::from_unsigned_tx(purchase_tx.clone())?;
+    for (seller_psbt, _) in &seller_psbts {
+        combine_seller_psbt(&mut buyer_psbt, seller_psbt)?;
+    }
+
+    // the dummy goes first, payment inputs follow the sellers'
+    let buyer_inputs = std::iter::once((0, &dummy_utxo))
+        .chain((seller_inputs + 1..).zip(selected_payment_utxos.iter()))
+        .collect::<Vec<_>>();
+    // several inputs can come from the same transaction, fetch each once
+    let mut prev_txs = HashMap::from([(dummy_utxo.outpoint.txid, dummy_tx)]);
+    for (_, utxo) in &buyer_inputs {
+        if let Entry::Vacant(entry) = prev_txs.entry(utxo.outpoint.txid) {
+            entry.insert(ctx.buyer_transaction(utxo.outpoint.txid)?);
+        }
+    }
+    for (i, utxo) in buyer_inputs {
+        set_input_utxo(
+            &mut buyer_psbt.inputs[i],
+            &prev_txs[&utxo.outpoint.txid],
+            utxo.outpoint.vout,
+            input_kinds[i],
+        );
+        // carried into the PSBT the wallet or an external signer signs, so
+        // neither has to derive the keys again
+        if input_kinds[i] == InputKind::P2tr {
+            set_taproot_key(
+                ctx.buyer_node.as_ref(),
+                &mut buyer_psbt.inputs[i],
+                &utxo.script_pubkey,
+                ctx.config.network,
+            )?;
+        } else {
+            set_key_origin(
+                ctx.buyer_node.as_ref(),
+                &mut buyer_psbt.inputs[i],
+                &utxo.script_pubkey,
+                ctx.config.network,
+            )?;
+        }
+    }
+
+    let (buyer_psbt, _) = process_psbt(ctx, ctx.buyer_node.as_ref(), &buyer_psbt, None)?;
+    log::info!(
+        "built buyer psbt listings={} price={} service_fee={} inputs={} outputs={} fee_rate={} signed={}",
+        listings,
+        price.to_sat(),
+        service_fee.to_sat(),
+        buyer_psbt.inputs.len(),
+        buyer_psbt.outputs.len(),
+        fee_rate.to_sat(),
+        !ctx.no_sign
+    );
+    Ok(buyer_psbt)
+}
+
+/// Fails with [`Error::ExceedsMaxTotal`] if `price`, `service_fee` and
+/// `mining_fee` add up to more than the context's `max_total`, if one is set.
+fn check_max_total(
+    ctx: &MarketplaceContext,
+    price: Amount,
+    service_fee: Amount,
+    mining_fee: Amount,
+) -> Result<()> {
+    let Some(max) = ctx.max_total else {
+        return Ok(());
+    };
+    let total = checked_sum([price, service_fee, mining_fee])?;
+    if total > max {
+        return Err(Error::ExceedsMaxTotal { total, max });
+    }
+    Ok(())
+}
+
+/// Folds the seller's inputs and their paired outputs into `buyer_psbt`, at
+/// the indices where the purchase spends the seller's UTXOs, so every field
+/// the seller filled in (signatures, key origins, proprietary data) is kept.
+/// Fails unless each seller output sits at the same index as its input.
+fn combine_seller_psbt(buyer_psbt: &mut Psbt, seller_psbt: &Psbt) -> Result<()> {
+    let indices = seller_psbt
+        .unsigned_tx
+        .input
+        .iter()
+        .map(|seller_input| {
+            buyer_psbt
+                .unsigned_tx
+                .input
+                .iter()
+                .position(|txin| txin.previous_output == seller_input.previous_output)
+                .ok_or(Error::MissingOutput(seller_input.previous_output))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    // a SINGLE signature commits to the output at its input's index, so a
+    // misplaced pair would leave the seller's signature invalid
+    for (i, &index) in indices.iter().enumerate() {
+        let seller_output = seller_psbt.unsigned_tx.output.get(i);
+        if seller_output.is_none() || buyer_psbt.unsigned_tx.output.get(index) != seller_output {
+            return Err(Error::SellerPsbtMismatch(format!(
+                "seller input {} is at purchase index {} but its output is not",
+                i, index
+            )));
+        }
+    }
+
+    // `Psbt::combine` only merges PSBTs of the same transaction, so restate
+    // the seller's maps against the purchase
+    let mut seller_part = Psbt::from_unsigned_tx(buyer_psbt.unsigned_tx.clone())?;
+    seller_part.version = seller_psbt.version;
+    seller_part.xpub = seller_psbt.xpub.clone();
+    seller_part.proprietary = seller_psbt.proprietary.clone();
+    seller_part.unknown = seller_psbt.unknown.clone();
+    for (i, &index) in indices.iter().enumerate() {
+        seller_part.inputs[index] = seller_psbt.inputs[i].clone();
+        seller_part.outputs[index] = seller_psbt.outputs[i].clone();
+    }
+    buyer_psbt.combine(seller_part)?;
+
+    // `Input::combine` leaves the sighash type out, but the seller signed
+    // with a specific one the finalizer needs
+    for (i, &index) in indices.iter().enumerate() {
+        buyer_psbt.inputs[index].sighash_type = seller_psbt.inputs[i].sighash_type;
+    }
+    Ok(())
+}
+
+/// Largest OP_RETURN payload nodes relay by default.
+pub const MAX_OP_RETURN_DATA: usize = 80;
+
+/// Appends a zero-value OP_RETURN output carrying `data`, at most
+/// [`MAX_OP_RETURN_DATA`] bytes. Nodes relay only one such output per
+/// transaction, so `tx` must not have one yet. Outputs are appended, so call
+/// it once the seller payments are in place.
+pub fn add_op_return(tx: &mut Transaction, data: &[u8]) -> Result<()> {
+    if data.len() > MAX_OP_RETURN_DATA {
+        return Err(Error::InvalidMemo("longer than 80 bytes"));
+    }
+    if tx
+        .output
+        .iter()
+        .any(|output| output.script_pubkey.is_op_return())
+    {
+        return Err(Error::InvalidMemo(
+            "transaction already has an OP_RETURN output",
+        ));
+    }
+    let data = PushBytesBuf::try_from(data.to_vec()).expect("80 bytes fit in a push");
+    tx.output.push(TxOut {
+        value: 0,
+        script_pubkey: ScriptBuf::new_op_return(&data),
+    });
+    Ok(())
+}
+
+/// Asks the inscription index again, bypassing the cache, whether the dummy or any payment
+/// input carries an inscription, since spending one as padding or payment
+/// would hand it to the seller or the miner.
+fn check_no_inscriptions_spent(
+    ctx: &MarketplaceContext,
+    dummy_utxo: &SpendableUtxo,
+    payment_utxos: &[SpendableUtxo],
+) -> Result<()> {
+    // a dummy only a dry run created isn't known to ord
+    let buyer_outpoints = std::iter::once(dummy_utxo)
+        .chain(payment_utxos)
+        .filter(|utxo| !ctx.is_simulated(utxo.outpoint.txid))
+        .map(|utxo| utxo.outpoint)
+        .collect::<Vec<_>>();
+    match ctx
+        .inscription_index
+        .inscription_outputs(&buyer_outpoints)?
+        .into_iter()
+        .next()
+    {
+        Some(outpoint) => Err(Error::InscriptionSpent(outpoint)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fee::parse_fee_splits;
+    use crate::rpc::mock::{
+        config, funding_tx, key, p2wpkh, Market, INSCRIPTION_VALUE, NETWORK, PRICE,
+    };
+    use crate::verify::INSCRIPTION_CARRIER_OUTPUT;
+
+    #[test]
+    fn builds_purchase_without_a_node() {
+        let market = Market::new();
+        let listing = market.list();
+        let buyer_psbt = market.buy(listing.clone()).unwrap();
+
+        let tx = &buyer_psbt.unsigned_tx;
+        assert_eq!(tx.input.len(), 3);
+        assert_eq!(tx.input[1].previous_output, market.inscription_utxo);
+        let config = &market.ctx.config;
+        assert_eq!(
+            tx.output[INSCRIPTION_CARRIER_OUTPUT],
+            TxOut {
+                value: market.ctx.dummy_value.to_sat() + INSCRIPTION_VALUE,
+                script_pubkey: config.receive_address.script_pubkey(),
+            }
+        );
+        assert_eq!(tx.output[1], listing.0.unsigned_tx.output[0]);
+        assert_eq!(tx.output[1].value, PRICE.to_sat());
+        assert_eq!(
+            tx.output[2].script_pubkey,
+            config.marketplace_address.script_pubkey()
+        );
+        assert_eq!(tx.output[3].value, market.ctx.dummy_value.to_sat());
+        assert_eq!(
+            tx.output[4].script_pubkey,
+            config.change_address.script_pubkey()
+        );
+        // the seller's signature and every buyer prevout came along
+        assert_eq!(buyer_psbt.inputs[1].partial_sigs.len(), 1);
+        assert!(buyer_psbt
+            .inputs
+            .iter()
+            .all(|input| input.witness_utxo.is_some()));
+        assert_eq!(market.buyer.calls("walletprocesspsbt"), 1);
+    }
+
+    #[test]
+    fn rejects_listing_for_another_price() {
+        let market = Market::new();
+        let (seller_psbt, inscription_output) = market.list();
+        let result = create_buyer_psbt(
+            &market.ctx,
+            seller_psbt,
+            market.inscription_utxo,
+            inscription_output,
+            PRICE - Amount::from_sat(1),
+            None,
+            false,
+            ChangePolicy::default(),
+            SortPolicy::default(),
+        );
+        assert!(matches!(result, Err(Error::SellerPsbtMismatch(_))));
+    }
+
+    /// The payment UTXO value that covers the purchase with nothing left
+    /// over, and the purchase such a UTXO leads to.
+    fn exact_payment(market: &Market) -> (u64, Transaction) {
+        let mut without_change = market.buy(market.list()).unwrap().unsigned_tx;
+        without_change.output.pop();
+        let mining_fee = fee_for_vsize(
+            Amount::from_sat(1),
+            estimate_vsize(&without_change, &[InputKind::P2wpkh; 3]),
+        )
+        .unwrap();
+        // everything but what the dummy and the inscription pay for output 0
+        let required = without_change
+            .output
+            .iter()
+            .map(|output| output.value)
+            .sum::<u64>()
+            + mining_fee.to_sat()
+            - market.ctx.dummy_value.to_sat()
+            - INSCRIPTION_VALUE;
+        (required, without_change)
+    }
+
+    #[test]
+    fn exact_funds_leave_no_change() {
+        let market = Market::new();
+        let (required, without_change) = exact_payment(&market);
+
+        market.clear_payment_utxos();
+        market.fund_buyer(20, required, 6);
+        let tx = market.buy(market.list()).unwrap().unsigned_tx;
+        assert_eq!(tx.output, without_change.output);
+        assert!(tx
+            .output
+            .iter()
+            .all(|output| Amount::from_sat(output.value) >= dust_limit(&output.script_pubkey)));
+
+        market.clear_payment_utxos();
+        market.fund_buyer(21, required - 1, 6);
+        assert!(matches!(
+            market.buy(market.list()),
+            Err(Error::InsufficientFunds { .. })
+        ));
+    }
+
+    #[test]
+    fn rbf_marks_only_the_buyer_inputs() {
+        let market = Market::new();
+        let (seller_psbt, inscription_output) = market.list();
+        let buyer_psbt = create_buyer_psbt(
+            &market.ctx,
+            seller_psbt.clone(),
+            market.inscription_utxo,
+            inscription_output,
+            PRICE,
+            None,
+            true,
+            ChangePolicy::default(),
+            SortPolicy::default(),
+        )
+        .unwrap();
+
+        let tx = &buyer_psbt.unsigned_tx;
+        assert_eq!(tx.input[1], seller_psbt.unsigned_tx.input[0]);
+        assert_eq!(buyer_psbt.inputs[1], seller_psbt.inputs[0]);
+        for (i, input) in tx.input.iter().enumerate() {
+            if i != 1 {
+                assert_eq!(input.sequence, Sequence::ENABLE_RBF_NO_LOCKTIME);
+            }
+        }
+    }
+
+    #[test]
+    fn configured_dummy_value_is_used_throughout() {
+        let mut market = Market::new();
+        let dummy_value = Amount::from_sat(600);
+        market.ctx.dummy_value = dummy_value;
+        // the split's change is unconfirmed, so the payment needs another
+        market.fund_buyer(10, 100_000, 6);
+        let tx = market.buy(market.list()).unwrap().unsigned_tx;
+
+        // the 1000 sat dummy is too large, so a 600 sat one was split off
+        let sent = market.buyer.state().sent.clone();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].output[0].value, dummy_value.to_sat());
+        assert_eq!(
+            tx.input[0].previous_output,
+            OutPoint::new(sent[0].txid(), 0)
+        );
+        assert_eq!(
+            tx.output[INSCRIPTION_CARRIER_OUTPUT].value,
+            dummy_value.to_sat() + INSCRIPTION_VALUE
+        );
+        assert_eq!(tx.output[3].value, dummy_value.to_sat());
+    }
+
+    #[test]
+    fn seller_signature_survives_the_combine() {
+        let market = Market::new();
+        let listing = market.list();
+        let seller_input = listing.0.inputs[0].clone();
+        let buyer_psbt = market.buy(listing).unwrap();
+
+        assert!(!seller_input.partial_sigs.is_empty());
+        assert_eq!(buyer_psbt.inputs[1].partial_sigs, seller_input.partial_sigs);
+        assert_eq!(buyer_psbt.inputs[1].sighash_type, seller_input.sighash_type);
+    }
+
+    #[test]
+    fn change_below_min_change_goes_to_the_miner() {
+        let mut market = Market::new();
+        let (required, without_change) = exact_payment(&market);
+        // 400 sats of change once its own output is paid for
+        let change_output_fee =
+            output_weight(&market.ctx.config.change_address.script_pubkey()) / 4;
+        market.clear_payment_utxos();
+        market.fund_buyer(20, required + change_output_fee as u64 + 400, 6);
+
+        let tx = market.buy(market.list()).unwrap().unsigned_tx;
+        assert_eq!(tx.output.len(), without_change.output.len() + 1);
+        assert_eq!(tx.output.last().unwrap().value, 400);
+
+        market.ctx.min_change = Amount::from_sat(500);
+        let tx = market.buy(market.list()).unwrap().unsigned_tx;
+        assert_eq!(tx.output, without_change.output);
+    }
+
+    #[test]
+    fn inputs_sharing_a_txid_fetch_it_once() {
+        let market = Market::new();
+        market.buyer.state().unspent.clear();
+        let buyer_address = &market.ctx.config.buyer_address;
+        let tx = funding_tx(10, &[(1000, buyer_address), (100_000, buyer_address)]);
+        market.buyer.add_unspent(&tx, 0, 6);
+        market.buyer.add_unspent(&tx, 1, 6);
+
+        let purchase = market.buy(market.list()).unwrap().unsigned_tx;
+        assert_eq!(purchase.input[0].previous_output.txid, tx.txid());
+        assert_eq!(purchase.input[2].previous_output.txid, tx.txid());
+        assert_eq!(market.buyer.calls("getrawtransaction"), 1);
+    }
+
+    #[test]
+    fn every_fee_split_gets_its_output() {
+        let mut config = config();
+        let splits = format!("{}:500bps,{}:600", p2wpkh(4), p2wpkh(5));
+        config.fee_splits = Some(parse_fee_splits(&splits, NETWORK).unwrap());
+        let market = Market::with_config(config);
+
+        let tx = market.buy(market.list()).unwrap().unsigned_tx;
+        assert_eq!(
+            tx.output[2..4],
+            [
+                TxOut {
+                    value: 2_500,
+                    script_pubkey: p2wpkh(4).script_pubkey(),
+                },
+                TxOut {
+                    value: 600,
+                    script_pubkey: p2wpkh(5).script_pubkey(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn bip69_sort_keeps_the_seller_signature_valid() {
+        let market = Market::new();
+        market.clear_payment_utxos();
+        for seed in 20..23 {
+            market.fund_buyer(seed, 25_000, 6);
+        }
+        let (seller_psbt, inscription_output) = market.list();
+        let seller_output = seller_psbt.unsigned_tx.output[SELLER_PAYMENT_OUTPUT].clone();
+        let psbt = create_buyer_psbt(
+            &market.ctx,
+            seller_psbt,
+            market.inscription_utxo,
+            inscription_output,
+            PRICE,
+            None,
+            false,
+            ChangePolicy::default(),
+            SortPolicy::Bip69,
+        )
+        .unwrap();
+        let tx = &psbt.unsigned_tx;
+
+        let payment_inputs = tx.input[2..]
+            .iter()
+            .map(|input| {
+                (
+                    input.previous_output.txid.to_string(),
+                    input.previous_output.vout,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(payment_inputs.len(), 3);
+        assert!(payment_inputs.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(tx.output[2..].windows(2).all(|pair| (
+            pair[0].value,
+            pair[0].script_pubkey.as_bytes()
+        ) <= (
+            pair[1].value,
+            pair[1].script_pubkey.as_bytes()
+        )));
+
+        assert_eq!(tx.output[1], seller_output);
+        let mut cache = bitcoin::sighash::SighashCache::new(tx);
+        let (msg, _) = psbt.sighash_ecdsa(1, &mut cache).unwrap();
+        let secp = bitcoin::secp256k1::Secp256k1::verification_only();
+        assert!(!psbt.inputs[1].partial_sigs.is_empty());
+        for (pubkey, sig) in &psbt.inputs[1].partial_sigs {
+            secp.verify_ecdsa(&msg, &sig.sig, &pubkey.inner).unwrap();
+        }
+    }
+
+    #[test]
+    fn misaligned_seller_output_is_rejected() {
+        let market = Market::new();
+        let (seller_psbt, inscription_output) = market.list();
+        let mut purchase = market
+            .buy((seller_psbt.clone(), inscription_output))
+            .unwrap()
+            .unsigned_tx;
+        purchase.output.swap(1, 2);
+
+        let mut buyer_psbt = Psbt::from_unsigned_tx(purchase).unwrap();
+        assert!(matches!(
+            combine_seller_psbt(&mut buyer_psbt, &seller_psbt),
+            Err(Error::SellerPsbtMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn near_miss_reports_the_exact_shortfall() {
+        let market = Market::new();
+        let (required, _) = exact_payment(&market);
+        market.clear_payment_utxos();
+        market.fund_buyer(20, required - 1, 6);
+
+        match market.buy(market.list()) {
+            Err(Error::InsufficientFunds {
+                required: reported,
+                available,
+                shortfall,
+            }) => {
+                // amounts are net of the payment input's own fee
+                assert_eq!(shortfall, reported - available);
+                assert_eq!(shortfall, Amount::from_sat(1));
+            }
+            other => panic!("expected insufficient funds, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn split_source_is_not_spent_as_payment() {
+        let market = Market::new();
+        market.buyer.state().unspent.clear();
+        let source = market.fund_buyer(20, 60_000, 6);
+        market.fund_buyer(21, 100_000, 6);
+
+        let tx = market.buy(market.list()).unwrap().unsigned_tx;
+        let split = market.buyer.state().sent[0].clone();
+        assert_eq!(split.input[0].previous_output, source);
+        assert_eq!(tx.input[0].previous_output.txid, split.txid());
+        assert!(tx.input.iter().all(|input| input.previous_output != source));
+    }
+
+    #[test]
+    fn carrier_pays_the_requested_postage() {
+        let mut market = Market::new();
+        market.ctx.postage = Some(Amount::from_sat(20_000));
+        let tx = market.buy(market.list()).unwrap().unsigned_tx;
+        assert_eq!(tx.output[INSCRIPTION_CARRIER_OUTPUT].value, 20_000);
+
+        // the dummy and the inscription alone are worth 11,000 sats
+        market.ctx.postage = Some(Amount::from_sat(10_999));
+        assert!(matches!(
+            market.buy(market.list()),
+            Err(Error::PostageTooLow { minimum, .. })
+                if minimum == Amount::from_sat(INSCRIPTION_VALUE) + market.ctx.dummy_value
+        ));
+    }
+
+    #[test]
+    fn merging_change_drops_the_next_dummy_output() {
+        let market = Market::new();
+        let outputs = |policy| {
+            market
+                .buy_with(market.list(), policy)
+                .unwrap()
+                .unsigned_tx
+                .output
+        };
+        let dummy_value = market.ctx.dummy_value.to_sat();
+        let next_dummy = TxOut {
+            value: dummy_value,
+            script_pubkey: market.ctx.config.dummy_address.script_pubkey(),
+        };
+        let with_dummy = outputs(ChangePolicy::CreateNextDummy);
+        assert!(with_dummy.contains(&next_dummy));
+
+        let merged = outputs(ChangePolicy::SingleChange);
+        assert_eq!(merged.len(), with_dummy.len() - 1);
+        assert!(!merged.contains(&next_dummy));
+        // the next dummy's sats and the fee of its output end up as change
+        assert!(merged.last().unwrap().value > with_dummy.last().unwrap().value + dummy_value);
+
+        // with only the dummy the purchase spends, the next one is still made
+        assert_eq!(outputs(ChangePolicy::NextDummyIfNone), with_dummy);
+        let spare = funding_tx(20, &[(dummy_value, &market.ctx.config.buyer_address)]);
+        market.buyer.add_unspent(&spare, 0, 6);
+        assert_eq!(outputs(ChangePolicy::NextDummyIfNone), merged);
+    }
+
+    #[test]
+    fn inscription_goes_to_the_requested_receive_address() {
+        let mut config = config();
+        config.receive_address = p2wpkh(8);
+        let market = Market::with_config(config);
+        let carrier = |receive_address| {
+            let (seller_psbt, inscription_output) = market.list();
+            create_buyer_psbt(
+                &market.ctx,
+                seller_psbt,
+                market.inscription_utxo,
+                inscription_output,
+                PRICE,
+                receive_address,
+                false,
+                ChangePolicy::default(),
+                SortPolicy::default(),
+            )
+            .unwrap()
+            .unsigned_tx
+            .output[INSCRIPTION_CARRIER_OUTPUT]
+                .script_pubkey
+                .clone()
+        };
+
+        assert_eq!(carrier(Some(p2wpkh(7))), p2wpkh(7).script_pubkey());
+        assert_eq!(carrier(None), p2wpkh(8).script_pubkey());
+        assert_ne!(
+            carrier(None),
+            market.ctx.config.dummy_address.script_pubkey()
+        );
+    }
+
+    #[test]
+    fn buyer_roles_use_their_own_addresses() {
+        let mut config = config();
+        config.dummy_address = p2wpkh(5);
+        config.change_address = p2wpkh(6);
+        config.receive_address = p2wpkh(7);
+        let market = Market::with_config(config);
+        // the addresses are the buyer wallet's own
+        market
+            .buyer
+            .state()
+            .scripts
+            .extend([p2wpkh(5).script_pubkey(), p2wpkh(6).script_pubkey()]);
+        // no dummy yet, so one is split off first
+        market
+            .buyer
+            .state()
+            .unspent
+            .retain(|utxo| utxo.amount != market.ctx.dummy_value);
+        market.fund_buyer(20, 100_000, 6);
+
+        let tx = market.buy(market.list()).unwrap().unsigned_tx;
+        let split = market.buyer.state().sent[0].clone();
+        let scripts = |outputs: &[TxOut]| {
+            outputs
+                .iter()
+                .map(|output| output.script_pubkey.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            scripts(&split.output),
+            [p2wpkh(5).script_pubkey(), p2wpkh(6).script_pubkey()]
+        );
+        assert_eq!(tx.input[0].previous_output, OutPoint::new(split.txid(), 0));
+        assert_eq!(scripts(&tx.output[..1]), [p2wpkh(7).script_pubkey()]);
+        assert_eq!(
+            scripts(&tx.output[3..]),
+            [p2wpkh(5).script_pubkey(), p2wpkh(6).script_pubkey()]
+        );
+    }
+
+    #[test]
+    fn hundreds_of_tiny_utxos_are_too_many_inputs() {
+        let mut market = Market::new();
+        market.clear_payment_utxos();
+        // together worth the purchase, but only about 140 fit in the weight
+        for seed in 0..300 {
+            market.fund_buyer(100 + seed, 300, 6);
+        }
+        market.ctx.max_tx_weight = 40_000;
+
+        // reported is what the rest of the purchase leaves the payment inputs
+        assert!(matches!(
+            market.buy(market.list()),
+            Err(Error::TooManyInputs(weight)) if weight < 40_000
+        ));
+        assert!(market.buyer.state().sent.is_empty());
+    }
+
+    #[test]
+    fn total_above_the_cap_is_refused() {
+        let mut market = Market::new();
+        let service_fee = market.buy(market.list()).unwrap().unsigned_tx.output[2].value;
+        let calls = market.buyer.calls("walletprocesspsbt");
+
+        // price and service fee fit, the mining fee doesn't
+        let max = PRICE + Amount::from_sat(service_fee);
+        market.ctx.max_total = Some(max);
+        match market.buy(market.list()) {
+            Err(Error::ExceedsMaxTotal {
+                total,
+                max: reported,
+            }) => {
+                assert_eq!(reported, max);
+                assert!(total > max);
+            }
+            other => panic!("expected the cap to be exceeded, got {:?}", other),
+        }
+        assert_eq!(market.buyer.calls("walletprocesspsbt"), calls);
+
+        market.ctx.max_total = Some(max + Amount::from_sat(10_000));
+        market.buy(market.list()).unwrap();
+    }
+
+    #[test]
+    fn wallet_gets_the_buyer_key_origins() {
+        let market = Market::new();
+        let secp = bitcoin::secp256k1::Secp256k1::new();
+        let pubkey = key(2).public_key(&secp);
+        market.buyer.state().address_info.insert(
+            market.ctx.config.buyer_address.to_string(),
+            serde_json::json!({
+                "pubkey": pubkey.to_string(),
+                "hdmasterfingerprint": "d34db33f",
+                "hdkeypath": "m/84'/1'/0'/0/0",
+            }),
+        );
+
+        market.buy(market.list()).unwrap();
+        let state = market.buyer.state();
+        let given = state.processed.last().unwrap();
+        for index in [0, 2] {
+            let (fingerprint, path) = &given.inputs[index].bip32_derivation[&pubkey.inner];
+            assert_eq!(fingerprint.to_string(), "d34db33f");
+            assert_eq!(path.to_string(), "m/84'/1'/0'/0/0");
+        }
+    }
+
+    #[test]
+    fn inscription_sats_flow_from_the_listing_to_the_buyer() {
+        let market = Market::new();
+        let (seller_psbt, inscription_output) = market.list();
+        // the listing prices the inscription's output without carrying it
+        assert_eq!(inscription_output.value, INSCRIPTION_VALUE);
+        assert_eq!(
+            seller_psbt.unsigned_tx.output[SELLER_PAYMENT_OUTPUT].value,
+            PRICE.to_sat()
+        );
+
+        let mut psbt = market
+            .buy((seller_psbt, inscription_output.clone()))
+            .unwrap();
+        let tx = crate::finalize::finalize(&mut psbt).unwrap();
+        let prevouts = [
+            TxOut {
+                value: market.ctx.dummy_value.to_sat(),
+                script_pubkey: market.ctx.config.buyer_address.script_pubkey(),
+            },
+            inscription_output,
+        ];
+        for offset in [0, INSCRIPTION_VALUE / 2, INSCRIPTION_VALUE - 1] {
+            verify_sat_offset(&tx, &prevouts, 1, offset).unwrap();
+        }
+        // the dummy's sats come first, then every inscription sat
+        assert_eq!(
+            tx.output[INSCRIPTION_CARRIER_OUTPUT],
+            TxOut {
+                value: market.ctx.dummy_value.to_sat() + INSCRIPTION_VALUE,
+                script_pubkey: market.ctx.config.receive_address.script_pubkey(),
+            }
+        );
+        assert_eq!(tx.output[1].value, PRICE.to_sat());
+    }
+
+    #[test]
+    fn seller_paid_fee_comes_out_of_the_seller_output() {
+        let buyer_paid = Market::new();
+        let buyer_paid = buyer_paid.buy(buyer_paid.list()).unwrap().unsigned_tx;
+        let mut config = config();
+        config.fee_payer = FeePayer::Seller;
+        let seller_paid = Market::with_config(config);
+        let seller_paid = seller_paid.buy(seller_paid.list()).unwrap().unsigned_tx;
+
+        let fee = buyer_paid.output[2].value;
+        assert_eq!(seller_paid.output[2], buyer_paid.output[2]);
+        assert_eq!(seller_paid.output[1].value, PRICE.to_sat() - fee);
+        // same inputs and sizes, so the buyer keeps the fee as change
+        assert_eq!(seller_paid.input, buyer_paid.input);
+        assert_eq!(
+            seller_paid.output.last().unwrap().value,
+            buyer_paid.output.last().unwrap().value + fee
+        );
+    }
+
+    #[test]
+    fn rerun_after_a_failure_reuses_the_split_dummy() {
+        let market = Market::new();
+        market.buyer.state().unspent.clear();
+        market.fund_buyer(20, 60_000, 6);
+
+        // the split spends the only confirmed UTXO, leaving nothing to pay with
+        assert!(matches!(
+            market.buy(market.list()),
+            Err(Error::InsufficientFunds { .. })
+        ));
+        let split = market.buyer.state().sent[0].clone();
+
+        market.fund_buyer(21, 100_000, 6);
+        let tx = market.buy(market.list()).unwrap().unsigned_tx;
+        assert_eq!(market.buyer.state().sent.len(), 1);
+        assert_eq!(tx.input[0].previous_output, OutPoint::new(split.txid(), 0));
+    }
+
+    #[test]
+    fn memo_goes_in_one_op_return_after_the_seller_output() {
+        let mut market = Market::new();
+        let memo = [0x42; 40];
+        market.ctx.memo = Some(memo.to_vec());
+        let psbt = market.buy(market.list()).unwrap();
+        let tx = &psbt.unsigned_tx;
+
+        let op_returns = tx
+            .output
+            .iter()
+            .enumerate()
+            .filter(|(_, output)| output.script_pubkey.is_op_return())
+            .collect::<Vec<_>>();
+        assert_eq!(op_returns.len(), 1);
+        let (index, output) = op_returns[0];
+        assert!(index > 1);
+        assert_eq!(output.value, 0);
+        assert_eq!(&output.script_pubkey.as_bytes()[2..], &memo[..]);
+        // the seller's pair keeps its index, so the signature holds
+        assert_eq!(tx.output[1].value, PRICE.to_sat());
+        assert!(!psbt.inputs[1].partial_sigs.is_empty());
+
+        assert!(matches!(
+            add_op_return(&mut tx.clone(), &memo),
+            Err(Error::InvalidMemo(_))
+        ));
+        assert!(matches!(
+            add_op_return(&mut tx.clone(), &[0; MAX_OP_RETURN_DATA + 1]),
+            Err(Error::InvalidMemo(_))
+        ));
+    }
+}
@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+
+pub const USAGE: &str = "\
+Usage: test-psbt <command> [options]
+
+Commands:
+  sell                      build, sign and print a seller PSBT, with the
+                            seller's net proceeds on stderr
+      --utxo <txid:vout>    inscription to list [env: SELLER_UTXO]
+      --price <sat>         listing price [env: LISTING_PRICE_SAT]
+      --locktime-height <height>
+                            the purchase can't be mined before this height
+      --royalty-to <address>
+                            pay a creator royalty there out of the price
+      --royalty-bps <n>     royalty in hundredths of a percent of the price
+      --privkey <wif>       sign with this key instead of the seller wallet
+      --no-sign             leave the PSBT for an external signer
+      --signer <signer>     wallet (default) or hwi to sign on a hardware wallet
+      --device-fingerprint <fp>
+                            master key fingerprint of the hwi device
+      --uri                 print a shareable bitcoin: listing uri instead
+      --out <file>          write the PSBT there instead of printing it
+      --format <fmt>        base64 (default) or binary
+      --psbt-version <n>    0 (default) or 2 for BIP 370 signers
+  cancel                    void listings by spending the inscription back
+      --utxo <txid:vout>    listed inscription [env: SELLER_UTXO]
+      --to <address>        send it there instead [env: SELLER_ADDRESS]
+      --privkey <wif>       sign with this key instead of the seller wallet
+      --dry-run             don't broadcast the cancellation [env: DRY_RUN]
+  buy                       combine a seller PSBT into a signed buyer PSBT
+      --seller-psbt, --in <file>
+                            seller PSBT, base64 or binary
+      --utxo <txid:vout>    inscription being bought [env: SELLER_UTXO]
+      --price <sat>         advertised price [env: LISTING_PRICE_SAT]
+      --out <file>          write the PSBT there instead of printing it
+      --format <fmt>        base64 (default) or binary
+      --psbt-version <n>    0 (default) or 2 for BIP 370 signers
+      --receive-to <address>
+                            send the inscription there [env: BUYER_RECEIVE_ADDRESS]
+      --no-rbf              don't signal replace-by-fee on the buyer inputs
+      --no-next-dummy       don't create a dummy for the next purchase
+      --change-to <address> send all change there, without a next dummy
+      --reuse-dummy         only create a next dummy when no spare one is left
+      --bip69               sort payment inputs and buyer outputs per BIP69
+      --coin-selection <strategy>
+                            bnb, largest-first, smallest-first or random
+                            [env: COIN_SELECTION]
+      --no-sign             leave the PSBT for an external signer; needs an
+                            existing dummy UTXO
+      --signer <signer>     wallet (default) or hwi to sign on a hardware wallet
+      --device-fingerprint <fp>
+                            master key fingerprint of the hwi device
+      --postage <sat>       value of the inscription output [env: POSTAGE_SAT]
+      --memo <text>         stamp up to 80 bytes into an OP_RETURN output
+      --conf-target <blocks>
+                            confirm within this many blocks [env: CONF_TARGET]
+      --max-fee-rate <sat/vB>
+                            refuse to pay more [env: MAX_FEE_RATE_SAT_VB]
+      --dry-run             don't broadcast a new dummy UTXO [env: DRY_RUN]
+  broadcast                 finalize a signed PSBT, review it and send it
+      --psbt, --in <file>   PSBT to broadcast, base64 or binary
+      --dry-run             print the final tx and txid instead [env: DRY_RUN]
+      --yes                 don't ask for confirmation before sending
+      --skip-mempool-check  don't ask the node's mempool to accept it first
+      --max-fee-rate <sat/vB>
+                            refuse to send if it pays more [env: MAX_FEE_RATE_SAT_VB]
+  bump                      sign a higher-fee replacement of a stuck purchase
+      --txid <txid>         transaction to replace
+      --fee-rate <sat/vB>   fee rate of the replacement
+      --no-sign             leave the PSBT for an external signer
+      --signer <signer>     wallet (default) or hwi to sign on a hardware wallet
+      --device-fingerprint <fp>
+                            master key fingerprint of the hwi device
+      --out <file>          write the PSBT there instead of printing it
+      --format <fmt>        base64 (default) or binary
+      --psbt-version <n>    0 (default) or 2 for BIP 370 signers
+  fees                      compare what a PSBT would cost across targets
+      --psbt, --in <file>   PSBT to price, base64 or binary
+      --targets <blocks,...>
+                            confirmation targets (default 1,3,6,12,24,144)
+  describe                  print the contents of a PSBT
+      --psbt, --in <file>   PSBT to describe, base64 or binary
+  prep-dummies              split buyer funds into dummy UTXOs in one tx
+      --count <n>           number of dummies to create
+      --coin-selection <strategy>
+                            bnb, largest-first, smallest-first or random
+                            [env: COIN_SELECTION]
+      --conf-target <blocks>
+                            confirm within this many blocks [env: CONF_TARGET]
+      --max-fee-rate <sat/vB>
+                            refuse to pay more [env: MAX_FEE_RATE_SAT_VB]
+      --dry-run             don't broadcast the split tx [env: DRY_RUN]
+
+Options for every command:
+  --output <fmt>            text (default) or json, printing one object with
+                            the results or {\"error\": ...} on failure
+";
+
+/// Options every command accepts, as in [`Command::options`].
+const GLOBAL_OPTIONS: &[(&str, bool)] = &[("output", true)];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Sell,
+    Cancel,
+    Buy,
+    Broadcast,
+    Bump,
+    Fees,
+    Describe,
+    PrepDummies,
+}
+
+impl Command {
+    /// The options the command accepts and whether each takes a value.
+    fn options(self) -> &'static [(&'static str, bool)] {
+        match self {
+            Command::Sell => &[
+                ("utxo", true),
+                ("price", true),
+                ("locktime-height", true),
+                ("royalty-to", true),
+                ("royalty-bps", true),
+                ("privkey", true),
+                ("no-sign", false),
+                ("signer", true),
+                ("device-fingerprint", true),
+                ("uri", false),
+                ("out", true),
+                ("format", true),
+                ("psbt-version", true),
+            ],
+            Command::Cancel => &[
+                ("utxo", true),
+                ("to", true),
+                ("privkey", true),
+                ("dry-run", false),
+            ],
+            Command::Buy => &[
+                ("seller-psbt", true),
+                ("in", true),
+                ("utxo", true),
+                ("price", true),
+                ("out", true),
+                ("format", true),
+                ("psbt-version", true),
+                ("receive-to", true),
+                ("no-rbf", false),
+                ("no-next-dummy", false),
+                ("change-to", true),
+                ("reuse-dummy", false),
+                ("bip69", false),
+                ("coin-selection", true),
+                ("no-sign", false),
+                ("signer", true),
+                ("device-fingerprint", true),
+                ("postage", true),
+                ("memo", true),
+                ("conf-target", true),
+                ("max-fee-rate", true),
+                ("dry-run", false),
+            ],
+            Command::Broadcast => &[
+                ("psbt", true),
+                ("in", true),
+                ("dry-run", false),
+                ("yes", false),
+                ("skip-mempool-check", false),
+                ("max-fee-rate", true),
+            ],
+            Command::Bump => &[
+                ("txid", true),
+                ("fee-rate", true),
+                ("no-sign", false),
+                ("signer", true),
+                ("device-fingerprint", true),
+                ("out", true),
+                ("format", true),
+                ("psbt-version", true),
+            ],
+            Command::Fees => &[("psbt", true), ("in", true), ("targets", true)],
+            Command::Describe => &[("psbt", true), ("in", true)],
+            Command::PrepDummies => &[
+                ("count", true),
+                ("coin-selection", true),
+                ("conf-target", true),
+                ("max-fee-rate", true),
+                ("dry-run", false),
+            ],
+        }
+    }
+
+    /// Options without an environment fallback, one of each group being
+    /// required.
+    fn required(self) -> &'static [&'static [&'static str]] {
+        match self {
+            Command::Sell => &[],
+            Command::Cancel => &[],
+            Command::Buy => &[&["seller-psbt", "in"]],
+            Command::Broadcast => &[&["psbt", "in"]],
+            Command::Bump => &[&["txid"], &["fee-rate"]],
+            Command::Fees => &[&["psbt", "in"]],
+            Command::Describe => &[&["psbt", "in"]],
+            Command::PrepDummies => &[&["count"]],
+        }
+    }
+}
+
+/// A parsed command line: the subcommand and the options given to it.
+#[derive(Debug)]
+pub struct Invocation {
+    pub command: Command,
+    values: HashMap<&'static str, String>,
+}
+
+impl Invocation {
+    /// The value given for `--<name>`, if any.
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+
+    /// Whether the switch `--<name>` was given.
+    pub fn flag(&self, name: &str) -> bool {
+        self.values.contains_key(name)
+    }
+
+    /// Whether results are printed as JSON rather than text.
+    pub fn json(&self) -> bool {
+        self.value("output") == Some("json")
+    }
+}
+
+/// Parses the arguments following the program name.
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Invocation, String> {
+    let mut args = args.into_iter();
+    let command = match args.next().as_deref() {
+        Some("sell") => Command::Sell,
+        Some("cancel") => Command::Cancel,
+        Some("buy") => Command::Buy,
+        Some("broadcast") => Command::Broadcast,
+        Some("bump") => Command::Bump,
+        Some("fees") => Command::Fees,
+        Some("describe") => Command::Describe,
+        Some("prep-dummies") => Command::PrepDummies,
+        Some(other) => return Err(format!("unknown command {:?}", other)),
+        None => return Err("missing command".to_string()),
+    };
+
+    let mut values = HashMap::new();
+    while let Some(arg) = args.next() {
+        let flag = arg
+            .strip_prefix("--")
+            .ok_or_else(|| format!("unexpected argument {:?}", arg))?;
+        let (flag, inline_value) = match flag.split_once('=') {
+            Some((flag, value)) => (flag, Some(value.to_string())),
+            None => (flag, None),
+        };
+        let &(name, takes_value) = command
+            .options()
+            .iter()
+            .chain(GLOBAL_OPTIONS)
+            .find(|(name, _)| *name == flag)
+            .ok_or_else(|| format!("unknown option --{}", flag))?;
+
+        let value = if takes_value {
+            inline_value
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("--{} needs a value", name))?
+        } else if inline_value.is_some() {
+            return Err(format!("--{} takes no value", name));
+        } else {
+            String::new()
+        };
+        values.insert(name, value);
+    }
+
+    if let Some(missing) = command
+        .required()
+        .iter()
+        .find(|group| !group.iter().any(|name| values.contains_key(name)))
+    {
+        return Err(format!("--{} is required", missing.join(" or --")));
+    }
+    if let Some(output) = values.get("output") {
+        if output != "text" && output != "json" {
+            return Err(format!("unknown output format {:?}", output));
+        }
+    }
+
+    Ok(Invocation { command, values })
+}
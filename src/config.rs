@@ -0,0 +1,385 @@
+//! Up-front validation of the environment.
+//!
+//! The marketplace's settings, read from the environment once at startup
+//! rather than wherever a flow first needs them, so a missing or malformed
+//! variable can't surface halfway through a run. [`validate_env`] reports
+//! every problem at once.
+
+use crate::explorer::parse_explorer_url;
+use crate::fee::{parse_fee_splits, FeePayer, FeeSplit, DEFAULT_MARKETPLACE_FEE_FLOOR};
+use crate::selection::CoinSelection;
+use crate::{
+    env_opt, network_from_env, parse_checked_address, Error, Result, DEFAULT_PRICE, SERVICE_FEE,
+};
+use bitcoin::{Address, Amount, Network, OutPoint};
+use reqwest::Url;
+use std::str::FromStr;
+
+/// Variables without which no node-backed command can run.
+const REQUIRED: &[&str] = &[
+    "BITCOIN_RPC_URL",
+    "BITCOIN_RPC_USER",
+    "BITCOIN_RPC_PASS",
+    "SELLER_RPC_URL",
+    "SELLER_RPC_USER",
+    "SELLER_RPC_PASS",
+    "BUYER_RPC_URL",
+    "BUYER_RPC_USER",
+    "BUYER_RPC_PASS",
+];
+
+/// Optional numeric settings, only checked for being parseable; the context
+/// reads them itself.
+const NUMBERS: &[&str] = &[
+    "BUYER_MIN_CONF",
+    "SELLER_MIN_CONF",
+    "DUMMY_UTXO_SAT",
+    "DUMMY_WAIT_TIMEOUT_SECS",
+    "MIN_CHANGE_SAT",
+    "POSTAGE_SAT",
+    "BUYER_MAX_TOTAL_SAT",
+    "MAX_TX_WEIGHT",
+    "ORD_HTTP_TIMEOUT_SECS",
+    "MIN_FEE_RATE_SAT_VB",
+    "FALLBACK_FEE_RATE_SAT_VB",
+    "MAX_FEE_RATE_SAT_VB",
+    "RPC_RETRY_BASE_DELAY_MS",
+];
+
+/// The marketplace's settings, read once at startup. Addresses belong to
+/// `network`.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub network: Network,
+    /// The seller's address, where a cancelled listing sends the
+    /// inscription back.
+    pub seller_address: Address,
+    /// The buyer's wallet address, and the default of the three below.
+    pub buyer_address: Address,
+    /// Where bought inscriptions go unless the purchase names an address.
+    pub receive_address: Address,
+    /// Where new dummies go, whether split off for a purchase, created for
+    /// the next one or prepared in bulk.
+    pub dummy_address: Address,
+    /// Where the buyer's change goes.
+    pub change_address: Address,
+    /// Receives the service fee unless `fee_splits` are set.
+    pub marketplace_address: Address,
+    /// Listing price when none is given on the command line.
+    pub price: Amount,
+    /// Flat service fee per listing, charged when `marketplace_fee_bps` is
+    /// unset.
+    pub service_fee: Amount,
+    /// Service fee in hundredths of a percent of the price, at least
+    /// `marketplace_fee_floor`.
+    pub marketplace_fee_bps: Option<u16>,
+    pub marketplace_fee_floor: Amount,
+    /// Recipients sharing the marketplace fee instead of
+    /// `marketplace_address`.
+    pub fee_splits: Option<Vec<FeeSplit>>,
+    /// Whether the buyer pays the marketplace fee on top of the price or the
+    /// seller out of it.
+    pub fee_payer: FeePayer,
+    pub ord_explorer: Url,
+    pub tx_explorer: Option<Url>,
+    pub seller_utxo: Option<OutPoint>,
+}
+
+impl Config {
+    /// A config with the default price and the flat [`SERVICE_FEE`].
+    pub fn new(
+        network: Network,
+        seller_address: Address,
+        buyer_address: Address,
+        marketplace_address: Address,
+        ord_explorer: Url,
+    ) -> Self {
+        Config {
+            network,
+            seller_address,
+            receive_address: buyer_address.clone(),
+            dummy_address: buyer_address.clone(),
+            change_address: buyer_address.clone(),
+            buyer_address,
+            marketplace_address,
+            price: DEFAULT_PRICE,
+            service_fee: SERVICE_FEE,
+            marketplace_fee_bps: None,
+            marketplace_fee_floor: DEFAULT_MARKETPLACE_FEE_FLOOR,
+            fee_splits: None,
+            fee_payer: FeePayer::default(),
+            ord_explorer,
+            tx_explorer: None,
+            seller_utxo: None,
+        }
+    }
+
+    /// The distinct addresses the buyer's funds and dummies sit at.
+    pub fn buyer_addresses(&self) -> Vec<&Address> {
+        let mut addresses = vec![&self.buyer_address];
+        for address in [&self.dummy_address, &self.change_address] {
+            if !addresses.contains(&address) {
+                addresses.push(address);
+            }
+        }
+        addresses
+    }
+}
+
+/// Reads the [`Config`] from `BITCOIN_NETWORK`, `SELLER_ADDRESS`,
+/// `BUYER_ADDRESS`, the `BUYER_*_ADDRESS` overrides, `MARKET_PLACE_ADDRESS`,
+/// `LISTING_PRICE_SAT`, the `MARKETPLACE_FEE_*` settings, `FEE_SPLITS`,
+/// `FEE_PAYER`, `ORD_EXPLORER`, `TX_EXPLORER` and `SELLER_UTXO`, checking
+/// along the way that the RPC variables are set and every other variable
+/// parses. Fails with [`Error::InvalidConfig`] listing each offending
+/// variable.
+pub fn validate_env() -> Result<Config> {
+    let mut problems = Vec::new();
+
+    let network = match network_from_env() {
+        Ok(network) => network,
+        Err(e) => {
+            problems.push(("BITCOIN_NETWORK", e.to_string()));
+            Network::Testnet
+        }
+    };
+    for &name in REQUIRED {
+        required(&mut problems, name);
+    }
+    for &name in NUMBERS {
+        optional::<u64>(&mut problems, name);
+    }
+    optional::<u16>(&mut problems, "CONF_TARGET");
+    optional::<u32>(&mut problems, "RPC_RETRY_ATTEMPTS");
+    optional::<bool>(&mut problems, "DRY_RUN");
+    optional::<CoinSelection>(&mut problems, "COIN_SELECTION");
+
+    let seller_address = address(&mut problems, "SELLER_ADDRESS", network);
+    let buyer_address = address(&mut problems, "BUYER_ADDRESS", network);
+    let marketplace_address = address(&mut problems, "MARKET_PLACE_ADDRESS", network);
+    let receive_address = optional_address(&mut problems, "BUYER_RECEIVE_ADDRESS", network);
+    let dummy_address = optional_address(&mut problems, "BUYER_DUMMY_ADDRESS", network);
+    let change_address = optional_address(&mut problems, "BUYER_CHANGE_ADDRESS", network);
+    let ord_explorer = required(&mut problems, "ORD_EXPLORER")
+        .and_then(|base| url(&mut problems, "ORD_EXPLORER", &base));
+    let tx_explorer = optional::<String>(&mut problems, "TX_EXPLORER")
+        .and_then(|base| url(&mut problems, "TX_EXPLORER", &base));
+    let fee_splits = optional::<String>(&mut problems, "FEE_SPLITS").and_then(|splits| {
+        parse_fee_splits(&splits, network)
+            .map_err(|e| problems.push(("FEE_SPLITS", e.to_string())))
+            .ok()
+    });
+    let price =
+        optional(&mut problems, "LISTING_PRICE_SAT").map_or(DEFAULT_PRICE, Amount::from_sat);
+    let marketplace_fee_bps = optional(&mut problems, "MARKETPLACE_FEE_BPS");
+    let marketplace_fee_floor = optional(&mut problems, "MARKETPLACE_FEE_FLOOR_SAT")
+        .map_or(DEFAULT_MARKETPLACE_FEE_FLOOR, Amount::from_sat);
+    let fee_payer = optional(&mut problems, "FEE_PAYER").unwrap_or_default();
+    let seller_utxo = optional(&mut problems, "SELLER_UTXO");
+
+    match (
+        seller_address,
+        buyer_address,
+        marketplace_address,
+        ord_explorer,
+    ) {
+        (
+            Some(seller_address),
+            Some(buyer_address),
+            Some(marketplace_address),
+            Some(ord_explorer),
+        ) if problems.is_empty() => Ok(Config {
+            receive_address: receive_address.unwrap_or_else(|| buyer_address.clone()),
+            dummy_address: dummy_address.unwrap_or_else(|| buyer_address.clone()),
+            change_address: change_address.unwrap_or_else(|| buyer_address.clone()),
+            price,
+            marketplace_fee_bps,
+            marketplace_fee_floor,
+            fee_splits,
+            fee_payer,
+            tx_explorer,
+            seller_utxo,
+            ..Config::new(
+                network,
+                seller_address,
+                buyer_address,
+                marketplace_address,
+                ord_explorer,
+            )
+        }),
+        _ => Err(Error::InvalidConfig(problems)),
+    }
+}
+
+/// The value of `name`, noting it as missing if unset or empty.
+fn required(problems: &mut Vec<(&'static str, String)>, name: &'static str) -> Option<String> {
+    match env_opt(name) {
+        Ok(Some(value)) => Some(value),
+        Ok(None) => {
+            problems.push((name, "missing".to_string()));
+            None
+        }
+        Err(e) => {
+            problems.push((name, reason(e)));
+            None
+        }
+    }
+}
+
+/// The parsed value of `name` if set, noting it if it doesn't parse.
+fn optional<T: FromStr>(
+    problems: &mut Vec<(&'static str, String)>,
+    name: &'static str,
+) -> Option<T> {
+    env_opt(name)
+        .map_err(|e| problems.push((name, reason(e))))
+        .ok()
+        .flatten()
+}
+
+fn address(
+    problems: &mut Vec<(&'static str, String)>,
+    name: &'static str,
+    network: Network,
+) -> Option<Address> {
+    let value = required(problems, name)?;
+    parse_address(problems, name, &value, network)
+}
+
+fn optional_address(
+    problems: &mut Vec<(&'static str, String)>,
+    name: &'static str,
+    network: Network,
+) -> Option<Address> {
+    let value = optional::<String>(problems, name)?;
+    parse_address(problems, name, &value, network)
+}
+
+fn parse_address(
+    problems: &mut Vec<(&'static str, String)>,
+    name: &'static str,
+    value: &str,
+    network: Network,
+) -> Option<Address> {
+    parse_checked_address(value, network)
+        .map_err(|e| problems.push((name, e.to_string())))
+        .ok()
+}
+
+fn url(problems: &mut Vec<(&'static str, String)>, name: &'static str, base: &str) -> Option<Url> {
+    parse_explorer_url(base)
+        .map_err(|e| problems.push((name, e.to_string())))
+        .ok()
+}
+
+/// What is wrong with a variable, without repeating its name.
+fn reason(e: Error) -> String {
+    match e {
+        Error::InvalidEnv(_, value) => format!("invalid value {:?}", value),
+        Error::Env(_, _) => "not valid unicode".to_string(),
+        e => e.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::mock::p2wpkh;
+    use crate::test_env::lock_env;
+    use std::env;
+
+    const ADDRESSES: &[&str] = &[
+        "SELLER_ADDRESS",
+        "BUYER_ADDRESS",
+        "MARKET_PLACE_ADDRESS",
+        "BUYER_RECEIVE_ADDRESS",
+        "BUYER_DUMMY_ADDRESS",
+        "BUYER_CHANGE_ADDRESS",
+    ];
+
+    fn clear_env() {
+        for name in REQUIRED.iter().chain(NUMBERS).chain(ADDRESSES).chain(&[
+            "ORD_EXPLORER",
+            "TX_EXPLORER",
+            "FEE_SPLITS",
+            "FEE_PAYER",
+            "LISTING_PRICE_SAT",
+            "MARKETPLACE_FEE_BPS",
+            "MARKETPLACE_FEE_FLOOR_SAT",
+            "SELLER_UTXO",
+            "CONF_TARGET",
+            "RPC_RETRY_ATTEMPTS",
+            "DRY_RUN",
+            "COIN_SELECTION",
+        ]) {
+            env::remove_var(name);
+        }
+        env::set_var("BITCOIN_NETWORK", "regtest");
+    }
+
+    #[test]
+    fn partial_env_lists_every_missing_variable() {
+        let _env = lock_env();
+        clear_env();
+        for name in ["BITCOIN_RPC_URL", "BITCOIN_RPC_USER", "BITCOIN_RPC_PASS"] {
+            env::set_var(name, "x");
+        }
+        env::set_var("SELLER_ADDRESS", p2wpkh(1).to_string());
+        env::set_var("BUYER_MIN_CONF", "one");
+
+        let problems = match validate_env() {
+            Err(Error::InvalidConfig(problems)) => problems,
+            other => panic!("expected an invalid config, got {:?}", other),
+        };
+        let missing = problems
+            .iter()
+            .filter(|(_, reason)| reason == "missing")
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            missing,
+            [
+                "SELLER_RPC_URL",
+                "SELLER_RPC_USER",
+                "SELLER_RPC_PASS",
+                "BUYER_RPC_URL",
+                "BUYER_RPC_USER",
+                "BUYER_RPC_PASS",
+                "BUYER_ADDRESS",
+                "MARKET_PLACE_ADDRESS",
+                "ORD_EXPLORER",
+            ]
+        );
+        assert!(problems.contains(&("BUYER_MIN_CONF", "invalid value \"one\"".to_string())));
+        assert_eq!(problems.len(), missing.len() + 1);
+        clear_env();
+    }
+
+    #[test]
+    fn malformed_optional_settings_are_reported_at_startup() {
+        let _env = lock_env();
+        clear_env();
+        for name in REQUIRED {
+            env::set_var(name, "x");
+        }
+        for name in ["SELLER_ADDRESS", "BUYER_ADDRESS", "MARKET_PLACE_ADDRESS"] {
+            env::set_var(name, p2wpkh(1).to_string());
+        }
+        env::set_var("ORD_EXPLORER", "http://127.0.0.1:8080/");
+        env::set_var("BUYER_MAX_TOTAL_SAT", "lots");
+        env::set_var("COIN_SELECTION", "largest");
+
+        let problems = match validate_env() {
+            Err(Error::InvalidConfig(problems)) => problems,
+            other => panic!("expected an invalid config, got {:?}", other),
+        };
+        assert_eq!(
+            problems,
+            [
+                ("BUYER_MAX_TOTAL_SAT", "invalid value \"lots\"".to_string()),
+                ("COIN_SELECTION", "invalid value \"largest\"".to_string()),
+            ]
+        );
+        clear_env();
+    }
+}
@@ -0,0 +1,296 @@
+use crate::config::{validate_env, Config};
+use crate::explorer::Esplora;
+use crate::fee::FeeConfig;
+use crate::inscription::{ord_http_timeout, InscriptionIndex, OrdExplorer};
+use crate::retry::RetryPolicy;
+use crate::rpc::{BitcoinRpc, RPC_INVALID_ADDRESS_OR_KEY};
+use crate::selection::CoinSelection;
+use crate::signer::PsbtSigner;
+use crate::utxo::{DEFAULT_DUMMY_VALUE, DEFAULT_DUMMY_WAIT_TIMEOUT};
+use crate::{env_amount, env_opt, env_or, env_var, Error, Result};
+use bitcoin::{Amount, OutPoint, PrivateKey, Transaction, Txid};
+use bitcoincore_rpc::json::ListUnspentResultEntry;
+use bitcoincore_rpc::{jsonrpc, Auth, Client};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Confirmations buyer funds need when `BUYER_MIN_CONF` is unset.
+pub const DEFAULT_BUYER_MIN_CONF: usize = 1;
+
+/// Confirmations a listed inscription needs when `SELLER_MIN_CONF` is
+/// unset.
+pub const DEFAULT_SELLER_MIN_CONF: u32 = 1;
+
+/// Largest transaction built when `MAX_TX_WEIGHT` is unset, the most nodes
+/// relay by default.
+pub const DEFAULT_MAX_TX_WEIGHT: usize = 400_000;
+
+/// The nodes and settings a marketplace run operates against.
+pub struct MarketplaceContext {
+    pub full_node: Box<dyn BitcoinRpc>,
+    pub seller_node: Box<dyn BitcoinRpc>,
+    pub buyer_node: Box<dyn BitcoinRpc>,
+    /// Network, addresses, prices and explorers.
+    pub config: Config,
+    /// How idempotent RPC reads are retried.
+    pub retry: RetryPolicy,
+    /// Confirmations a buyer UTXO needs before it is spent or counted.
+    pub buyer_min_conf: usize,
+    /// Confirmations the inscription UTXO needs before it is listed.
+    pub seller_min_conf: u32,
+    /// Value of the dummy outputs that pad a purchase.
+    pub dummy_value: Amount,
+    /// How long a purchase waits for a dummy it just split off to reach the
+    /// buyer's wallet.
+    pub dummy_wait_timeout: Duration,
+    /// Value of the buyer's inscription output when set, topped up from the
+    /// payment inputs; otherwise just the dummy plus the inscriptions.
+    pub postage: Option<Amount>,
+    /// Data stamped into each purchase in an OP_RETURN output, e.g. the
+    /// marketplace's order identifier.
+    pub memo: Option<Vec<u8>>,
+    /// How fee rates are estimated and capped.
+    pub fee: FeeConfig,
+    /// Most a purchase may cost in price, service fee and mining fee
+    /// together; unlimited when unset.
+    pub max_total: Option<Amount>,
+    /// How the purchase and dummy transactions pick the buyer's UTXOs.
+    pub coin_selection: CoinSelection,
+    /// Weight units the purchase and dummy transactions may not exceed,
+    /// which caps how many UTXOs they spend.
+    pub max_tx_weight: usize,
+    /// Smallest change output worth creating; less is left to the miner. The
+    /// change script's dust limit applies when this is below it.
+    pub min_change: Amount,
+    /// Signs seller PSBTs instead of the seller node's wallet, which is then
+    /// only asked for UTXOs.
+    pub seller_key: Option<PrivateKey>,
+    /// Build and sign everything but never broadcast.
+    pub dry_run: bool,
+    /// Have the wallets only fill in the seller and buyer PSBTs, with their
+    /// UTXOs and key origins, rather than sign them. An external signer then
+    /// signs each PSBT before it is passed on or broadcast. Steps that must
+    /// broadcast a transaction of their own, like splitting off a dummy,
+    /// fail with [`Error::SigningDisabled`].
+    pub no_sign: bool,
+    /// Signs in place of the nodes' wallets, which then only fill in the
+    /// PSBTs with their UTXOs and key origins.
+    pub signer: Option<Box<dyn PsbtSigner>>,
+    /// Transactions a dry run would have broadcast.
+    simulated_txs: Mutex<HashMap<Txid, Transaction>>,
+    /// Where inscriptions are looked up, ord unless replaced.
+    pub inscription_index: Box<dyn InscriptionIndex>,
+    /// Where transactions the full node can't find are fetched instead.
+    pub tx_explorer: Option<Esplora>,
+    /// Inscription lookups already answered during this run.
+    inscription_cache: Mutex<HashMap<OutPoint, bool>>,
+}
+
+impl MarketplaceContext {
+    pub fn new(
+        full_node: impl BitcoinRpc + 'static,
+        seller_node: impl BitcoinRpc + 'static,
+        buyer_node: impl BitcoinRpc + 'static,
+        config: Config,
+    ) -> Self {
+        MarketplaceContext {
+            full_node: Box::new(full_node),
+            seller_node: Box::new(seller_node),
+            buyer_node: Box::new(buyer_node),
+            inscription_index: Box::new(OrdExplorer::from_url(config.ord_explorer.clone())),
+            config,
+            retry: RetryPolicy::default(),
+            buyer_min_conf: DEFAULT_BUYER_MIN_CONF,
+            seller_min_conf: DEFAULT_SELLER_MIN_CONF,
+            dummy_value: DEFAULT_DUMMY_VALUE,
+            dummy_wait_timeout: DEFAULT_DUMMY_WAIT_TIMEOUT,
+            postage: None,
+            memo: None,
+            fee: FeeConfig::default(),
+            max_total: None,
+            coin_selection: CoinSelection::default(),
+            max_tx_weight: DEFAULT_MAX_TX_WEIGHT,
+            min_change: Amount::ZERO,
+            seller_key: None,
+            dry_run: false,
+            no_sign: false,
+            signer: None,
+            simulated_txs: Mutex::new(HashMap::new()),
+            inscription_cache: Mutex::new(HashMap::new()),
+            tx_explorer: None,
+        }
+    }
+
+    /// Builds a context from the [`Config`] of [`validate_env`], the
+    /// `BITCOIN_*`, `SELLER_*` and `BUYER_*` RPC variables, the `RPC_RETRY_*`
+    /// settings, `ORD_HTTP_TIMEOUT_SECS`, the fee settings of
+    /// [`FeeConfig::from_env`] and `BUYER_MIN_CONF`, `SELLER_MIN_CONF`,
+    /// `DUMMY_UTXO_SAT`, `DUMMY_WAIT_TIMEOUT_SECS`, `POSTAGE_SAT`,
+    /// `BUYER_MAX_TOTAL_SAT`, `COIN_SELECTION`, `MAX_TX_WEIGHT`,
+    /// `MIN_CHANGE_SAT` and `DRY_RUN`.
+    pub fn from_env() -> Result<Self> {
+        let config = validate_env()?;
+        let timeout = ord_http_timeout()?;
+        let inscription_index =
+            OrdExplorer::from_url(config.ord_explorer.clone()).with_timeout(timeout);
+        let tx_explorer = config
+            .tx_explorer
+            .as_ref()
+            .map(|base| Esplora::new(base.as_str(), timeout))
+            .transpose()?;
+        let mut ctx = MarketplaceContext::new(
+            rpc_client("BITCOIN_RPC_URL", "BITCOIN_RPC_USER", "BITCOIN_RPC_PASS")?,
+            rpc_client("SELLER_RPC_URL", "SELLER_RPC_USER", "SELLER_RPC_PASS")?,
+            rpc_client("BUYER_RPC_URL", "BUYER_RPC_USER", "BUYER_RPC_PASS")?,
+            config,
+        );
+        ctx.retry = RetryPolicy::from_env()?;
+        ctx.inscription_index = Box::new(inscription_index);
+        ctx.tx_explorer = tx_explorer;
+        ctx.buyer_min_conf = env_or("BUYER_MIN_CONF", DEFAULT_BUYER_MIN_CONF)?;
+        ctx.seller_min_conf = env_or("SELLER_MIN_CONF", DEFAULT_SELLER_MIN_CONF)?;
+        ctx.dummy_value = env_amount("DUMMY_UTXO_SAT", DEFAULT_DUMMY_VALUE)?;
+        ctx.dummy_wait_timeout = Duration::from_secs(env_or(
+            "DUMMY_WAIT_TIMEOUT_SECS",
+            DEFAULT_DUMMY_WAIT_TIMEOUT.as_secs(),
+        )?);
+        ctx.postage = env_opt("POSTAGE_SAT")?.map(Amount::from_sat);
+        ctx.fee = FeeConfig::from_env()?;
+        ctx.max_total = env_opt("BUYER_MAX_TOTAL_SAT")?.map(Amount::from_sat);
+        ctx.coin_selection = env_or("COIN_SELECTION", CoinSelection::default())?;
+        ctx.max_tx_weight = env_or("MAX_TX_WEIGHT", DEFAULT_MAX_TX_WEIGHT)?;
+        ctx.min_change = env_amount("MIN_CHANGE_SAT", Amount::ZERO)?;
+        ctx.dry_run = env_or("DRY_RUN", false)?;
+        Ok(ctx)
+    }
+
+    /// Remembers `tx` as broadcast by a dry run, so later steps can spend its
+    /// outputs and avoid its inputs.
+    pub fn record_simulated(&self, tx: Transaction) {
+        self.simulated_txs.lock().unwrap().insert(tx.txid(), tx);
+    }
+
+    /// Whether a transaction recorded by a dry run spends `out`.
+    pub fn is_simulated_spent(&self, out: OutPoint) -> bool {
+        self.simulated_txs
+            .lock()
+            .unwrap()
+            .values()
+            .any(|tx| tx.input.iter().any(|input| input.previous_output == out))
+    }
+
+    /// Whether `txid` was only recorded by a dry run.
+    pub fn is_simulated(&self, txid: Txid) -> bool {
+        self.simulated_txs.lock().unwrap().contains_key(&txid)
+    }
+
+    /// Fetches `txid` from the buyer node, or from the transactions a dry run
+    /// recorded instead of broadcasting.
+    pub fn buyer_transaction(&self, txid: Txid) -> Result<Transaction> {
+        if let Some(tx) = self.simulated_txs.lock().unwrap().get(&txid) {
+            return Ok(tx.clone());
+        }
+        self.retry
+            .run(|| self.buyer_node.get_raw_transaction(&txid, None))
+    }
+
+    /// Fetches `txid` from the full node, falling back to the `tx_explorer`
+    /// when the node doesn't have it, e.g. because it is pruned or runs
+    /// without `txindex`.
+    pub fn full_node_transaction(&self, txid: Txid) -> Result<Transaction> {
+        match self
+            .retry
+            .run(|| self.full_node.get_raw_transaction(&txid, None))
+        {
+            Err(Error::Rpc(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(e))))
+                if e.code == RPC_INVALID_ADDRESS_OR_KEY && self.tx_explorer.is_some() =>
+            {
+                log::debug!(
+                    "full node lacks tx, asking the block explorer txid={}",
+                    txid
+                );
+                self.tx_explorer
+                    .as_ref()
+                    .expect("checked above")
+                    .fetch_prevout_tx(txid)
+            }
+            result => result,
+        }
+    }
+
+    /// Whether `out` carries an inscription, asking the index only the first
+    /// time.
+    pub fn is_inscription(&self, out: OutPoint) -> Result<bool> {
+        if let Some(&cached) = self.inscription_cache.lock().unwrap().get(&out) {
+            return Ok(cached);
+        }
+        let inscribed = self.inscription_index.is_inscription(out)?;
+        self.inscription_cache
+            .lock()
+            .unwrap()
+            .insert(out, inscribed);
+        Ok(inscribed)
+    }
+
+    /// Whether the inscription status of `out` is already known.
+    pub fn is_inscription_cached(&self, out: OutPoint) -> bool {
+        self.inscription_cache.lock().unwrap().contains_key(&out)
+    }
+
+    /// Returns which of `utxos` carry inscriptions, batching the lookups that
+    /// aren't cached yet into one index query.
+    pub fn inscription_outputs(
+        &self,
+        utxos: &[ListUnspentResultEntry],
+    ) -> Result<HashSet<OutPoint>> {
+        let uncached = {
+            let cache = self.inscription_cache.lock().unwrap();
+            utxos
+                .iter()
+                .map(|utxo| OutPoint::new(utxo.txid, utxo.vout))
+                .filter(|outpoint| !cache.contains_key(outpoint))
+                .collect::<Vec<_>>()
+        };
+        let fetched = self.inscription_index.inscription_outputs(&uncached)?;
+
+        let mut cache = self.inscription_cache.lock().unwrap();
+        for outpoint in uncached {
+            cache.insert(outpoint, fetched.contains(&outpoint));
+        }
+        Ok(utxos
+            .iter()
+            .map(|utxo| OutPoint::new(utxo.txid, utxo.vout))
+            .filter(|outpoint| cache[outpoint])
+            .collect())
+    }
+}
+
+fn rpc_client(url: &'static str, user: &'static str, pass: &'static str) -> Result<Client> {
+    let auth = Auth::UserPass(env_var(user)?, env_var(pass)?);
+    Ok(Client::new(&env_var(url)?, auth)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::explorer::mock::{serve, Response};
+    use crate::rpc::mock::{funding_tx, p2wpkh, Market};
+    use bitcoin::consensus::encode::serialize_hex;
+    use std::time::Duration;
+
+    #[test]
+    fn falls_back_to_the_explorer_for_unknown_transactions() {
+        let mut market = Market::new();
+        let tx = funding_tx(10, &[(5_000, &p2wpkh(4))]);
+        let (url, server) = serve(vec![Response::ok(serialize_hex(&tx))]);
+        market.ctx.tx_explorer = Some(Esplora::new(url.as_str(), Duration::from_secs(5)).unwrap());
+
+        assert_eq!(market.ctx.full_node_transaction(tx.txid()).unwrap(), tx);
+        assert_eq!(market.full.calls("getrawtransaction"), 1);
+        assert_eq!(
+            server.join().unwrap(),
+            [format!("GET /tx/{}/hex ", tx.txid())]
+        );
+    }
+}
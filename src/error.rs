@@ -0,0 +1,525 @@
+use bitcoin::blockdata::transaction::ParseOutPointError;
+use bitcoin::consensus::encode;
+use bitcoin::psbt::PsbtParseError;
+use bitcoin::sighash::EcdsaSighashType;
+use bitcoin::{address, psbt, Amount, Network, OutPoint, Txid};
+use std::env::VarError;
+use std::fmt;
+use std::time::Duration;
+
+/// Everything that can go wrong while building, signing or broadcasting
+/// the seller/buyer PSBTs.
+#[derive(Debug)]
+pub enum Error {
+    /// A required environment variable is unset or not valid unicode.
+    Env(&'static str, VarError),
+    /// An environment variable is set but cannot be parsed.
+    InvalidEnv(&'static str, String),
+    /// A command line option has an invalid value.
+    InvalidArgument(&'static str, String),
+    /// Environment variables found missing or malformed at startup, each
+    /// with what is wrong with it.
+    InvalidConfig(Vec<(&'static str, String)>),
+    /// A file could not be read or written.
+    Io(std::io::Error),
+    /// `BITCOIN_NETWORK` is not one of mainnet, testnet, regtest or signet.
+    UnknownNetwork(String),
+    /// A `<txid>:<vout>` string could not be parsed.
+    OutPointParse(String, ParseOutPointError),
+    /// The bitcoin node rejected an RPC call.
+    Rpc(bitcoincore_rpc::Error),
+    /// The full, seller or buyer node did not answer at startup.
+    NodeUnreachable {
+        which: &'static str,
+        source: bitcoincore_rpc::Error,
+    },
+    /// A node runs on another chain, as `getblockchaininfo` names it, than
+    /// the configured network.
+    NetworkMismatch {
+        node: &'static str,
+        expected: Network,
+        actual: String,
+    },
+    /// A PSBT could not be constructed from its unsigned transaction or
+    /// deserialized from its binary encoding.
+    Psbt(psbt::Error),
+    /// A PSBT returned by the node could not be decoded.
+    PsbtParse(PsbtParseError),
+    /// A version 2 PSBT is malformed, for the given reason.
+    InvalidPsbtV2(&'static str),
+    /// The purchase memo can't be carried in an OP_RETURN output.
+    InvalidMemo(&'static str),
+    /// A raw transaction returned by the node could not be decoded.
+    Consensus(encode::Error),
+    /// The node's mempool would reject the transaction, for the given reason.
+    MempoolRejected(Txid, String),
+    /// The node could not fully finalize a PSBT.
+    PsbtNotFinalized,
+    /// The external signer failed, with its message.
+    ExternalSigner(String),
+    /// The in-crate finalizer can't complete an input, e.g. one of a script
+    /// type it doesn't know; a node may still be able to.
+    CannotFinalize(OutPoint, &'static str),
+    /// An address was malformed or belongs to another network.
+    Address(address::Error),
+    /// A well-formed address belongs to another network than the configured
+    /// one.
+    AddressNetworkMismatch { expected: Network, got: Network },
+    /// A UTXO listed by the node carries no address.
+    MissingAddress(OutPoint),
+    /// A previous transaction does not have the referenced output.
+    MissingOutput(OutPoint),
+    /// The inscription to list has already been spent.
+    InscriptionAlreadySpent(OutPoint),
+    /// The inscription to list has fewer confirmations than
+    /// `SELLER_MIN_CONF` asks for.
+    InscriptionUnconfirmed {
+        outpoint: OutPoint,
+        confirmations: u32,
+        required: u32,
+    },
+    /// The node's UTXO set disagrees with the transaction an output was
+    /// fetched from.
+    UtxoMismatch(OutPoint),
+    /// A listing URI is malformed or lacks the listing.
+    InvalidListingUri(String, &'static str),
+    /// The base URL of the ord or block explorer is malformed.
+    InvalidExplorerUrl(String, &'static str),
+    /// The ord or block explorer could not be queried.
+    Http(reqwest::Error),
+    /// The ord or block explorer did not answer in time; worth retrying later.
+    HttpTimeout(reqwest::Error),
+    /// The ord explorer answered with an unexpected JSON body.
+    Json(serde_json::Error),
+    /// The seller PSBT isn't validly signed for being combined.
+    InvalidSellerSignature(String),
+    /// The seller PSBT doesn't list the advertised inscription and price.
+    SellerPsbtMismatch(String),
+    /// A seller input can't be signed with the given key, e.g. because it
+    /// pays someone else's script.
+    SellerSigning(OutPoint, String),
+    /// The seller sighash type can't be used for a listing.
+    UnsupportedSighash(EcdsaSighashType, &'static str),
+    /// The listing price is too low for the seller's output to be relayed.
+    PriceBelowDust { price: Amount, dust: Amount },
+    /// The listing price doesn't exceed the inscription output's own value by
+    /// a relayable amount, so the seller PSBT alone could send the
+    /// inscription to the fee.
+    PriceBelowPostage { price: Amount, minimum: Amount },
+    /// The royalty would take the whole listing price.
+    RoyaltyTooHigh { royalty: Amount, price: Amount },
+    /// An entry of `FEE_SPLITS` is malformed.
+    InvalidFeeSplit(String, &'static str),
+    /// The marketplace fees would take more than the listing price.
+    FeesExceedPrice { fees: Amount, price: Amount },
+    /// The seller has no UTXO worth at least the royalty to pair its output
+    /// with.
+    NoRoyaltyFunding(Amount),
+    /// An output would be too small to be relayed.
+    OutputBelowDust { value: Amount, dust: Amount },
+    /// A transaction would pay more than the configured maximum fee rate, in
+    /// sats per vbyte.
+    FeeRateTooHigh {
+        fee_rate: Amount,
+        max_fee_rate: Amount,
+    },
+    /// Adding up output or input values exceeded the range of an amount.
+    Overflow,
+    /// The buyer cannot cover the price plus fees: `required` is the full
+    /// cost, `available` what the buyer can put towards it.
+    InsufficientFunds {
+        required: Amount,
+        available: Amount,
+        shortfall: Amount,
+    },
+    /// The price, service fee and mining fee of a purchase add up to more
+    /// than the buyer's configured maximum.
+    ExceedsMaxTotal { total: Amount, max: Amount },
+    /// The buyer has no UTXOs that are safe to spend.
+    NoSpendableUtxos,
+    /// The buyer's funds would cover the payment, but only in inputs that
+    /// push the transaction past this many weight units.
+    TooManyInputs(usize),
+    /// No buyer UTXO is large enough to split a dummy of this value off,
+    /// with the split's fee and change.
+    CannotCreateDummy(Amount),
+    /// This step broadcasts a transaction the wallet must sign, but PSBTs
+    /// are left for an external signer.
+    SigningDisabled(&'static str),
+    /// The requested postage is less than the dummy and inscriptions that
+    /// make up the buyer's inscription output.
+    PostageTooLow { postage: Amount, minimum: Amount },
+    /// The inscribed sat of a purchase input would land in output `landed`,
+    /// or go to the fee when `None`, instead of the buyer's output 0.
+    InscriptionSatMisplaced { input: usize, landed: Option<usize> },
+    /// A buyer input picked as dummy or payment carries an inscription.
+    InscriptionSpent(OutPoint),
+    /// A broadcast output did not reach the wallet in time.
+    UtxoTimeout(OutPoint, Duration),
+    /// The block explorer returned something else than the transaction asked
+    /// for.
+    InvalidExplorerTx(Txid),
+    /// ord answered a batch lookup with another number of outputs than it
+    /// was asked for, so the answers can't be matched to the outpoints.
+    OrdOutputCount { requested: usize, returned: usize },
+    /// A transaction to be bumped spends an input the wallet cannot re-sign.
+    ForeignInput(Txid, OutPoint),
+    /// A task on the async runtime's blocking pool was cancelled before it
+    /// finished, as happens when the runtime shuts down.
+    TaskCancelled,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// [`Error::InsufficientFunds`] with the shortfall worked out.
+    pub fn insufficient_funds(required: Amount, available: Amount) -> Self {
+        Error::InsufficientFunds {
+            required,
+            available,
+            shortfall: required.checked_sub(available).unwrap_or(Amount::ZERO),
+        }
+    }
+
+    /// Whether the same call may succeed if tried again later.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Error::HttpTimeout(_) | Error::UtxoTimeout(_, _))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Env(name, _) => write!(f, "environment variable {} is not usable", name),
+            Error::InvalidEnv(name, value) => {
+                write!(
+                    f,
+                    "environment variable {} has invalid value {:?}",
+                    name, value
+                )
+            }
+            Error::InvalidArgument(name, value) => {
+                write!(f, "option --{} has invalid value {:?}", name, value)
+            }
+            Error::InvalidConfig(problems) => {
+                write!(f, "the environment is not usable:")?;
+                for (name, problem) in problems {
+                    write!(f, "\n  {}: {}", name, problem)?;
+                }
+                Ok(())
+            }
+            Error::Io(_) => write!(f, "file access failed"),
+            Error::UnknownNetwork(s) => write!(
+                f,
+                "unknown BITCOIN_NETWORK {:?}, expected mainnet, testnet, regtest or signet",
+                s
+            ),
+            Error::OutPointParse(s, _) => write!(f, "invalid outpoint {:?}", s),
+            Error::Rpc(_) => write!(f, "bitcoin rpc call failed"),
+            Error::NodeUnreachable { which, .. } => {
+                write!(f, "cannot reach the {} node", which)
+            }
+            Error::NetworkMismatch {
+                node,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "the {} node is on chain {:?} but the configured network is {}",
+                node, actual, expected
+            ),
+            Error::Psbt(_) => write!(f, "invalid psbt"),
+            Error::PsbtParse(_) => write!(f, "failed to decode psbt"),
+            Error::InvalidPsbtV2(reason) => write!(f, "invalid version 2 psbt: {}", reason),
+            Error::InvalidMemo(reason) => write!(f, "invalid memo: {}", reason),
+            Error::Consensus(_) => write!(f, "failed to decode transaction"),
+            Error::MempoolRejected(txid, reason) => {
+                write!(f, "mempool would reject tx {}: {}", txid, reason)
+            }
+            Error::ExternalSigner(message) => write!(f, "external signer failed: {}", message),
+            Error::PsbtNotFinalized => write!(f, "psbt could not be finalized"),
+            Error::CannotFinalize(out, reason) => {
+                write!(f, "cannot finalize input {}: {}", out, reason)
+            }
+            Error::Address(_) => write!(f, "invalid address"),
+            Error::AddressNetworkMismatch { expected, got } => write!(
+                f,
+                "address is for {} but the configured network is {}",
+                got, expected
+            ),
+            Error::MissingAddress(out) => write!(f, "utxo {} has no address", out),
+            Error::MissingOutput(out) => write!(f, "output {} does not exist", out),
+            Error::InscriptionAlreadySpent(out) => {
+                write!(f, "inscription output {} is already spent", out)
+            }
+            Error::InscriptionUnconfirmed {
+                outpoint,
+                confirmations,
+                required,
+            } => write!(
+                f,
+                "inscription output {} has {} confirmations, {} required",
+                outpoint, confirmations, required
+            ),
+            Error::UtxoMismatch(out) => {
+                write!(f, "utxo {} does not match its transaction", out)
+            }
+            Error::InvalidListingUri(uri, reason) => {
+                write!(f, "invalid listing uri {:?}: {}", uri, reason)
+            }
+            Error::InvalidExplorerUrl(url, reason) => {
+                write!(f, "invalid explorer url {:?}: {}", url, reason)
+            }
+            Error::Http(_) => write!(f, "explorer request failed"),
+            Error::HttpTimeout(_) => write!(f, "explorer did not answer in time"),
+            Error::Json(_) => write!(f, "unexpected ord explorer response"),
+            Error::InvalidSellerSignature(reason) => {
+                write!(f, "invalid seller signature: {}", reason)
+            }
+            Error::SellerPsbtMismatch(reason) => {
+                write!(f, "seller psbt does not match the listing: {}", reason)
+            }
+            Error::SellerSigning(out, reason) => {
+                write!(f, "cannot sign seller input {}: {}", out, reason)
+            }
+            Error::UnsupportedSighash(sighash, reason) => {
+                write!(f, "cannot list with sighash {}: {}", sighash, reason)
+            }
+            Error::PriceBelowDust { price, dust } => write!(
+                f,
+                "listing price {} is below the {} dust limit of the seller output",
+                price, dust
+            ),
+            Error::PriceBelowPostage { price, minimum } => write!(
+                f,
+                "listing price {} must be at least {}, the inscription's postage plus dust",
+                price, minimum
+            ),
+            Error::RoyaltyTooHigh { royalty, price } => write!(
+                f,
+                "royalty of {} does not fit in the listing price of {}",
+                royalty, price
+            ),
+            Error::InvalidFeeSplit(split, reason) => {
+                write!(f, "invalid fee split {:?}: {}", split, reason)
+            }
+            Error::FeesExceedPrice { fees, price } => write!(
+                f,
+                "marketplace fees of {} exceed the listing price of {}",
+                fees, price
+            ),
+            Error::NoRoyaltyFunding(royalty) => write!(
+                f,
+                "seller has no utxo of at least the {} royalty to pay it with",
+                royalty
+            ),
+            Error::OutputBelowDust { value, dust } => {
+                write!(f, "output of {} is below its {} dust limit", value, dust)
+            }
+            Error::FeeRateTooHigh {
+                fee_rate,
+                max_fee_rate,
+            } => write!(
+                f,
+                "fee rate of {} sat/vB exceeds the maximum of {} sat/vB",
+                fee_rate.to_sat(),
+                max_fee_rate.to_sat()
+            ),
+            Error::Overflow => write!(f, "amount overflow"),
+            Error::ExceedsMaxTotal { total, max } => write!(
+                f,
+                "purchase would cost {} in price and fees, more than the maximum of {}",
+                total, max
+            ),
+            Error::InsufficientFunds {
+                required,
+                available,
+                shortfall,
+            } => write!(
+                f,
+                "buyer doesn't have enough funds: {} needed, {} available, {} short",
+                required, available, shortfall
+            ),
+            Error::NoSpendableUtxos => write!(f, "buyer doesn't have any spendable utxos"),
+            Error::TooManyInputs(max_weight) => write!(
+                f,
+                "paying takes more inputs than fit in {} weight units, consolidate the buyer's utxos first",
+                max_weight
+            ),
+            Error::CannotCreateDummy(value) => write!(
+                f,
+                "no buyer utxo can be split into a {} dummy plus fee and change",
+                value
+            ),
+            Error::SigningDisabled(step) => write!(
+                f,
+                "{} needs the wallet to sign, but signing is disabled",
+                step
+            ),
+            Error::PostageTooLow { postage, minimum } => write!(
+                f,
+                "postage {} is below the {} of the dummy and inscriptions",
+                postage, minimum
+            ),
+            Error::InscriptionSatMisplaced {
+                input,
+                landed: Some(output),
+            } => write!(
+                f,
+                "inscribed sat of input {} lands in output {} instead of the buyer's",
+                input, output
+            ),
+            Error::InscriptionSatMisplaced {
+                input,
+                landed: None,
+            } => write!(
+                f,
+                "inscribed sat of input {} would not reach the buyer's output",
+                input
+            ),
+            Error::InscriptionSpent(out) => write!(
+                f,
+                "refusing to spend inscription {} as dummy or payment",
+                out
+            ),
+            Error::UtxoTimeout(out, timeout) => write!(
+                f,
+                "utxo {} did not show up in the wallet within {}s",
+                out,
+                timeout.as_secs()
+            ),
+            Error::InvalidExplorerTx(txid) => {
+                write!(f, "block explorer returned an invalid transaction {}", txid)
+            }
+            Error::OrdOutputCount {
+                requested,
+                returned,
+            } => write!(
+                f,
+                "ord returned {} outputs for {} outpoints",
+                returned, requested
+            ),
+            Error::ForeignInput(txid, out) => write!(
+                f,
+                "cannot bump {}: input {} does not belong to the wallet",
+                txid, out
+            ),
+            Error::TaskCancelled => write!(f, "blocking task was cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Env(_, e) => Some(e),
+            Error::OutPointParse(_, e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Rpc(e) | Error::NodeUnreachable { source: e, .. } => Some(e),
+            Error::Psbt(e) => Some(e),
+            Error::PsbtParse(e) => Some(e),
+            Error::Consensus(e) => Some(e),
+            Error::Address(e) => Some(e),
+            Error::Http(e) | Error::HttpTimeout(e) => Some(e),
+            Error::Json(e) => Some(e),
+            Error::InvalidEnv(_, _)
+            | Error::InvalidArgument(_, _)
+            | Error::InvalidConfig(_)
+            | Error::UnknownNetwork(_)
+            | Error::MempoolRejected(_, _)
+            | Error::InvalidPsbtV2(_)
+            | Error::InvalidMemo(_)
+            | Error::ExternalSigner(_)
+            | Error::PsbtNotFinalized
+            | Error::CannotFinalize(_, _)
+            | Error::AddressNetworkMismatch { .. }
+            | Error::NetworkMismatch { .. }
+            | Error::MissingAddress(_)
+            | Error::MissingOutput(_)
+            | Error::InscriptionAlreadySpent(_)
+            | Error::InscriptionUnconfirmed { .. }
+            | Error::UtxoMismatch(_)
+            | Error::InvalidListingUri(_, _)
+            | Error::InvalidExplorerUrl(_, _)
+            | Error::InvalidSellerSignature(_)
+            | Error::SellerPsbtMismatch(_)
+            | Error::SellerSigning(_, _)
+            | Error::UnsupportedSighash(_, _)
+            | Error::PriceBelowDust { .. }
+            | Error::PriceBelowPostage { .. }
+            | Error::RoyaltyTooHigh { .. }
+            | Error::InvalidFeeSplit(_, _)
+            | Error::FeesExceedPrice { .. }
+            | Error::NoRoyaltyFunding(_)
+            | Error::OutputBelowDust { .. }
+            | Error::FeeRateTooHigh { .. }
+            | Error::Overflow
+            | Error::InsufficientFunds { .. }
+            | Error::ExceedsMaxTotal { .. }
+            | Error::NoSpendableUtxos
+            | Error::TooManyInputs(_)
+            | Error::CannotCreateDummy(_)
+            | Error::SigningDisabled(_)
+            | Error::PostageTooLow { .. }
+            | Error::InscriptionSatMisplaced { .. }
+            | Error::InscriptionSpent(_)
+            | Error::UtxoTimeout(_, _)
+            | Error::InvalidExplorerTx(_)
+            | Error::OrdOutputCount { .. }
+            | Error::ForeignInput(_, _)
+            | Error::TaskCancelled => None,
+        }
+    }
+}
+
+impl From<bitcoincore_rpc::Error> for Error {
+    fn from(e: bitcoincore_rpc::Error) -> Self {
+        Error::Rpc(e)
+    }
+}
+
+impl From<psbt::Error> for Error {
+    fn from(e: psbt::Error) -> Self {
+        Error::Psbt(e)
+    }
+}
+
+impl From<PsbtParseError> for Error {
+    fn from(e: PsbtParseError) -> Self {
+        Error::PsbtParse(e)
+    }
+}
+
+impl From<encode::Error> for Error {
+    fn from(e: encode::Error) -> Self {
+        Error::Consensus(e)
+    }
+}
+
+impl From<address::Error> for Error {
+    fn from(e: address::Error) -> Self {
+        Error::Address(e)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            Error::HttpTimeout(e)
+        } else {
+            Error::Http(e)
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
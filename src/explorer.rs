@@ -0,0 +1,191 @@
+use crate::{Error, Result};
+use bitcoin::consensus;
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::{Transaction, Txid};
+use reqwest::blocking::Client;
+use reqwest::header::ACCEPT;
+use reqwest::Url;
+use std::time::Duration;
+
+/// Parses `base` as the root of an HTTP explorer, with or without a trailing
+/// slash. Only http and https are accepted.
+pub fn parse_explorer_url(base: &str) -> Result<Url> {
+    let invalid = |reason| Error::InvalidExplorerUrl(base.to_string(), reason);
+    let mut url = Url::parse(base).map_err(|_| invalid("not a url"))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(invalid("expected an http or https url"));
+    }
+    // without it `join` would replace the last path segment
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    Ok(url)
+}
+
+/// The endpoint `path` of the explorer rooted at `base`.
+pub fn explorer_endpoint(base: &Url, path: &str) -> Result<Url> {
+    base.join(path)
+        .map_err(|_| Error::InvalidExplorerUrl(base.to_string(), "cannot build an endpoint url"))
+}
+
+/// An esplora API, e.g. mempool.space's, serving the transactions a pruned
+/// node or one without `txindex` can't.
+#[derive(Debug, Clone)]
+pub struct Esplora {
+    base: Url,
+    client: Client,
+}
+
+impl Esplora {
+    /// The API rooted at `base`, e.g. `https://mempool.space/testnet/api/`.
+    pub fn new(base: &str, timeout: Duration) -> Result<Self> {
+        Ok(Esplora {
+            base: parse_explorer_url(base)?,
+            client: Client::builder()
+                .connect_timeout(timeout)
+                .timeout(timeout)
+                .build()?,
+        })
+    }
+
+    /// Fetches `txid` as raw hex and checks it really is that transaction,
+    /// since the explorer isn't trusted like the node.
+    pub fn fetch_prevout_tx(&self, txid: Txid) -> Result<Transaction> {
+        let resp = self
+            .client
+            .get(explorer_endpoint(&self.base, &format!("tx/{}/hex", txid))?)
+            .header(ACCEPT, "text/plain")
+            .send()?
+            .error_for_status()?;
+        let raw_tx =
+            Vec::<u8>::from_hex(resp.text()?.trim()).map_err(|_| Error::InvalidExplorerTx(txid))?;
+        let tx: Transaction = consensus::deserialize(&raw_tx)?;
+        if tx.txid() != txid {
+            return Err(Error::InvalidExplorerTx(txid));
+        }
+        Ok(tx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trailing_slash_makes_no_difference() {
+        for (with, without) in [
+            ("http://127.0.0.1:8080/", "http://127.0.0.1:8080"),
+            ("https://ord.example/api/", "https://ord.example/api"),
+        ] {
+            let with = parse_explorer_url(with).unwrap();
+            let without = parse_explorer_url(without).unwrap();
+            assert_eq!(with, without);
+            assert_eq!(
+                explorer_endpoint(&with, "output/abc:0").unwrap(),
+                explorer_endpoint(&without, "output/abc:0").unwrap()
+            );
+        }
+        assert_eq!(
+            explorer_endpoint(
+                &parse_explorer_url("https://ord.example/api").unwrap(),
+                "outputs"
+            )
+            .unwrap()
+            .as_str(),
+            "https://ord.example/api/outputs"
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_and_non_http_urls() {
+        for base in ["not a url", "ftp://ord.example/"] {
+            assert!(matches!(
+                parse_explorer_url(base),
+                Err(Error::InvalidExplorerUrl(_, _))
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod mock {
+    //! A local HTTP server standing in for ord or esplora.
+
+    use reqwest::Url;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+    use std::time::Duration;
+
+    /// What the server answers one request with.
+    pub(crate) struct Response {
+        pub status: u16,
+        pub body: String,
+        /// How long the server waits before answering.
+        pub delay: Duration,
+    }
+
+    impl Response {
+        pub(crate) fn ok(body: impl Into<String>) -> Self {
+            Response {
+                status: 200,
+                body: body.into(),
+                delay: Duration::ZERO,
+            }
+        }
+    }
+
+    /// Answers one connection with each of `responses` in turn, returning
+    /// the server's root and a handle yielding every request received, as
+    /// `METHOD path body`, once all are answered.
+    pub(crate) fn serve(responses: Vec<Response>) -> (Url, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind a local port");
+        let url = format!("http://{}/", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (stream, _) = listener.accept().expect("accept a connection");
+                let mut reader = BufReader::new(stream);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut content_length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = header.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                let mut parts = request_line.split_whitespace();
+                requests.push(format!(
+                    "{} {} {}",
+                    parts.next().unwrap_or_default(),
+                    parts.next().unwrap_or_default(),
+                    String::from_utf8_lossy(&body)
+                ));
+
+                thread::sleep(response.delay);
+                // a client that gave up has closed the connection already
+                let _ = write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 {} X\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    response.status,
+                    response.body.len(),
+                    response.body
+                );
+            }
+            requests
+        });
+        (url, handle)
+    }
+}
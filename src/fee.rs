@@ -0,0 +1,579 @@
+use crate::config::Config;
+use crate::input::InputKind;
+use crate::rpc::BitcoinRpc;
+use crate::utxo::fetch_prevout;
+use crate::{env_amount, env_or, parse_checked_address, Error, MarketplaceContext, Result};
+use bitcoin::{Address, Amount, Network, ScriptBuf, Transaction, TxOut, VarInt};
+use std::fmt;
+use std::str::FromStr;
+
+/// Confirmation target used when asking the node for a fee estimate.
+pub const DEFAULT_CONF_TARGET: u16 = 6;
+
+/// Highest fee rate paid when `MAX_FEE_RATE_SAT_VB` is unset, in sats per
+/// vbyte.
+pub const DEFAULT_MAX_FEE_RATE: Amount = Amount::from_sat(500);
+
+/// How fee rates are picked, all rates in sats per vbyte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeeConfig {
+    /// Blocks the node's estimate aims to confirm within.
+    pub conf_target: u16,
+    /// Lowest rate ever paid.
+    pub min_fee_rate: Amount,
+    /// Rate paid when the node has no estimate.
+    pub fallback_fee_rate: Amount,
+    /// Highest rate a transaction may pay, guarding against estimator
+    /// spikes and surplus left to the miner.
+    pub max_fee_rate: Amount,
+}
+
+impl Default for FeeConfig {
+    fn default() -> Self {
+        FeeConfig {
+            conf_target: DEFAULT_CONF_TARGET,
+            min_fee_rate: Amount::ONE_SAT,
+            fallback_fee_rate: Amount::ONE_SAT,
+            max_fee_rate: DEFAULT_MAX_FEE_RATE,
+        }
+    }
+}
+
+impl FeeConfig {
+    /// Reads `CONF_TARGET`, `MIN_FEE_RATE_SAT_VB`, `FALLBACK_FEE_RATE_SAT_VB`
+    /// (the minimum by default) and `MAX_FEE_RATE_SAT_VB`.
+    pub fn from_env() -> Result<Self> {
+        let min_fee_rate = env_amount("MIN_FEE_RATE_SAT_VB", Amount::ONE_SAT)?;
+        Ok(FeeConfig {
+            conf_target: env_or("CONF_TARGET", DEFAULT_CONF_TARGET)?,
+            min_fee_rate,
+            fallback_fee_rate: env_amount("FALLBACK_FEE_RATE_SAT_VB", min_fee_rate)?,
+            max_fee_rate: env_amount("MAX_FEE_RATE_SAT_VB", DEFAULT_MAX_FEE_RATE)?,
+        })
+    }
+
+    /// Fails with [`Error::FeeRateTooHigh`] if `fee` over `vsize` vbytes
+    /// pays more than `max_fee_rate`.
+    pub fn check_fee(&self, fee: Amount, vsize: usize) -> Result<()> {
+        let max_fee = fee_for_vsize(self.max_fee_rate, vsize)?;
+        if fee > max_fee {
+            return Err(Error::FeeRateTooHigh {
+                fee_rate: Amount::from_sat(fee.to_sat().div_ceil(vsize.max(1) as u64)),
+                max_fee_rate: self.max_fee_rate,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Returns the node's fee estimate for confirmation within the configured
+/// target as sats per vbyte, the fallback rate if it has none, never going
+/// below the minimum. Fails with [`Error::FeeRateTooHigh`] rather than pay
+/// more than the maximum.
+pub fn estimate_fee_rate(client: &dyn BitcoinRpc, config: &FeeConfig) -> Result<Amount> {
+    let estimate = client.estimate_smart_fee(config.conf_target, None)?;
+
+    // the node reports BTC/kvB
+    let fee_rate = match estimate.fee_rate {
+        Some(per_kvb) => Amount::from_sat(per_kvb.to_sat().div_ceil(1000)),
+        None => config.fallback_fee_rate,
+    }
+    .max(config.min_fee_rate);
+    if fee_rate > config.max_fee_rate {
+        return Err(Error::FeeRateTooHigh {
+            fee_rate,
+            max_fee_rate: config.max_fee_rate,
+        });
+    }
+    Ok(fee_rate)
+}
+
+/// Confirmation targets [`simulate_fees`] compares unless told otherwise.
+pub const DEFAULT_SIMULATED_TARGETS: &[u16] = &[1, 3, 6, 12, 24, 144];
+
+/// What `tx` would cost at the buyer node's estimate for each of `targets`,
+/// as `(conf_target, fee_rate, fee)` with rates in sats per vbyte. The
+/// configured minimum and fallback rates apply but the maximum doesn't, so
+/// rates a purchase would refuse still show. The size is estimated as if
+/// every input were signed, its previous output fetched to tell its kind.
+pub fn simulate_fees(
+    ctx: &MarketplaceContext,
+    tx: &Transaction,
+    targets: &[u16],
+) -> Result<Vec<(u16, Amount, Amount)>> {
+    let input_kinds = tx
+        .input
+        .iter()
+        .map(|input| {
+            fetch_prevout(ctx, input.previous_output)
+                .map(|prevout| InputKind::from_script(&prevout.script_pubkey))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let vsize = estimate_vsize(tx, &input_kinds);
+    targets
+        .iter()
+        .map(|&conf_target| {
+            let config = FeeConfig {
+                conf_target,
+                max_fee_rate: Amount::MAX_MONEY,
+                ..ctx.fee
+            };
+            let fee_rate = estimate_fee_rate(ctx.buyer_node.as_ref(), &config)?;
+            Ok((conf_target, fee_rate, fee_for_vsize(fee_rate, vsize)?))
+        })
+        .collect()
+}
+
+/// Bitcoin Core's default `-dustrelayfee`, in sats per kvB.
+const DUST_RELAY_FEE: u64 = 3000;
+
+/// The smallest value an output paying `script` may carry and still be
+/// relayed: what it costs at the dust relay fee to create and later spend it.
+pub fn dust_limit(script: &ScriptBuf) -> Amount {
+    if script.is_op_return() {
+        return Amount::ZERO;
+    }
+    let output_size = 8 + VarInt(script.len() as u64).len() + script.len();
+    // outpoint + script_sig length + sequence, plus the signature data
+    let spend_size = if script.is_witness_program() {
+        32 + 4 + 1 + 107 / 4 + 4
+    } else {
+        32 + 4 + 1 + 107 + 4
+    };
+    Amount::from_sat((output_size + spend_size) as u64 * DUST_RELAY_FEE / 1000)
+}
+
+/// Fails with [`Error::OutputBelowDust`] if `out` would not be relayed.
+pub fn check_dust(out: &TxOut) -> Result<()> {
+    let dust = dust_limit(&out.script_pubkey);
+    if Amount::from_sat(out.value) < dust {
+        return Err(Error::OutputBelowDust {
+            value: Amount::from_sat(out.value),
+            dust,
+        });
+    }
+    Ok(())
+}
+
+/// Adds up `values`, failing with [`Error::Overflow`] instead of wrapping
+/// around on corrupt or malicious amounts.
+pub fn checked_sum(values: impl IntoIterator<Item = Amount>) -> Result<Amount> {
+    values
+        .into_iter()
+        .try_fold(Amount::ZERO, |sum, value| sum.checked_add(value))
+        .ok_or(Error::Overflow)
+}
+
+/// Fails unless the finalized `tx`, spending `prevouts` in input order, pays
+/// a fee within its inputs and at most `ceiling` sats per vbyte, the last
+/// guard against building a transaction that hands its funds to the miner.
+pub fn assert_sane_fee(tx: &Transaction, prevouts: &[TxOut], ceiling: Amount) -> Result<()> {
+    let inputs = checked_sum(
+        prevouts
+            .iter()
+            .map(|prevout| Amount::from_sat(prevout.value)),
+    )?;
+    let outputs = checked_sum(
+        tx.output
+            .iter()
+            .map(|output| Amount::from_sat(output.value)),
+    )?;
+    // more out than in would never be accepted, and means the prevouts are off
+    let fee = inputs.checked_sub(outputs).ok_or(Error::Overflow)?;
+    let vsize = tx.vsize();
+    let max_fee = fee_for_vsize(ceiling, vsize)?;
+    if fee > max_fee {
+        return Err(Error::FeeRateTooHigh {
+            fee_rate: Amount::from_sat(fee.to_sat().div_ceil(vsize.max(1) as u64)),
+            max_fee_rate: ceiling,
+        });
+    }
+    Ok(())
+}
+
+/// What `vsize` vbytes cost at `fee_rate` sats per vbyte.
+pub fn fee_for_vsize(fee_rate: Amount, vsize: usize) -> Result<Amount> {
+    fee_rate.checked_mul(vsize as u64).ok_or(Error::Overflow)
+}
+
+/// Lowest marketplace fee charged when `MARKETPLACE_FEE_BPS` is set.
+pub const DEFAULT_MARKETPLACE_FEE_FLOOR: Amount = Amount::from_sat(546);
+
+/// The marketplace's cut of `price`, `basis_points` being hundredths of a
+/// percent.
+pub fn marketplace_fee(price: Amount, basis_points: u16) -> Amount {
+    Amount::from_sat((price.to_sat() as u128 * basis_points as u128 / 10_000) as u64)
+}
+
+/// The service fee the buyer pays on a sale at `price`: the configured basis
+/// points of the price but at least the fee floor, or the flat service fee
+/// when no basis points are configured.
+pub fn service_fee(config: &Config, price: Amount) -> Amount {
+    match config.marketplace_fee_bps {
+        Some(basis_points) => {
+            marketplace_fee(price, basis_points).max(config.marketplace_fee_floor)
+        }
+        None => config.service_fee,
+    }
+}
+
+/// Who the marketplace fee is charged to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FeePayer {
+    /// The buyer's payment inputs fund the fee on top of the price.
+    #[default]
+    Buyer,
+    /// The fee comes out of the seller's payment output, so the buyer pays
+    /// just the price. The seller's signature commits to that output, so this
+    /// is decided when the listing is signed.
+    Seller,
+}
+
+impl FromStr for FeePayer {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "buyer" => Ok(FeePayer::Buyer),
+            "seller" => Ok(FeePayer::Seller),
+            _ => Err(format!("unknown fee payer {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for FeePayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FeePayer::Buyer => write!(f, "buyer"),
+            FeePayer::Seller => write!(f, "seller"),
+        }
+    }
+}
+
+/// A fee recipient's cut of each sale.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeeShare {
+    /// A fixed amount per listing.
+    Flat(Amount),
+    /// Hundredths of a percent of the listing price.
+    BasisPoints(u16),
+}
+
+/// One recipient of the marketplace fee, e.g. the platform or a referrer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeeSplit {
+    pub recipient: Address,
+    pub share: FeeShare,
+}
+
+impl FeeSplit {
+    /// The recipient's cut of a sale at `price`.
+    pub fn amount(&self, price: Amount) -> Amount {
+        match self.share {
+            FeeShare::Flat(amount) => amount,
+            FeeShare::BasisPoints(basis_points) => marketplace_fee(price, basis_points),
+        }
+    }
+}
+
+/// Parses fee splits written as comma separated `<address>:<share>`, the
+/// share being a number of sats or basis points suffixed with `bps`, e.g.
+/// `tb1q...:500bps,tb1q...:100`. Basis points may add up to at most 10000.
+pub fn parse_fee_splits(value: &str, network: Network) -> Result<Vec<FeeSplit>> {
+    let mut splits = Vec::new();
+    for entry in value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        let invalid = |reason| Error::InvalidFeeSplit(entry.to_string(), reason);
+        let (address, share) = entry
+            .rsplit_once(':')
+            .ok_or_else(|| invalid("expected <address>:<share>"))?;
+        let recipient = parse_checked_address(address, network).map_err(|e| match e {
+            Error::Address(_) => invalid("invalid address"),
+            e => e,
+        })?;
+        let share = match share.strip_suffix("bps") {
+            Some(basis_points) => FeeShare::BasisPoints(
+                basis_points
+                    .parse()
+                    .map_err(|_| invalid("invalid basis points"))?,
+            ),
+            None => FeeShare::Flat(Amount::from_sat(
+                share.parse().map_err(|_| invalid("invalid amount"))?,
+            )),
+        };
+        splits.push(FeeSplit { recipient, share });
+    }
+    if splits.is_empty() {
+        return Err(Error::InvalidFeeSplit(value.to_string(), "no splits"));
+    }
+    let basis_points = splits
+        .iter()
+        .map(|split| match split.share {
+            FeeShare::BasisPoints(basis_points) => u32::from(basis_points),
+            FeeShare::Flat(_) => 0,
+        })
+        .sum::<u32>();
+    if basis_points > 10_000 {
+        return Err(Error::InvalidFeeSplit(
+            value.to_string(),
+            "basis points add up to more than the price",
+        ));
+    }
+    Ok(splits)
+}
+
+/// Everything the marketplace charges on one sale at `price`, all fee splits
+/// together, before the checks of [`service_fee_outputs`].
+pub fn total_service_fee(config: &Config, price: Amount) -> Result<Amount> {
+    match &config.fee_splits {
+        Some(splits) => checked_sum(splits.iter().map(|split| split.amount(price))),
+        None => Ok(service_fee(config, price)),
+    }
+}
+
+/// What the seller's payment output carries on a sale at `price`: all of it,
+/// or what the fee leaves when the config's `fee_payer` is the seller.
+pub fn seller_payout(config: &Config, price: Amount) -> Result<Amount> {
+    match config.fee_payer {
+        FeePayer::Buyer => Ok(price),
+        FeePayer::Seller => {
+            let fees = total_service_fee(config, price)?;
+            price
+                .checked_sub(fees)
+                .ok_or(Error::FeesExceedPrice { fees, price })
+        }
+    }
+}
+
+/// The lowest price whose [`seller_payout`] is `payout`, for a buyer who
+/// only sees the seller's payment output but has to charge the fee on the
+/// price. With a basis point fee several prices can leave the same payout.
+pub fn price_for_payout(config: &Config, payout: Amount) -> Result<Amount> {
+    let payout_at = |price: u64| {
+        let price = Amount::from_sat(price);
+        Ok::<_, Error>(price.checked_sub(total_service_fee(config, price)?))
+    };
+    // what a sale leaves the seller grows with its price
+    let (mut low, mut high) = (payout.to_sat(), Amount::MAX_MONEY.to_sat());
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if payout_at(mid)?.is_some_and(|left| left >= payout) {
+            high = mid;
+        } else {
+            low = mid + 1;
+        }
+    }
+    if payout_at(low)? != Some(payout) {
+        return Err(Error::SellerPsbtMismatch(format!(
+            "seller payment of {} leaves no room for the marketplace fee",
+            payout
+        )));
+    }
+    Ok(Amount::from_sat(low))
+}
+
+/// The marketplace fee outputs of a purchase of listings at
+/// `listing_prices`: one per recipient of the config's fee splits, each
+/// charged per listing, or without splits a single [`service_fee`] output to
+/// the marketplace address. Every output must clear the dust limit and
+/// together they may not exceed the listings' total price.
+pub fn service_fee_outputs(config: &Config, listing_prices: &[Amount]) -> Result<Vec<TxOut>> {
+    let price = checked_sum(listing_prices.iter().copied())?;
+    let outputs = match &config.fee_splits {
+        Some(splits) => splits
+            .iter()
+            .map(|split| {
+                let fee = checked_sum(listing_prices.iter().map(|&price| split.amount(price)))?;
+                Ok(TxOut {
+                    value: fee.to_sat(),
+                    script_pubkey: split.recipient.script_pubkey(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?,
+        None => {
+            // charged per listing so the fee floor applies to each inscription
+            let fee = checked_sum(
+                listing_prices
+                    .iter()
+                    .map(|&price| service_fee(config, price)),
+            )?;
+            vec![TxOut {
+                value: fee.to_sat(),
+                script_pubkey: config.marketplace_address.script_pubkey(),
+            }]
+        }
+    };
+    for output in &outputs {
+        check_dust(output)?;
+    }
+    let fees = checked_sum(outputs.iter().map(|output| Amount::from_sat(output.value)))?;
+    if fees > price {
+        return Err(Error::FeesExceedPrice { fees, price });
+    }
+    Ok(outputs)
+}
+
+/// Estimates the virtual size `tx` will have once every input is signed,
+/// `input_kinds[i]` describing how `tx.input[i]` is spent.
+pub fn estimate_vsize(tx: &Transaction, input_kinds: &[InputKind]) -> usize {
+    estimate_weight(tx, input_kinds).div_ceil(4)
+}
+
+/// Estimates the weight `tx` will have once every input is signed, as
+/// [`estimate_vsize`] does.
+pub fn estimate_weight(tx: &Transaction, input_kinds: &[InputKind]) -> usize {
+    let outputs_size = tx
+        .output
+        .iter()
+        .map(|out| {
+            let script_len = out.script_pubkey.len();
+            8 + VarInt(script_len as u64).len() + script_len
+        })
+        .sum::<usize>();
+    // version + locktime + input/output counts
+    let base_size = 4
+        + 4
+        + VarInt(tx.input.len() as u64).len()
+        + VarInt(tx.output.len() as u64).len()
+        + outputs_size;
+
+    let mut weight = base_size * 4 + input_kinds.iter().map(|t| t.weight()).sum::<usize>();
+    if input_kinds.iter().any(|t| t.is_segwit()) {
+        // segwit marker and flag
+        weight += 2;
+    }
+    weight
+}
+
+/// The weight of an output paying `script`.
+pub fn output_weight(script: &ScriptBuf) -> usize {
+    (8 + VarInt(script.len() as u64).len() + script.len()) * 4
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::mock::{funding_tx, key, p2tr, p2wpkh, Market};
+    use bitcoin::secp256k1::Secp256k1;
+
+    #[test]
+    fn dust_limits_match_core() {
+        let pubkey = key(1).public_key(&Secp256k1::new());
+        let p2pkh = ScriptBuf::new_p2pkh(&pubkey.pubkey_hash());
+        assert_eq!(dust_limit(&p2pkh), Amount::from_sat(546));
+        assert_eq!(
+            dust_limit(&p2wpkh(1).script_pubkey()),
+            Amount::from_sat(294)
+        );
+        assert_eq!(dust_limit(&p2tr(1).script_pubkey()), Amount::from_sat(330));
+    }
+
+    #[test]
+    fn check_dust_rejects_one_sat_below_the_limit() {
+        let script_pubkey = p2tr(1).script_pubkey();
+        assert!(check_dust(&TxOut {
+            value: 330,
+            script_pubkey: script_pubkey.clone(),
+        })
+        .is_ok());
+        assert!(matches!(
+            check_dust(&TxOut {
+                value: 329,
+                script_pubkey,
+            }),
+            Err(Error::OutputBelowDust { .. })
+        ));
+    }
+
+    #[test]
+    fn sums_near_u64_max_fail_cleanly() {
+        let near_max = Amount::from_sat(u64::MAX - 1);
+        assert_eq!(
+            checked_sum([near_max, Amount::from_sat(1)]).unwrap(),
+            Amount::from_sat(u64::MAX)
+        );
+        assert!(matches!(
+            checked_sum([near_max, Amount::from_sat(2)]),
+            Err(Error::Overflow)
+        ));
+        assert!(matches!(fee_for_vsize(near_max, 2), Err(Error::Overflow)));
+    }
+
+    #[test]
+    fn estimate_above_the_ceiling_is_refused() {
+        let mut market = Market::new();
+        market.ctx.fee.max_fee_rate = Amount::from_sat(50);
+        // 51 sats per vbyte
+        market.buyer.state().fee_rate = Some(Amount::from_sat(51_000));
+
+        assert!(matches!(
+            estimate_fee_rate(&market.buyer, &market.ctx.fee),
+            Err(Error::FeeRateTooHigh { fee_rate, max_fee_rate })
+                if fee_rate == Amount::from_sat(51) && max_fee_rate == Amount::from_sat(50)
+        ));
+        assert!(matches!(
+            market.buy(market.list()),
+            Err(Error::FeeRateTooHigh { .. })
+        ));
+        assert!(market.buyer.state().sent.is_empty());
+    }
+
+    #[test]
+    fn simulation_follows_the_estimate_of_each_target() {
+        let mut market = Market::new();
+        let tx = market.buy(market.list()).unwrap().unsigned_tx;
+        market
+            .buyer
+            .state()
+            .fee_rates
+            .extend([(1, Amount::from_sat(20_000)), (6, Amount::from_sat(5_000))]);
+        // shown even though a purchase would refuse it
+        market.ctx.fee.max_fee_rate = Amount::from_sat(10);
+
+        let vsize = estimate_vsize(&tx, &[InputKind::P2wpkh; 3]) as u64;
+        let simulated = simulate_fees(&market.ctx, &tx, &[1, 6, 144]).unwrap();
+        assert_eq!(
+            simulated,
+            [1, 6, 144].map(|target| {
+                let rate = match target {
+                    1 => 20,
+                    6 => 5,
+                    _ => 1,
+                };
+                (
+                    target,
+                    Amount::from_sat(rate),
+                    Amount::from_sat(rate * vsize),
+                )
+            })
+        );
+    }
+
+    #[test]
+    fn sane_fee_check_rejects_an_overpaying_transaction() {
+        let prevouts = funding_tx(10, &[(100_000, &p2wpkh(1))]).output;
+        let mut tx = funding_tx(11, &[(0, &p2wpkh(2))]);
+        let vsize = tx.vsize() as u64;
+        let ceiling = Amount::from_sat(50);
+
+        tx.output[0].value = 100_000 - 50 * vsize;
+        assert_sane_fee(&tx, &prevouts, ceiling).unwrap();
+
+        tx.output[0].value -= 1;
+        assert!(matches!(
+            assert_sane_fee(&tx, &prevouts, ceiling),
+            Err(Error::FeeRateTooHigh { fee_rate, max_fee_rate })
+                if fee_rate == Amount::from_sat(51) && max_fee_rate == ceiling
+        ));
+
+        // paying out more than comes in
+        tx.output[0].value = 100_001;
+        assert!(matches!(
+            assert_sane_fee(&tx, &prevouts, ceiling),
+            Err(Error::Overflow)
+        ));
+    }
+}
@@ -0,0 +1,201 @@
+use crate::input::InputKind;
+use crate::{Error, Result};
+use bitcoin::psbt::{self, Psbt};
+use bitcoin::script::{Builder, PushBytes};
+use bitcoin::{ScriptBuf, Transaction, Witness};
+
+/// Finalizes every input of a signed `psbt` without a node and extracts the
+/// transaction. Handles key-path P2TR and single-key P2WPKH, P2SH-P2WPKH
+/// and P2PKH inputs, which is all the purchase and seller PSBTs spend;
+/// inputs finalized already are kept as they are. Fails with
+/// [`Error::CannotFinalize`] before touching `psbt` if any input is of
+/// another kind or lacks its signature, so a node can still finalize it.
+pub fn finalize(psbt: &mut Psbt) -> Result<Transaction> {
+    let finals = (0..psbt.inputs.len())
+        .map(|index| final_scripts(psbt, index))
+        .collect::<Result<Vec<_>>>()?;
+    for (input, finals) in psbt.inputs.iter_mut().zip(finals) {
+        if let Some((script_sig, witness)) = finals {
+            set_final_scripts(input, script_sig, witness);
+        }
+    }
+    Ok(psbt.clone().extract_tx())
+}
+
+/// The final script_sig and witness of input `index`, or `None` if it is
+/// finalized already.
+fn final_scripts(psbt: &Psbt, index: usize) -> Result<Option<(ScriptBuf, Witness)>> {
+    let input = &psbt.inputs[index];
+    if input.final_script_sig.is_some() || input.final_script_witness.is_some() {
+        return Ok(None);
+    }
+    let outpoint = psbt.unsigned_tx.input[index].previous_output;
+    let unsupported = |reason| Error::CannotFinalize(outpoint, reason);
+    let prevout = psbt
+        .spend_utxo(index)
+        .map_err(|_| unsupported("its previous output is missing"))?;
+
+    if prevout.script_pubkey.is_v1_p2tr() {
+        let sig = input
+            .tap_key_sig
+            .ok_or(unsupported("it has no taproot key signature"))?;
+        return Ok(Some((
+            ScriptBuf::new(),
+            Witness::from_slice(&[sig.to_vec()]),
+        )));
+    }
+
+    let mut sigs = input.partial_sigs.iter();
+    let (pubkey, sig) = match (sigs.next(), sigs.next()) {
+        (Some(sig), None) => sig,
+        (None, _) => return Err(unsupported("it is not signed")),
+        (Some(_), Some(_)) => return Err(unsupported("it has several signatures")),
+    };
+    let key_witness = || Witness::from_slice(&[sig.to_vec(), pubkey.to_bytes()]);
+    match InputKind::from_script(&prevout.script_pubkey) {
+        InputKind::P2wpkh => Ok(Some((ScriptBuf::new(), key_witness()))),
+        InputKind::P2shP2wpkh => {
+            let redeem_script = input
+                .redeem_script
+                .as_ref()
+                .filter(|script| script.is_v0_p2wpkh())
+                .ok_or(unsupported("its redeem script is not P2WPKH"))?;
+            let redeem_script = <&PushBytes>::try_from(redeem_script.as_bytes())
+                .map_err(|_| unsupported("its redeem script is too long"))?;
+            let script_sig = Builder::new().push_slice(redeem_script).into_script();
+            Ok(Some((script_sig, key_witness())))
+        }
+        InputKind::P2pkh if prevout.script_pubkey.is_p2pkh() => {
+            let script_sig = Builder::new()
+                .push_slice(sig.serialize())
+                .push_key(pubkey)
+                .into_script();
+            Ok(Some((script_sig, Witness::default())))
+        }
+        InputKind::P2pkh | InputKind::P2tr => Err(unsupported("its script type isn't known")),
+    }
+}
+
+/// Sets the final scripts of `input` and clears the fields only needed for
+/// signing, as BIP 174 asks of a finalizer.
+fn set_final_scripts(input: &mut psbt::Input, script_sig: ScriptBuf, witness: Witness) {
+    if !script_sig.is_empty() {
+        input.final_script_sig = Some(script_sig);
+    }
+    if !witness.is_empty() {
+        input.final_script_witness = Some(witness);
+    }
+    input.partial_sigs.clear();
+    input.sighash_type = None;
+    input.redeem_script = None;
+    input.witness_script = None;
+    input.bip32_derivation.clear();
+    input.tap_key_sig = None;
+    input.tap_script_sigs.clear();
+    input.tap_scripts.clear();
+    input.tap_key_origins.clear();
+    input.tap_internal_key = None;
+    input.tap_merkle_root = None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::mock::{funding_tx, key, p2tr, p2wpkh, Market, NETWORK};
+    use crate::seller::sign_seller_psbt_with_key;
+    use bitcoin::absolute::LockTime;
+    use bitcoin::secp256k1::Secp256k1;
+    use bitcoin::sighash::EcdsaSighashType;
+    use bitcoin::{Address, OutPoint, TxIn, TxOut};
+
+    #[test]
+    fn p2wpkh_seller_witness_reaches_the_final_transaction() {
+        let market = Market::new();
+        let mut buyer_psbt = market.buy(market.list()).unwrap();
+        let (pubkey, sig) = buyer_psbt.inputs[1]
+            .partial_sigs
+            .iter()
+            .next()
+            .map(|(pubkey, sig)| (*pubkey, *sig))
+            .unwrap();
+        assert_eq!(pubkey, key(1).public_key(&Secp256k1::new()));
+
+        let tx = finalize(&mut buyer_psbt).unwrap();
+        assert!(tx.input[1].script_sig.is_empty());
+        assert_eq!(
+            tx.input[1].witness.to_vec(),
+            [sig.to_vec(), pubkey.to_bytes()]
+        );
+    }
+
+    /// A PSBT spending a P2WPKH, P2PKH, P2SH-P2WPKH and P2TR output of
+    /// `key(1)`, none of them signed yet.
+    fn unsigned_psbt() -> Psbt {
+        let pubkey = key(1).public_key(&Secp256k1::new());
+        let p2pkh = Address::p2pkh(&pubkey, NETWORK);
+        let p2sh_p2wpkh = Address::p2shwpkh(&pubkey, NETWORK).unwrap();
+        let funding = funding_tx(
+            10,
+            &[
+                (10_000, &p2wpkh(1)),
+                (10_000, &p2pkh),
+                (10_000, &p2sh_p2wpkh),
+                (10_000, &p2tr(1)),
+            ],
+        );
+        let tx = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: (0..4)
+                .map(|vout| TxIn {
+                    previous_output: OutPoint::new(funding.txid(), vout),
+                    ..Default::default()
+                })
+                .collect(),
+            output: vec![TxOut {
+                value: 39_000,
+                script_pubkey: p2wpkh(2).script_pubkey(),
+            }],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        for (input, prevout) in psbt.inputs.iter_mut().zip(&funding.output) {
+            input.witness_utxo = Some(prevout.clone());
+        }
+        psbt
+    }
+
+    #[test]
+    fn finalizes_every_supported_input_kind() {
+        let mut psbt = unsigned_psbt();
+        sign_seller_psbt_with_key(&mut psbt, &key(1), EcdsaSighashType::All).unwrap();
+        let tx = finalize(&mut psbt).unwrap();
+
+        assert_eq!(tx.input[0].witness.len(), 2);
+        assert!(tx.input[0].script_sig.is_empty());
+        assert!(tx.input[1].witness.is_empty());
+        assert_eq!(tx.input[1].script_sig.instructions().count(), 2);
+        assert_eq!(tx.input[2].witness.len(), 2);
+        assert_eq!(tx.input[2].script_sig.instructions().count(), 1);
+        assert_eq!(tx.input[3].witness.len(), 1);
+        assert!(tx.input[3].script_sig.is_empty());
+        for input in &psbt.inputs {
+            assert!(input.partial_sigs.is_empty());
+            assert!(input.tap_key_sig.is_none());
+            assert!(input.redeem_script.is_none());
+        }
+    }
+
+    #[test]
+    fn unsigned_input_leaves_the_psbt_untouched() {
+        let mut psbt = unsigned_psbt();
+        sign_seller_psbt_with_key(&mut psbt, &key(1), EcdsaSighashType::All).unwrap();
+        psbt.inputs[3].tap_key_sig = None;
+        let before = psbt.clone();
+
+        assert!(matches!(
+            finalize(&mut psbt),
+            Err(Error::CannotFinalize(outpoint, _)) if outpoint == psbt.unsigned_tx.input[3].previous_output
+        ));
+        assert_eq!(psbt, before);
+    }
+}
@@ -0,0 +1,171 @@
+use crate::rpc::BitcoinRpc;
+use crate::{Error, Result};
+use bitcoin::bip32::{DerivationPath, Fingerprint, KeySource};
+use bitcoin::key::XOnlyPublicKey;
+use bitcoin::psbt::{self, PsbtSighashType};
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::sighash::{EcdsaSighashType, TapSighashType};
+use bitcoin::{Address, Network, Script, Transaction};
+use std::str::FromStr;
+
+/// How a transaction input is spent, which decides its weight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputKind {
+    P2pkh,
+    P2shP2wpkh,
+    P2wpkh,
+    P2tr,
+}
+
+impl InputKind {
+    /// Guesses the spend type from the previous output's script, assuming the
+    /// most expensive legacy spend when the script isn't recognised.
+    pub fn from_script(script: &Script) -> Self {
+        if script.is_v0_p2wpkh() {
+            InputKind::P2wpkh
+        } else if script.is_v1_p2tr() {
+            InputKind::P2tr
+        } else if script.is_p2sh() {
+            InputKind::P2shP2wpkh
+        } else {
+            InputKind::P2pkh
+        }
+    }
+
+    /// Weight of the signed input: outpoint, sequence and script_sig at 4 WU
+    /// per byte plus the witness at 1 WU per byte.
+    pub fn weight(self) -> usize {
+        // outpoint + sequence + script_sig length prefix
+        const BASE: usize = 36 + 4 + 1;
+        match self {
+            // script_sig: <72 byte sig> <33 byte pubkey>
+            InputKind::P2pkh => (BASE + 107) * 4,
+            // script_sig: <0 <20 byte hash>>, witness: <72 byte sig> <33 byte pubkey>
+            InputKind::P2shP2wpkh => (BASE + 23) * 4 + 108,
+            InputKind::P2wpkh => BASE * 4 + 108,
+            // witness: <65 byte schnorr sig with explicit sighash>
+            InputKind::P2tr => BASE * 4 + 67,
+        }
+    }
+
+    pub fn is_segwit(self) -> bool {
+        self != InputKind::P2pkh
+    }
+
+    /// The PSBT sighash type for signing this kind of input with `sighash`:
+    /// its taproot counterpart for P2TR, the ECDSA one otherwise.
+    pub fn psbt_sighash(self, sighash: EcdsaSighashType) -> Result<PsbtSighashType> {
+        match self {
+            InputKind::P2tr => TapSighashType::from_consensus_u8(sighash.to_u32() as u8)
+                .map(PsbtSighashType::from)
+                .map_err(|_| Error::UnsupportedSighash(sighash, "it has no taproot counterpart")),
+            _ => Ok(PsbtSighashType::from(sighash)),
+        }
+    }
+}
+
+/// Attaches the output `vout` of `prev_tx` to `psbt_input`: just the output
+/// as `witness_utxo` for segwit inputs, the whole transaction as
+/// `non_witness_utxo` only for legacy ones that need it.
+pub fn set_input_utxo(
+    psbt_input: &mut psbt::Input,
+    prev_tx: &Transaction,
+    vout: u32,
+    kind: InputKind,
+) {
+    if kind.is_segwit() {
+        psbt_input.witness_utxo = Some(prev_tx.output[vout as usize].clone());
+    } else {
+        psbt_input.non_witness_utxo = Some(prev_tx.clone());
+    }
+}
+
+/// Looks up the internal key of a P2TR `script` owned by `client`'s wallet,
+/// with its key origin if the wallet reports one, from its `tr(...)`
+/// descriptor. Returns `None` if the wallet doesn't expose it, leaving it to
+/// the wallet to fill in while processing the PSBT.
+pub fn taproot_key_origin(
+    client: &dyn BitcoinRpc,
+    script: &Script,
+    network: Network,
+) -> Result<Option<(XOnlyPublicKey, Option<KeySource>)>> {
+    let address = match Address::from_script(script, network) {
+        Ok(address) => address,
+        Err(_) => return Ok(None),
+    };
+    let info = client.get_address_info(&address)?;
+    // e.g. tr([73c5da0a/86'/1'/0'/0/0]<x-only key>)#checksum
+    let Some(desc) = info["desc"]
+        .as_str()
+        .and_then(|desc| desc.strip_prefix("tr("))
+    else {
+        return Ok(None);
+    };
+    let (origin, desc) = match desc.strip_prefix('[') {
+        Some(origin) => match origin.split_once(']') {
+            Some((origin, rest)) => (Some(origin), rest),
+            None => return Ok(None),
+        },
+        None => (None, desc),
+    };
+    let key = desc
+        .split([')', ','])
+        .next()
+        .and_then(|key| XOnlyPublicKey::from_str(key).ok());
+    let origin = origin.and_then(|origin| {
+        let (fingerprint, path) = origin.split_once('/').unwrap_or((origin, ""));
+        let path = if path.is_empty() {
+            "m".to_string()
+        } else {
+            format!("m/{}", path)
+        };
+        Some((
+            Fingerprint::from_str(fingerprint).ok()?,
+            DerivationPath::from_str(&path).ok()?,
+        ))
+    });
+    Ok(key.map(|key| (key, origin)))
+}
+
+/// Fills in the key and its origin of an ECDSA `psbt_input` paying to
+/// `script` as `client`'s wallet reports them, so a signer given the PSBT
+/// finds its key without deriving it again. Leaves the input alone when the
+/// wallet doesn't know the address or its key origin.
+pub fn set_key_origin(
+    client: &dyn BitcoinRpc,
+    psbt_input: &mut psbt::Input,
+    script: &Script,
+    network: Network,
+) -> Result<()> {
+    let Ok(address) = Address::from_script(script, network) else {
+        return Ok(());
+    };
+    let info = client.get_address_info(&address)?;
+    let origin = || {
+        let key = PublicKey::from_str(info["pubkey"].as_str()?).ok()?;
+        let fingerprint = Fingerprint::from_str(info["hdmasterfingerprint"].as_str()?).ok()?;
+        let path = DerivationPath::from_str(info["hdkeypath"].as_str()?).ok()?;
+        Some((key, (fingerprint, path)))
+    };
+    if let Some((key, origin)) = origin() {
+        psbt_input.bip32_derivation.insert(key, origin);
+    }
+    Ok(())
+}
+
+/// Fills in the internal key and its origin of a key-path spent P2TR
+/// `psbt_input` paying to `script`, so the signing wallet can find the key.
+pub fn set_taproot_key(
+    client: &dyn BitcoinRpc,
+    psbt_input: &mut psbt::Input,
+    script: &Script,
+    network: Network,
+) -> Result<()> {
+    if let Some((key, origin)) = taproot_key_origin(client, script, network)? {
+        psbt_input.tap_internal_key = Some(key);
+        if let Some(origin) = origin {
+            psbt_input.tap_key_origins.insert(key, (Vec::new(), origin));
+        }
+    }
+    Ok(())
+}
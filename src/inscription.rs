@@ -0,0 +1,128 @@
+use crate::explorer::parse_explorer_url;
+use crate::utxo::{fetch_inscription_outputs, is_utxo_inscription};
+use crate::{env_or, Result};
+use bitcoin::OutPoint;
+use reqwest::blocking::Client;
+use reqwest::Url;
+use std::collections::HashSet;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// How long a request to ord may take when `ORD_HTTP_TIMEOUT_SECS` is unset.
+pub const DEFAULT_ORD_HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Tells which outputs carry inscriptions. Any `Fn(OutPoint) -> Result<bool>`
+/// is one, so tests can plug in a fixed predicate instead of ord.
+pub trait InscriptionIndex: Send + Sync {
+    fn is_inscription(&self, outpoint: OutPoint) -> Result<bool>;
+
+    /// Returns which of `outpoints` carry inscriptions. Looks them up one by
+    /// one unless the index can do better.
+    fn inscription_outputs(&self, outpoints: &[OutPoint]) -> Result<HashSet<OutPoint>> {
+        let mut inscribed = HashSet::new();
+        for &outpoint in outpoints {
+            if self.is_inscription(outpoint)? {
+                inscribed.insert(outpoint);
+            }
+        }
+        Ok(inscribed)
+    }
+}
+
+impl<F: Fn(OutPoint) -> Result<bool> + Send + Sync> InscriptionIndex for F {
+    fn is_inscription(&self, outpoint: OutPoint) -> Result<bool> {
+        self(outpoint)
+    }
+}
+
+/// An ord explorer queried over HTTP, usually the configured
+/// `ORD_EXPLORER`. One client is shared by all lookups.
+#[derive(Debug, Clone)]
+pub struct OrdExplorer {
+    base: Url,
+    /// Connect and read timeout of every request.
+    timeout: Duration,
+    client: OnceLock<Client>,
+}
+
+impl OrdExplorer {
+    /// The explorer rooted at `base`.
+    pub fn new(base: &str) -> Result<Self> {
+        Ok(OrdExplorer::from_url(parse_explorer_url(base)?))
+    }
+
+    /// The explorer rooted at `base`, already parsed by
+    /// [`parse_explorer_url`].
+    pub fn from_url(base: Url) -> Self {
+        OrdExplorer {
+            base,
+            timeout: DEFAULT_ORD_HTTP_TIMEOUT,
+            client: OnceLock::new(),
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self.client = OnceLock::new();
+        self
+    }
+
+    /// The explorer's root.
+    pub fn base(&self) -> &Url {
+        &self.base
+    }
+
+    /// The shared client, built on first use.
+    fn client(&self) -> Result<&Client> {
+        if let Some(client) = self.client.get() {
+            return Ok(client);
+        }
+        let client = Client::builder()
+            .connect_timeout(self.timeout)
+            .timeout(self.timeout)
+            .build()?;
+        Ok(self.client.get_or_init(|| client))
+    }
+}
+
+impl InscriptionIndex for OrdExplorer {
+    fn is_inscription(&self, outpoint: OutPoint) -> Result<bool> {
+        is_utxo_inscription(self.client()?, &self.base, outpoint)
+    }
+
+    fn inscription_outputs(&self, outpoints: &[OutPoint]) -> Result<HashSet<OutPoint>> {
+        fetch_inscription_outputs(self.client()?, &self.base, outpoints)
+    }
+}
+
+/// Reads `ORD_HTTP_TIMEOUT_SECS`, the timeout of requests to ord.
+pub fn ord_http_timeout() -> Result<Duration> {
+    Ok(Duration::from_secs(env_or(
+        "ORD_HTTP_TIMEOUT_SECS",
+        DEFAULT_ORD_HTTP_TIMEOUT.as_secs(),
+    )?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::explorer::mock::{serve, Response};
+    use crate::Error;
+    use bitcoin::hashes::Hash;
+    use bitcoin::Txid;
+
+    #[test]
+    fn slow_explorer_times_out_with_a_transient_error() {
+        let timeout = Duration::from_millis(100);
+        let (url, server) = serve(vec![Response {
+            delay: timeout * 5,
+            ..Response::ok(r#"{"inscriptions":[]}"#)
+        }]);
+        let explorer = OrdExplorer::from_url(url).with_timeout(timeout);
+
+        let result = explorer.is_inscription(OutPoint::new(Txid::all_zeros(), 0));
+        assert!(matches!(&result, Err(Error::HttpTimeout(_))));
+        assert!(result.unwrap_err().is_transient());
+        server.join().unwrap();
+    }
+}
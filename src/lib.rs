@@ -0,0 +1,129 @@
+pub mod bump;
+pub mod buyer;
+pub mod config;
+pub mod context;
+pub mod error;
+pub mod explorer;
+pub mod fee;
+pub mod finalize;
+pub mod input;
+pub mod inscription;
+pub mod listing;
+#[cfg(feature = "async")]
+pub mod nonblocking;
+pub mod psbt_file;
+pub mod psbt_v2;
+#[cfg(all(test, feature = "integration"))]
+mod regtest;
+pub mod retry;
+pub mod rpc;
+pub mod selection;
+pub mod seller;
+pub mod signer;
+pub mod summary;
+#[cfg(test)]
+mod test_env;
+pub mod utxo;
+pub mod verify;
+
+pub use context::MarketplaceContext;
+pub use error::{Error, Result};
+
+use bitcoin::{Address, Amount, Network};
+use std::env::{self, VarError};
+use std::str::FromStr;
+
+/// Listing price used when `LISTING_PRICE_SAT` is unset.
+pub const DEFAULT_PRICE: Amount = Amount::from_sat(1900);
+
+pub const SERVICE_FEE: Amount = Amount::from_sat(1000);
+
+/// Reads `BITCOIN_NETWORK` (`mainnet`, `testnet`, `regtest` or `signet`),
+/// defaulting to testnet when it is unset.
+pub fn network_from_env() -> Result<Network> {
+    let network = match env::var("BITCOIN_NETWORK") {
+        Ok(network) => network,
+        Err(VarError::NotPresent) => return Ok(Network::Testnet),
+        Err(e) => return Err(Error::Env("BITCOIN_NETWORK", e)),
+    };
+    match network.as_str() {
+        "mainnet" | "bitcoin" => Ok(Network::Bitcoin),
+        "testnet" => Ok(Network::Testnet),
+        "regtest" => Ok(Network::Regtest),
+        "signet" => Ok(Network::Signet),
+        _ => Err(Error::UnknownNetwork(network)),
+    }
+}
+
+pub fn env_var(name: &'static str) -> Result<String> {
+    env::var(name).map_err(|e| Error::Env(name, e))
+}
+
+/// Parses the variable `name`, or returns `None` when it is unset or empty.
+pub fn env_opt<T: FromStr>(name: &'static str) -> Result<Option<T>> {
+    match env::var(name) {
+        Ok(value) if value.is_empty() => Ok(None),
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| Error::InvalidEnv(name, value)),
+        Err(VarError::NotPresent) => Ok(None),
+        Err(e) => Err(Error::Env(name, e)),
+    }
+}
+
+/// Parses the variable `name`, falling back to `default` when it is unset.
+pub fn env_or<T: FromStr>(name: &'static str, default: T) -> Result<T> {
+    Ok(env_opt(name)?.unwrap_or(default))
+}
+
+/// Parses the variable `name` as a number of sats, falling back to `default`
+/// when it is unset.
+pub fn env_amount(name: &'static str, default: Amount) -> Result<Amount> {
+    Ok(Amount::from_sat(env_or(name, default.to_sat())?))
+}
+
+pub fn env_address(name: &'static str, network: Network) -> Result<Address> {
+    parse_checked_address(&env_var(name)?, network)
+}
+
+/// Parses `s` as an address of `network`, failing with
+/// [`Error::AddressNetworkMismatch`] naming both networks when it is well
+/// formed but meant for another one.
+pub fn parse_checked_address(s: &str, network: Network) -> Result<Address> {
+    let address = Address::from_str(s)?;
+    if address.is_valid_for_network(network) {
+        return Ok(address.assume_checked());
+    }
+    // testnet and signet share their prefixes, regtest only its bech32 one
+    let got = [Network::Bitcoin, Network::Testnet, Network::Regtest]
+        .into_iter()
+        .find(|&got| address.is_valid_for_network(got))
+        .unwrap_or(Network::Bitcoin);
+    Err(Error::AddressNetworkMismatch {
+        expected: network,
+        got,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAINNET_ADDRESS: &str = "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4";
+
+    #[test]
+    fn mainnet_address_names_both_networks() {
+        for network in [Network::Testnet, Network::Regtest] {
+            let e = parse_checked_address(MAINNET_ADDRESS, network).unwrap_err();
+            assert!(matches!(
+                e,
+                Error::AddressNetworkMismatch { expected, got: Network::Bitcoin } if expected == network
+            ));
+            let message = e.to_string();
+            assert!(message.contains(&Network::Bitcoin.to_string()));
+            assert!(message.contains(&network.to_string()));
+        }
+        assert!(parse_checked_address(MAINNET_ADDRESS, Network::Bitcoin).is_ok());
+    }
+}
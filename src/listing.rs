@@ -0,0 +1,98 @@
+//! Shareable listing URIs.
+//!
+//! A listing is published as a BIP21 URI paying the seller's address the
+//! listing price, extended with a `psbt` parameter carrying the base64
+//! seller PSBT:
+//!
+//! ```text
+//! bitcoin:<address>?amount=<price in BTC>&psbt=<percent-encoded base64 PSBT>
+//! ```
+//!
+//! BIP21 has no parameter for PSBTs. `psbt` is not prefixed with `req-`, so
+//! wallets that don't know it still read the address and amount, but paying
+//! those directly doesn't buy anything: the buyer has to combine the PSBT.
+
+use crate::{parse_checked_address, Error, Result};
+use bitcoin::psbt::Psbt;
+use bitcoin::{Address, Amount, Denomination, Network};
+use reqwest::Url;
+use std::str::FromStr;
+
+/// The URI parameter carrying the seller PSBT.
+pub const PSBT_PARAM: &str = "psbt";
+
+/// Renders a listing of `psbt` paying `price` to the seller's `address`.
+pub fn listing_uri(address: &Address, price: Amount, psbt: &Psbt) -> String {
+    let mut uri = Url::parse(&format!("bitcoin:{}", address)).expect("an address is a valid path");
+    uri.query_pairs_mut()
+        .append_pair("amount", &price.to_string_in(Denomination::Bitcoin))
+        .append_pair(PSBT_PARAM, &psbt.to_string());
+    uri.to_string()
+}
+
+/// Reads back a URI made by [`listing_uri`], checking the address belongs to
+/// `network`.
+pub fn parse_listing_uri(uri: &str, network: Network) -> Result<(Address, Amount, Psbt)> {
+    let invalid = |reason| Error::InvalidListingUri(uri.to_string(), reason);
+    let url = Url::parse(uri).map_err(|_| invalid("not a uri"))?;
+    if url.scheme() != "bitcoin" {
+        return Err(invalid("expected a bitcoin: uri"));
+    }
+    let address = parse_checked_address(url.path(), network).map_err(|e| match e {
+        Error::Address(_) => invalid("invalid address"),
+        e => e,
+    })?;
+
+    let (mut price, mut psbt) = (None, None);
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            "amount" => {
+                price = Some(
+                    Amount::from_str_in(&value, Denomination::Bitcoin)
+                        .map_err(|_| invalid("invalid amount"))?,
+                )
+            }
+            PSBT_PARAM => psbt = Some(Psbt::from_str(&value)?),
+            // BIP21: unknown parameters are ignored unless they are required
+            key if key.starts_with("req-") => {
+                return Err(invalid("unsupported required parameter"))
+            }
+            _ => {}
+        }
+    }
+    Ok((
+        address,
+        price.ok_or_else(|| invalid("missing amount"))?,
+        psbt.ok_or_else(|| invalid("missing psbt"))?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::mock::{p2wpkh, Market, NETWORK, PRICE};
+
+    #[test]
+    fn listing_uri_round_trips() {
+        let market = Market::new();
+        let (psbt, _) = market.list();
+        let address = &market.ctx.config.seller_address;
+
+        let uri = listing_uri(address, PRICE, &psbt);
+        assert!(uri.starts_with(&format!("bitcoin:{}?amount=0.0005&psbt=", address)));
+        assert_eq!(
+            parse_listing_uri(&uri, NETWORK).unwrap(),
+            (address.clone(), PRICE, psbt)
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_required_parameters() {
+        let (psbt, _) = Market::new().list();
+        let uri = format!("{}&req-expiry=1", listing_uri(&p2wpkh(1), PRICE, &psbt));
+        assert!(matches!(
+            parse_listing_uri(&uri, NETWORK),
+            Err(Error::InvalidListingUri(_, _))
+        ));
+    }
+}
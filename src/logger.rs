@@ -0,0 +1,41 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::env;
+
+/// Writes log records to stderr, filtered by the level in `RUST_LOG`
+/// (`error`, `warn`, `info`, `debug`, `trace` or `off`; `info` if unset).
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match record.level() {
+            Level::Error | Level::Warn | Level::Info => {
+                eprintln!("{:<5} {}", record.level(), record.args())
+            }
+            Level::Debug | Level::Trace => eprintln!(
+                "{:<5} {}: {}",
+                record.level(),
+                record.target(),
+                record.args()
+            ),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the stderr logger. An unparsable `RUST_LOG` falls back to `info`.
+pub fn init() {
+    let level = env::var("RUST_LOG")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(LevelFilter::Info);
+    log::set_max_level(level);
+    log::set_logger(&StderrLogger).expect("logger is only installed once");
+}
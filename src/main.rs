@@ -1,367 +1,700 @@
+mod cli;
+mod logger;
+#[cfg(test)]
+mod test_env;
+
 use bitcoin::absolute::LockTime;
-use bitcoin::psbt::{Psbt, PsbtSighashType};
-use bitcoin::sighash::EcdsaSighashType;
-use bitcoin::Network::Testnet;
-use bitcoin::{
-    Address, Amount, Network, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness,
-};
-use bitcoincore_rpc::json::{ListUnspentResultEntry, SigHashType};
-use bitcoincore_rpc::{Auth, Client, RpcApi};
-use lazy_static::lazy_static;
+use bitcoin::psbt::Psbt;
+use bitcoin::{consensus, Address, Amount, Network, OutPoint, PrivateKey};
+use cli::{Command, Invocation};
+use serde::Serialize;
 use std::env;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process;
 use std::str::FromStr;
-
-const NETWORK: Network = Testnet;
-const PRICE: u64 = 1900;
-
-const SERVICE_FEE: u64 = 1000;
-
-lazy_static! {
-    static ref SELLET_ADDRESS: Address = Address::from_str(&env::var("SELLER_ADDRESS").unwrap())
-        .unwrap()
-        .require_network(NETWORK)
-        .unwrap();
-    static ref FULL_NODE: Client = rpc_client(
-        env::var("BITCOIN_RPC_URL").unwrap(),
-        env::var("BITCOIN_RPC_USER").unwrap(),
-        env::var("BITCOIN_RPC_PASS").unwrap(),
-    );
-    static ref SELLLER_NODE: Client = rpc_client(
-        env::var("SELLER_RPC_URL").unwrap(),
-        env::var("SELLER_RPC_USER").unwrap(),
-        env::var("SELLER_RPC_PASS").unwrap(),
-    );
-    static ref BUYER_NODE: Client = rpc_client(
-        env::var("BUYER_RPC_URL").unwrap(),
-        env::var("BUYER_RPC_USER").unwrap(),
-        env::var("BUYER_RPC_PASS").unwrap(),
-    );
+use test_psbt::bump::bump_fee;
+use test_psbt::buyer::{create_buyer_psbt, ChangePolicy, SortPolicy, MAX_OP_RETURN_DATA};
+use test_psbt::config::Config;
+use test_psbt::fee::{
+    assert_sane_fee, estimate_vsize, simulate_fees, FeeConfig, DEFAULT_SIMULATED_TARGETS,
+};
+use test_psbt::finalize::finalize;
+use test_psbt::input::InputKind;
+use test_psbt::listing::listing_uri;
+use test_psbt::psbt_file::{
+    load_psbt, psbt_to_base64, save_psbt_version, serialize_psbt, PsbtFormat,
+};
+use test_psbt::rpc::{already_broadcast, ping_nodes, test_accept};
+use test_psbt::seller::{
+    cancel_listing, create_seller_psbt, seller_proceeds, Royalty, DEFAULT_SELLER_SIGHASH,
+};
+use test_psbt::signer::{process_psbt, Hwi, PsbtSigner};
+use test_psbt::summary::{describe_psbt, summarize_transaction};
+use test_psbt::utxo::{fetch_prevout, prepare_dummies};
+use test_psbt::{network_from_env, parse_checked_address, Error, MarketplaceContext, Result};
+
+/// Loads the variables in `path` into the environment, returning whether
+/// there was such a file. A missing one is fine: the real environment may
+/// hold everything, as in a container.
+fn load_dotenv(path: &Path) -> std::result::Result<bool, dotenv::Error> {
+    match dotenv::from_path(path) {
+        Ok(()) => Ok(true),
+        Err(dotenv::Error::Io(e)) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
 }
 
 fn main() {
-    dotenv::from_path(".env").unwrap();
-    let (seller_psbt, inscription_tx_out) = create_seller_psbt();
-    println!("seller_psbt: {}", seller_psbt);
-    if seller_psbt.is_empty() {
-        println!("seller_psbt should not empty");
-        return;
+    let dotenv = load_dotenv(Path::new(".env"));
+    logger::init();
+    match dotenv {
+        Ok(true) => log::debug!("loaded .env"),
+        Ok(false) => log::debug!("no .env, using the process environment"),
+        Err(e) => {
+            log::error!("cannot load .env: {}", e);
+            process::exit(1);
+        }
     }
-
-    let buyer_psbt = create_buyer_psbt(seller_psbt, inscription_tx_out);
-    println!("buyer_psbt: {}", buyer_psbt);
-    if buyer_psbt.is_empty() {
-        println!("buyer_psbt should not empty");
-        return;
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    // looked for before parsing so a bad command line is reported as json too
+    let json = args
+        .iter()
+        .zip(args.iter().skip(1))
+        .any(|(flag, value)| flag == "--output" && value == "json")
+        || args.iter().any(|arg| arg == "--output=json");
+    let invocation = match cli::parse(args) {
+        Ok(invocation) => invocation,
+        Err(e) if json => {
+            print_json(&ErrorReport { error: e });
+            process::exit(2);
+        }
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, cli::USAGE);
+            process::exit(2);
+        }
+    };
+    match run(&invocation) {
+        Ok(report) => {
+            if invocation.json() {
+                print_json(&report);
+            }
+        }
+        Err(e) => {
+            let mut message = e.to_string();
+            let mut source = std::error::Error::source(&e);
+            while let Some(cause) = source {
+                message += &format!("\n  caused by: {}", cause);
+                source = cause.source();
+            }
+            log::error!("{}", message);
+            if invocation.json() {
+                print_json(&ErrorReport { error: message });
+            }
+            process::exit(1);
+        }
     }
+}
 
-    let raw_buying_tx = BUYER_NODE
-        .finalize_psbt(&buyer_psbt, None)
-        .unwrap()
-        .hex
-        .unwrap();
-
-    let buying_txid = BUYER_NODE.send_raw_transaction(&raw_buying_tx).unwrap();
-    println!(
-        "inscription buying tx was succesfully send: {:?}",
-        &buying_txid
-    );
+/// What a command produced, printed as one object by `--output json`.
+/// Fields that don't apply to the command are left out.
+#[derive(Debug, Default, Serialize)]
+struct Report {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seller_psbt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    buyer_psbt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uri: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    txid: Option<String>,
+    /// The final transaction, when a dry run didn't broadcast it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tx: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    broadcast: Option<bool>,
+    /// In sats.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fee: Option<u64>,
+    /// In sats per vbyte, estimated for PSBTs that aren't signed yet.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fee_rate: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dummies: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fees: Option<Vec<FeeEstimate>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    proceeds: Option<Proceeds>,
 }
 
-fn rpc_client(rpc_url: String, user: String, pass: String) -> Client {
-    Client::new(&rpc_url, Auth::UserPass(user, pass)).unwrap()
+/// One row of the `fees` table, in sats and sats per vbyte.
+#[derive(Debug, Serialize)]
+struct FeeEstimate {
+    conf_target: u16,
+    fee_rate: u64,
+    fee: u64,
 }
 
-fn create_seller_psbt() -> (String, TxOut) {
-    let inscription_utxo = OutPoint::from_str(&env::var("SELLER_UTXO").unwrap()).unwrap();
-    let tx = FULL_NODE
-        .get_raw_transaction(&inscription_utxo.txid, None)
-        .unwrap();
-
-    let inscription_output = tx.output[inscription_utxo.vout as usize].clone();
-
-    let tx_sell = Transaction {
-        version: 2,
-        lock_time: LockTime::ZERO,
-        input: vec![TxIn {
-            previous_output: OutPoint {
-                txid: inscription_utxo.txid,
-                vout: inscription_utxo.vout,
-            },
-            script_sig: ScriptBuf::new(),
-            sequence: Sequence::MAX,
-            witness: Witness::default(),
-        }],
-        output: vec![
-            TxOut {
-                value: PRICE,
-                script_pubkey: inscription_output.script_pubkey,
-            },
-        ],
-    };
+/// What a listing leaves the seller, in sats. `marketplace_fee` is paid by
+/// `fee_payer`, `buyer` or `seller`.
+#[derive(Debug, Serialize)]
+struct Proceeds {
+    price: u64,
+    marketplace_fee: u64,
+    fee_payer: String,
+    royalty: u64,
+    net: u64,
+}
 
-    let mut psbt = Psbt::from_unsigned_tx(tx_sell).unwrap();
-
-    psbt.inputs[0].non_witness_utxo = Some(tx.clone());
-    psbt.inputs[0].sighash_type = Some(PsbtSighashType::from(
-        EcdsaSighashType::SinglePlusAnyoneCanPay,
-    ));
-
-    let processed_seller_psbt = SELLLER_NODE
-        .wallet_process_psbt(
-            &psbt.to_string(),
-            Some(true),
-            Some(SigHashType::from(EcdsaSighashType::SinglePlusAnyoneCanPay)),
-            None,
-        )
-        .unwrap();
-
-    (
-        processed_seller_psbt.psbt,
-        tx.output[inscription_utxo.vout as usize].clone(),
-    )
+#[derive(Debug, Serialize)]
+struct ErrorReport {
+    error: String,
 }
 
-fn create_buyer_psbt(seller_psbt: String, inscription_tx_out: TxOut) -> String {
-    let buyer = Address::from_str(&env::var("BUYER_ADDRESS").unwrap())
-        .unwrap()
-        .require_network(NETWORK)
-        .unwrap();
+fn print_json(value: &impl Serialize) {
+    println!(
+        "{}",
+        serde_json::to_string(value).expect("reports serialize to json")
+    );
+}
 
-    if BUYER_NODE.get_balance(None, None).unwrap() < Amount::from_sat(PRICE) {
-        println!("buyer doesn't have enough funds");
-        return Default::default();
+fn run(invocation: &Invocation) -> Result<Report> {
+    let json = invocation.json();
+    let mut report = Report::default();
+
+    // only reads a file, so don't require the nodes to be reachable
+    if invocation.command == Command::Describe {
+        let psbt = read_psbt(invocation, "psbt")?;
+        let description = describe_psbt(&psbt, network_from_env()?);
+        if json {
+            report.description = Some(description);
+        } else {
+            println!("{}", description);
+        }
+        return Ok(report);
     }
 
-    let sorted_spendable_utxos = get_buyer_spendable_utxos(&buyer);
+    let mut ctx = MarketplaceContext::from_env()?;
+    ping_nodes(&ctx)?;
+    ctx.dry_run |= invocation.flag("dry-run");
+    ctx.no_sign = invocation.flag("no-sign");
+    ctx.signer = signer(invocation, ctx.config.network)?;
+    ctx.fee = fee_config(invocation, ctx.fee)?;
+    if let Some(coin_selection) = arg(invocation, "coin-selection")? {
+        ctx.coin_selection = coin_selection;
+    }
 
-    if sorted_spendable_utxos.len() == 0 {
-        println!("buyer doesn't have any spendable utxos");
-        return Default::default();
+    match invocation.command {
+        Command::Sell => {
+            ctx.seller_key = seller_key(invocation, ctx.config.network)?;
+            let price = price(invocation, &ctx.config)?;
+            let royalty = royalty(invocation, ctx.config.network, price)?;
+            let (seller_psbt, inscription_tx_out) = create_seller_psbt(
+                &ctx,
+                inscription_utxo(invocation, &ctx.config)?,
+                price,
+                DEFAULT_SELLER_SIGHASH,
+                lock_time(invocation)?,
+                royalty.as_ref(),
+            )?;
+            let proceeds = seller_proceeds(&ctx.config, price, royalty.as_ref())?;
+            // stdout is left to the psbt or uri
+            if !json {
+                eprintln!("{}", proceeds);
+            }
+            report.proceeds = Some(Proceeds {
+                price: proceeds.price.to_sat(),
+                marketplace_fee: proceeds.marketplace_fee.to_sat(),
+                fee_payer: proceeds.fee_payer.to_string(),
+                royalty: proceeds.royalty.to_sat(),
+                net: proceeds.net.to_sat(),
+            });
+            if invocation.flag("uri") {
+                let seller =
+                    Address::from_script(&inscription_tx_out.script_pubkey, ctx.config.network)?;
+                let uri = listing_uri(&seller, price, &seller_psbt);
+                if json {
+                    report.uri = Some(uri);
+                } else {
+                    println!("{}", uri);
+                }
+            } else {
+                write_psbt(invocation, &seller_psbt)?;
+            }
+            report.seller_psbt = Some(psbt_base64(invocation, &seller_psbt)?);
+        }
+        Command::Cancel => {
+            ctx.seller_key = seller_key(invocation, ctx.config.network)?;
+            let inscription_utxo = inscription_utxo(invocation, &ctx.config)?;
+            let to = cancel_destination(invocation, &ctx.config)?;
+            let txid = cancel_listing(&ctx, inscription_utxo, &to)?;
+            if !json {
+                println!("{}", txid);
+            }
+            report.txid = Some(txid.to_string());
+        }
+        Command::Buy => {
+            if let Some(postage) = arg(invocation, "postage")? {
+                ctx.postage = Some(Amount::from_sat(postage));
+            }
+            if let Some(memo) = invocation.value("memo") {
+                // turned down before a dummy split is broadcast for nothing
+                if memo.len() > MAX_OP_RETURN_DATA {
+                    return Err(Error::InvalidArgument("memo", memo.to_string()));
+                }
+                ctx.memo = Some(memo.as_bytes().to_vec());
+            }
+            let seller_psbt = read_psbt(invocation, "seller-psbt")?;
+            let inscription_utxo = inscription_utxo(invocation, &ctx.config)?;
+            let inscription_tx_out = fetch_prevout(&ctx, inscription_utxo)?;
+            let buyer_psbt = create_buyer_psbt(
+                &ctx,
+                seller_psbt,
+                inscription_utxo,
+                inscription_tx_out,
+                price(invocation, &ctx.config)?,
+                address_arg(invocation, "receive-to", ctx.config.network)?,
+                !invocation.flag("no-rbf"),
+                change_policy(invocation, ctx.config.network)?,
+                if invocation.flag("bip69") {
+                    SortPolicy::Bip69
+                } else {
+                    SortPolicy::AsBuilt
+                },
+            )?;
+            write_psbt(invocation, &buyer_psbt)?;
+            if let Some((fee, fee_rate)) = psbt_fee(&buyer_psbt) {
+                report.fee = Some(fee.to_sat());
+                report.fee_rate = Some(fee_rate);
+            }
+            report.buyer_psbt = Some(psbt_base64(invocation, &buyer_psbt)?);
+        }
+        Command::Broadcast => {
+            let mut psbt = read_psbt(invocation, "psbt")?;
+            let tx = match finalize(&mut psbt) {
+                Ok(tx) => tx,
+                Err(Error::CannotFinalize(input, reason)) => {
+                    log::debug!("finalizing on the node input={} reason={}", input, reason);
+                    let raw_tx = ctx
+                        .buyer_node
+                        .finalize_psbt(&psbt.to_string(), None)?
+                        .hex
+                        .ok_or(Error::PsbtNotFinalized)?;
+                    consensus::deserialize(&raw_tx)?
+                }
+                Err(e) => return Err(e),
+            };
+
+            let prevouts = (0..psbt.inputs.len())
+                .map(|i| {
+                    psbt.spend_utxo(i)
+                        .cloned()
+                        .map_err(|_| Error::MissingOutput(tx.input[i].previous_output))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let summary = summarize_transaction(&tx, &prevouts, ctx.config.network);
+            eprintln!("{}", summary);
+            report.txid = Some(tx.txid().to_string());
+            report.fee = Some(summary.fee.to_sat());
+            report.fee_rate = Some(summary.fee_rate);
+            report.broadcast = Some(false);
+
+            assert_sane_fee(&tx, &prevouts, ctx.fee.max_fee_rate)?;
+            if already_broadcast(ctx.buyer_node.as_ref(), tx.txid())? {
+                log::warn!("purchase tx already broadcast txid={}", tx.txid());
+                report.broadcast = Some(true);
+                return Ok(report);
+            }
+            if !invocation.flag("skip-mempool-check") {
+                test_accept(ctx.buyer_node.as_ref(), &tx)?;
+                log::debug!("mempool would accept purchase tx txid={}", tx.txid());
+            }
+
+            if ctx.dry_run {
+                log::info!("dry run: not broadcasting purchase tx txid={}", tx.txid());
+                let hex = consensus::encode::serialize_hex(&tx);
+                if !json {
+                    println!("{}", hex);
+                }
+                report.tx = Some(hex);
+                return Ok(report);
+            }
+            if !invocation.flag("yes") && !confirm("broadcast this transaction?")? {
+                log::warn!("not broadcasting purchase tx txid={}", tx.txid());
+                return Ok(report);
+            }
+            let txid = ctx
+                .buyer_node
+                .send_raw_transaction(&consensus::serialize(&tx))?;
+            log::info!("broadcast purchase tx txid={}", txid);
+            report.broadcast = Some(true);
+        }
+        Command::Bump => {
+            let txid = required_arg(invocation, "txid")?;
+            let fee_rate = Amount::from_sat(required_arg(invocation, "fee-rate")?);
+            let psbt = bump_fee(ctx.buyer_node.as_ref(), txid, fee_rate)?;
+            let (psbt, _) = process_psbt(&ctx, ctx.buyer_node.as_ref(), &psbt, None)?;
+            log::info!(
+                "built replacement psbt txid={} fee_rate={}",
+                txid,
+                fee_rate.to_sat()
+            );
+            write_psbt(invocation, &psbt)?;
+            if let Some((fee, fee_rate)) = psbt_fee(&psbt) {
+                report.fee = Some(fee.to_sat());
+                report.fee_rate = Some(fee_rate);
+            }
+            report.buyer_psbt = Some(psbt_base64(invocation, &psbt)?);
+        }
+        Command::Fees => {
+            let psbt = read_psbt(invocation, "psbt")?;
+            let targets = match invocation.value("targets") {
+                Some(value) => value
+                    .split(',')
+                    .map(|target| target.trim().parse())
+                    .collect::<std::result::Result<Vec<u16>, _>>()
+                    .map_err(|_| Error::InvalidArgument("targets", value.to_string()))?,
+                None => DEFAULT_SIMULATED_TARGETS.to_vec(),
+            };
+            let estimates = simulate_fees(&ctx, &psbt.unsigned_tx, &targets)?;
+            if !json {
+                println!("{:>6}  {:>10}  {:>12}", "blocks", "sat/vB", "fee");
+                for (conf_target, fee_rate, fee) in &estimates {
+                    let refused = if *fee_rate > ctx.fee.max_fee_rate {
+                        "  above max fee rate"
+                    } else {
+                        ""
+                    };
+                    println!(
+                        "{:>6}  {:>10}  {:>12}{}",
+                        conf_target,
+                        fee_rate.to_sat(),
+                        fee.to_sat(),
+                        refused
+                    );
+                }
+            }
+            report.fees = Some(
+                estimates
+                    .into_iter()
+                    .map(|(conf_target, fee_rate, fee)| FeeEstimate {
+                        conf_target,
+                        fee_rate: fee_rate.to_sat(),
+                        fee: fee.to_sat(),
+                    })
+                    .collect(),
+            );
+        }
+        Command::PrepDummies => {
+            let count: usize = required_arg(invocation, "count")?;
+            if count == 0 {
+                return Err(Error::InvalidArgument("count", count.to_string()));
+            }
+            let dummies = prepare_dummies(&ctx, &ctx.config.dummy_address, count, ctx.dummy_value)?;
+            if !json {
+                for dummy in &dummies {
+                    println!("{}", dummy);
+                }
+            }
+            report.dummies = Some(dummies.iter().map(OutPoint::to_string).collect());
+        }
+        Command::Describe => unreachable!("handled before connecting to the nodes"),
     }
+    Ok(report)
+}
 
-    let dummy_utxo = retrieve_dummy_utxo(&buyer, &sorted_spendable_utxos);
-    let buyer_address = dummy_utxo
-        .clone()
-        .address
-        .unwrap()
-        .require_network(NETWORK)
-        .unwrap();
-
-    let seller_psbt = Psbt::from_str(&seller_psbt).unwrap();
-    let seller_psbt_extracted_tx = seller_psbt.clone().extract_tx();
-    let reversed_sorted_utxos = sorted_spendable_utxos
-        .clone()
-        .into_iter()
-        .rev()
+/// The fee `psbt` pays and its rate once signed, when every prevout is known.
+fn psbt_fee(psbt: &Psbt) -> Option<(Amount, f64)> {
+    let prevouts = (0..psbt.inputs.len())
+        .map(|i| psbt.spend_utxo(i).ok())
+        .collect::<Option<Vec<_>>>()?;
+    let input = prevouts
+        .iter()
+        .map(|prevout| Amount::from_sat(prevout.value))
+        .sum::<Amount>();
+    let output = psbt
+        .unsigned_tx
+        .output
+        .iter()
+        .map(|output| Amount::from_sat(output.value))
+        .sum::<Amount>();
+    let fee = input.checked_sub(output)?;
+    let input_kinds = prevouts
+        .iter()
+        .map(|prevout| InputKind::from_script(&prevout.script_pubkey))
         .collect::<Vec<_>>();
+    let vsize = estimate_vsize(&psbt.unsigned_tx, &input_kinds);
+    Some((fee, fee.to_sat() as f64 / vsize as f64))
+}
 
-    let mut purchase_tx = Transaction {
-        version: 2,
-        lock_time: LockTime::ZERO,
-        input: vec![
-            TxIn {
-                previous_output: OutPoint {
-                    txid: dummy_utxo.txid,
-                    vout: dummy_utxo.vout,
-                },
-                script_sig: ScriptBuf::new(),
-                sequence: Sequence::MAX,
-                witness: Witness::default(),
-            },
-            TxIn {
-                previous_output: seller_psbt_extracted_tx.input[0].previous_output.clone(),
-                script_sig: seller_psbt_extracted_tx.input[0].script_sig.clone(),
-                sequence: seller_psbt_extracted_tx.input[0].sequence.clone(),
-                witness: Witness::default(),
-            },
-        ],
-
-        output: vec![
-            TxOut {
-                value: inscription_tx_out.value + dummy_utxo.amount.to_sat(),
-                script_pubkey: buyer_address.script_pubkey(),
-            },
-            seller_psbt_extracted_tx.output[0].clone(),
-        ],
+fn lock_time(invocation: &Invocation) -> Result<Option<LockTime>> {
+    invocation
+        .value("locktime-height")
+        .map(|value| {
+            value
+                .parse()
+                .ok()
+                .and_then(|height| LockTime::from_height(height).ok())
+                .ok_or_else(|| Error::InvalidArgument("locktime-height", value.to_string()))
+        })
+        .transpose()
+}
+
+fn royalty(invocation: &Invocation, network: Network, price: Amount) -> Result<Option<Royalty>> {
+    let Some(recipient) = address_arg(invocation, "royalty-to", network)? else {
+        return Ok(None);
     };
+    let basis_points = invocation.value("royalty-bps").unwrap_or_default();
+    let basis_points = basis_points
+        .parse()
+        .map_err(|_| Error::InvalidArgument("royalty-bps", basis_points.to_string()))?;
+    Ok(Some(Royalty::from_basis_points(
+        recipient,
+        price,
+        basis_points,
+    )))
+}
 
-    // payment
-    let mut payment_utxos_value = 0;
-    let required_payment_value = PRICE + SERVICE_FEE + 1000 + 180 * 2 + 3 * 34 + 10;
-    let mut selected_payment_utxos: Vec<ListUnspentResultEntry> = Vec::new();
-
-    for utxo in reversed_sorted_utxos {
-        selected_payment_utxos.push(utxo.clone());
-        purchase_tx.input.push(TxIn {
-            previous_output: OutPoint {
-                txid: utxo.txid,
-                vout: utxo.vout,
-            },
-            script_sig: ScriptBuf::new(),
-            sequence: Sequence::MAX,
-            witness: Witness::default(),
-        });
-        payment_utxos_value += utxo.amount.to_sat();
-        if payment_utxos_value >= required_payment_value {
-            break;
-        }
+fn seller_key(invocation: &Invocation, network: Network) -> Result<Option<PrivateKey>> {
+    let Some(value) = invocation.value("privkey") else {
+        return Ok(None);
+    };
+    // never echo the key back in an error
+    let invalid = || Error::InvalidArgument("privkey", "<redacted>".to_string());
+    let key = PrivateKey::from_wif(value).map_err(|_| invalid())?;
+    // WIF only tells mainnet keys from test ones
+    if (key.network == Network::Bitcoin) != (network == Network::Bitcoin) {
+        return Err(invalid());
     }
+    Ok(Some(key))
+}
 
-    if payment_utxos_value < PRICE {
-        println!("buyer doesn't have enough funds");
-        return Default::default();
+/// The signer picked by `--signer`: the nodes' wallets by default, or with
+/// `hwi` the hardware wallet with `--device-fingerprint`.
+fn signer(invocation: &Invocation, network: Network) -> Result<Option<Box<dyn PsbtSigner>>> {
+    match invocation.value("signer") {
+        None | Some("wallet") => Ok(None),
+        Some("hwi") => {
+            let fingerprint = arg(invocation, "device-fingerprint")?
+                .ok_or_else(|| Error::InvalidArgument("device-fingerprint", String::new()))?;
+            Ok(Some(Box::new(Hwi::new(fingerprint, network))))
+        }
+        Some(other) => Err(Error::InvalidArgument("signer", other.to_string())),
     }
+}
 
-    purchase_tx.output.push(TxOut {
-        value: SERVICE_FEE,
-        script_pubkey: Address::from_str(&env::var("MARKET_PLACE_ADDRESS").unwrap())
-            .unwrap()
-            .require_network(NETWORK)
-            .unwrap()
-            .script_pubkey(),
-    });
-
-
-    purchase_tx.output.push(TxOut {
-        value: 1000,
-        script_pubkey: buyer_address.script_pubkey(),
-    });
+fn change_policy(invocation: &Invocation, network: Network) -> Result<ChangePolicy> {
+    if let Some(address) = address_arg(invocation, "change-to", network)? {
+        return Ok(ChangePolicy::ConsolidateTo(address));
+    }
+    if invocation.flag("no-next-dummy") {
+        return Ok(ChangePolicy::SingleChange);
+    }
+    if invocation.flag("reuse-dummy") {
+        return Ok(ChangePolicy::NextDummyIfNone);
+    }
+    Ok(ChangePolicy::default())
+}
 
-    purchase_tx.output.push(TxOut {
-        value: payment_utxos_value - required_payment_value,
-        script_pubkey: buyer_address.script_pubkey(),
-    });
+/// Asks `question` on stderr and reads a yes/no answer from stdin.
+fn confirm(question: &str) -> Result<bool> {
+    eprint!("{} [y/N] ", question);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
 
-    let mut buyer_psbt = Psbt::from_unsigned_tx(purchase_tx.clone()).unwrap();
+/// The value of `--<name>`, which the cli parser made sure is present.
+fn required_arg<T: FromStr>(invocation: &Invocation, name: &'static str) -> Result<T> {
+    let value = invocation.value(name).expect("required by the cli parser");
+    value
+        .parse()
+        .map_err(|_| Error::InvalidArgument(name, value.to_string()))
+}
 
-    buyer_psbt.inputs[0].non_witness_utxo = Some(
-        BUYER_NODE
-            .get_raw_transaction(&dummy_utxo.txid, None)
-            .unwrap(),
-    );
+/// Where `cancel` sends the inscription: `--to`, or else `SELLER_ADDRESS`.
+fn cancel_destination(invocation: &Invocation, config: &Config) -> Result<Address> {
+    Ok(address_arg(invocation, "to", config.network)?
+        .unwrap_or_else(|| config.seller_address.clone()))
+}
 
-    buyer_psbt.inputs[1] = seller_psbt.inputs[0].clone();
+/// The address given as `--<name>`, which must belong to `network`.
+fn address_arg(
+    invocation: &Invocation,
+    name: &'static str,
+    network: Network,
+) -> Result<Option<Address>> {
+    invocation
+        .value(name)
+        .map(|value| {
+            parse_checked_address(value, network).map_err(|e| match e {
+                Error::Address(_) => Error::InvalidArgument(name, value.to_string()),
+                e => e,
+            })
+        })
+        .transpose()
+}
 
-    selected_payment_utxos
-        .iter()
-        .enumerate()
-        .for_each(|(i, utxo)| {
-            buyer_psbt.inputs[i + 2].non_witness_utxo =
-                Some(BUYER_NODE.get_raw_transaction(&utxo.txid, None).unwrap());
-        });
+/// The value of `--<name>`, if given.
+fn arg<T: FromStr>(invocation: &Invocation, name: &'static str) -> Result<Option<T>> {
+    invocation
+        .value(name)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|_| Error::InvalidArgument(name, value.to_string()))
+        })
+        .transpose()
+}
 
-    let processed_buyer_psbt = BUYER_NODE
-        .wallet_process_psbt(&buyer_psbt.to_string(), Some(true), None, None)
-        .unwrap();
+/// `fee` with the `--conf-target` and `--max-fee-rate` overrides applied.
+fn fee_config(invocation: &Invocation, mut fee: FeeConfig) -> Result<FeeConfig> {
+    if let Some(value) = invocation.value("conf-target") {
+        fee.conf_target = value
+            .parse()
+            .map_err(|_| Error::InvalidArgument("conf-target", value.to_string()))?;
+    }
+    if let Some(value) = invocation.value("max-fee-rate") {
+        fee.max_fee_rate = value
+            .parse()
+            .map(Amount::from_sat)
+            .map_err(|_| Error::InvalidArgument("max-fee-rate", value.to_string()))?;
+    }
+    Ok(fee)
+}
 
-    processed_buyer_psbt.psbt
+fn inscription_utxo(invocation: &Invocation, config: &Config) -> Result<OutPoint> {
+    arg(invocation, "utxo")?
+        .or(config.seller_utxo)
+        .ok_or(Error::Env("SELLER_UTXO", env::VarError::NotPresent))
 }
 
-fn get_buyer_spendable_utxos(buyer: &Address) -> Vec<ListUnspentResultEntry> {
-    let unspent_utxos = BUYER_NODE
-        .list_unspent(None, None, Some(&[buyer]), Some(true), None)
-        .unwrap();
+fn price(invocation: &Invocation, config: &Config) -> Result<Amount> {
+    Ok(arg(invocation, "price")?.map_or(config.price, Amount::from_sat))
+}
 
-    // del utxos has inscription
-    let mut sorted_spendable_utxos = unspent_utxos
-        .into_iter()
-        .filter(|x| is_utxo_inscription(x) == false)
-        .collect::<Vec<_>>();
-    sorted_spendable_utxos.sort_by_key(|x| x.amount);
-    sorted_spendable_utxos
+/// Loads the PSBT named by `--<name>` or its `--in` alias.
+fn read_psbt(invocation: &Invocation, name: &str) -> Result<Psbt> {
+    let path = invocation
+        .value(name)
+        .or_else(|| invocation.value("in"))
+        .expect("required by the cli parser");
+    load_psbt(Path::new(path))
 }
 
-fn is_utxo_inscription(utxo: &ListUnspentResultEntry) -> bool {
-    let explorer_url = std::env::var("ORD_EXPLORER").unwrap()
-        + "output/"
-        + &utxo.txid.to_string()
-        + ":"
-        + &utxo.vout.to_string();
-    let resp = reqwest::blocking::get(explorer_url)
-        .unwrap()
-        .text()
-        .unwrap();
-    if resp.contains("inscription") {
-        true
-    } else {
-        false
+/// Saves `psbt` to `--out` if given, otherwise prints it, in the encoding
+/// picked by `--format` and the version picked by `--psbt-version`. With
+/// `--output json` it is only saved, the report carrying it instead.
+fn write_psbt(invocation: &Invocation, psbt: &Psbt) -> Result<()> {
+    let format = arg(invocation, "format")?.unwrap_or_default();
+    let version = arg(invocation, "psbt-version")?.unwrap_or_default();
+    match (invocation.value("out"), format) {
+        (Some(path), format) => save_psbt_version(psbt, Path::new(path), format, version),
+        (None, _) if invocation.json() => Ok(()),
+        (None, PsbtFormat::Base64) => {
+            println!("{}", psbt_to_base64(psbt, version));
+            Ok(())
+        }
+        (None, PsbtFormat::Binary) => Ok(io::stdout().write_all(&serialize_psbt(psbt, version))?),
     }
 }
 
-fn retrieve_dummy_utxo(
-    buyer: &Address,
-    utxos: &Vec<ListUnspentResultEntry>,
-) -> ListUnspentResultEntry {
-    let potential_dummy_utxos = &utxos
-        .iter()
-        .filter(|utxo| utxo.amount <= Amount::from_sat(1000))
-        .collect::<Vec<&ListUnspentResultEntry>>();
+/// `psbt` in base64 as the version picked by `--psbt-version`.
+fn psbt_base64(invocation: &Invocation, psbt: &Psbt) -> Result<String> {
+    Ok(psbt_to_base64(
+        psbt,
+        arg(invocation, "psbt-version")?.unwrap_or_default(),
+    ))
+}
 
-    let dummy_utxo = if potential_dummy_utxos.len() == 0 {
-        let dummy_address = utxos[0]
-            .clone()
-            .address
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_psbt::config::validate_env;
+
+    #[test]
+    fn runs_on_the_process_environment_without_a_dotenv() {
+        let _env = test_env::lock_env();
+        let missing = env::temp_dir().join("test-psbt-no-such-dir").join(".env");
+        assert!(!load_dotenv(&missing).unwrap());
+
+        env::set_var("BITCOIN_NETWORK", "regtest");
+        for node in ["BITCOIN", "SELLER", "BUYER"] {
+            env::set_var(format!("{}_RPC_URL", node), "http://127.0.0.1:18443");
+            env::set_var(format!("{}_RPC_USER", node), "user");
+            env::set_var(format!("{}_RPC_PASS", node), "pass");
+        }
+        for name in ["SELLER_ADDRESS", "BUYER_ADDRESS", "MARKET_PLACE_ADDRESS"] {
+            env::set_var(name, "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080");
+        }
+        env::set_var("ORD_EXPLORER", "http://127.0.0.1:8080/");
+        let config = validate_env().unwrap();
+        assert_eq!(config.network, Network::Regtest);
+    }
+
+    #[test]
+    fn reports_serialize_to_the_documented_keys() {
+        let report = Report {
+            seller_psbt: Some("cHNidP8=".to_string()),
+            buyer_psbt: Some("cHNidP8=".to_string()),
+            txid: Some("00".repeat(32)),
+            fee: Some(250),
+            fee_rate: Some(1.5),
+            ..Report::default()
+        };
+        let json: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&report).unwrap()).unwrap();
+        let mut keys = json
+            .as_object()
             .unwrap()
-            .require_network(NETWORK)
-            .unwrap();
-
-        let mut dummy_psbt = Psbt::from_unsigned_tx(Transaction {
-            version: 2,
-            lock_time: LockTime::ZERO,
-            input: vec![TxIn {
-                previous_output: OutPoint {
-                    txid: utxos[0].txid,
-                    vout: utxos[0].vout,
-                },
-                script_sig: ScriptBuf::new(),
-                sequence: Sequence::MAX,
-                witness: Witness::default(),
-            }],
-            output: vec![
-                TxOut {
-                    value: 1000,
-                    script_pubkey: dummy_address.script_pubkey(),
-                },
-                TxOut {
-                    value: utxos[0].amount.to_sat() - 1000 - 258,
-                    script_pubkey: dummy_address.script_pubkey(),
-                },
-            ],
-        })
-            .unwrap();
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(
+            keys,
+            ["buyer_psbt", "fee", "fee_rate", "seller_psbt", "txid"]
+        );
+        assert_eq!(json["fee"], 250);
+        assert_eq!(json["fee_rate"], 1.5);
+
+        let error = ErrorReport {
+            error: Error::PsbtNotFinalized.to_string(),
+        };
+        let json: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&error).unwrap()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({ "error": Error::PsbtNotFinalized.to_string() })
+        );
+    }
 
-        dummy_psbt.inputs[0].non_witness_utxo = Some(
-            BUYER_NODE
-                .get_raw_transaction(&utxos[0].txid, None)
-                .unwrap(),
+    #[test]
+    fn cancel_goes_back_to_the_seller_address_by_default() {
+        let address = |s: &str| {
+            s.parse::<Address<_>>()
+                .unwrap()
+                .require_network(Network::Regtest)
+                .unwrap()
+        };
+        let seller = address("bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080");
+        let other = address("bcrt1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3qzf4jry");
+        let config = Config::new(
+            Network::Regtest,
+            seller.clone(),
+            other.clone(),
+            other.clone(),
+            "http://127.0.0.1:8080/".parse().unwrap(),
         );
+        let args = |args: &[&str]| cli::parse(args.iter().map(|arg| arg.to_string())).unwrap();
 
-        let dummy_psbt_string = &dummy_psbt.to_string();
-        let processed_dummy_psbt = BUYER_NODE
-            .wallet_process_psbt(dummy_psbt_string, Some(true), None, None)
-            .unwrap();
-        let processed_dummy_psbt_string = &processed_dummy_psbt.psbt;
-        let dummy_raw_tx = BUYER_NODE
-            .finalize_psbt(processed_dummy_psbt_string, None)
-            .unwrap()
-            .hex
-            .unwrap();
-
-        let dummy_txid = BUYER_NODE.send_raw_transaction(&dummy_raw_tx).unwrap();
-        println!("created dummy {:?}", &dummy_txid);
-        let unspent_utxos = BUYER_NODE
-            .list_unspent(None, None, Some(&[&buyer]), Some(true), None)
-            .unwrap();
-        let mut sorted_utxos = unspent_utxos.clone();
-        sorted_utxos.sort_by_key(|x| x.amount);
-        let potential_dummy_utxos = &sorted_utxos
-            .iter()
-            .filter(|utxo| utxo.amount <= Amount::from_sat(1000))
-            .collect::<Vec<&ListUnspentResultEntry>>();
-        potential_dummy_utxos[0].clone()
-    } else {
-        potential_dummy_utxos[0].clone()
-    };
+        let invocation = args(&["cancel"]);
+        assert_eq!(cancel_destination(&invocation, &config).unwrap(), seller);
 
-    dummy_utxo
+        let invocation = args(&["cancel", "--to", &other.to_string()]);
+        assert_eq!(cancel_destination(&invocation, &config).unwrap(), other);
+    }
 }
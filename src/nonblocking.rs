@@ -0,0 +1,165 @@
+//! Async entry points for backends serving many buyers at once, behind the
+//! `async` feature. The node RPC client is blocking, so its calls run on
+//! tokio's blocking pool. The ord lookups use reqwest's async client and
+//! take it from the caller, so one client serves every request.
+
+use crate::buyer::{create_buyer_psbt, ChangePolicy, SortPolicy};
+use crate::explorer::explorer_endpoint;
+use crate::utxo::{inscribed_outputs, outputs_request, OrdOutput};
+use crate::{Error, MarketplaceContext, Result};
+use bitcoin::psbt::Psbt;
+use bitcoin::{Address, Amount, OutPoint, TxOut};
+use reqwest::header::{ACCEPT, CONTENT_TYPE};
+use reqwest::{Client, StatusCode, Url};
+use std::collections::HashSet;
+use std::panic;
+use std::sync::Arc;
+use tokio::task;
+
+/// Async counterpart of [`crate::utxo::is_utxo_inscription`].
+pub async fn is_utxo_inscription_async(
+    client: &Client,
+    explorer: &Url,
+    outpoint: OutPoint,
+) -> Result<bool> {
+    let resp = client
+        .get(explorer_endpoint(
+            explorer,
+            &format!("output/{}", outpoint),
+        )?)
+        .header(ACCEPT, "application/json")
+        .send()
+        .await?;
+    // ord doesn't know the output yet, so nothing can be inscribed on it
+    if resp.status() == StatusCode::NOT_FOUND {
+        return Ok(false);
+    }
+    let output: OrdOutput = serde_json::from_str(&resp.error_for_status()?.text().await?)?;
+    Ok(!output.inscriptions.is_empty())
+}
+
+/// Async counterpart of [`crate::utxo::fetch_inscription_outputs`]: one POST
+/// to ord's `/outputs` endpoint for all of `outpoints`.
+pub async fn fetch_inscription_outputs_async(
+    client: &Client,
+    explorer: &Url,
+    outpoints: &[OutPoint],
+) -> Result<HashSet<OutPoint>> {
+    if outpoints.is_empty() {
+        return Ok(HashSet::new());
+    }
+
+    let resp = client
+        .post(explorer_endpoint(explorer, "outputs")?)
+        .header(ACCEPT, "application/json")
+        .header(CONTENT_TYPE, "application/json")
+        .body(outputs_request(outpoints)?)
+        .send()
+        .await?
+        .error_for_status()?;
+    inscribed_outputs(outpoints, &resp.text().await?)
+}
+
+/// [`create_buyer_psbt`] for async callers. The buyer's UTXOs are looked up
+/// in the context's inscription index in one batch up front, so the builder
+/// finds them cached instead of asking one after the other.
+#[allow(clippy::too_many_arguments)]
+pub async fn create_buyer_psbt_async(
+    ctx: Arc<MarketplaceContext>,
+    seller_psbt: Psbt,
+    inscription_utxo: OutPoint,
+    inscription_tx_out: TxOut,
+    price: Amount,
+    receive_address: Option<Address>,
+    rbf: bool,
+    change_policy: ChangePolicy,
+    sort: SortPolicy,
+) -> Result<Psbt> {
+    prefetch_buyer_inscriptions(ctx.clone()).await?;
+    run_blocking(move || {
+        create_buyer_psbt(
+            &ctx,
+            seller_psbt,
+            inscription_utxo,
+            inscription_tx_out,
+            price,
+            receive_address,
+            rbf,
+            change_policy,
+            sort,
+        )
+    })
+    .await
+}
+
+/// Fills the context's inscription cache for every UTXO the buyer could
+/// spend with a single batched index lookup.
+async fn prefetch_buyer_inscriptions(ctx: Arc<MarketplaceContext>) -> Result<()> {
+    run_blocking(move || {
+        let buyer_addresses = ctx.config.buyer_addresses();
+        let unspent = ctx.retry.run(|| {
+            ctx.buyer_node.list_unspent(
+                Some(ctx.buyer_min_conf),
+                None,
+                Some(&buyer_addresses),
+                Some(true),
+                None,
+            )
+        })?;
+        ctx.inscription_outputs(&unspent)?;
+        Ok(())
+    })
+    .await
+}
+
+/// Runs `f` on tokio's blocking pool, passing its panics on to the caller.
+async fn run_blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    match task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(e) if e.is_panic() => panic::resume_unwind(e.into_panic()),
+        Err(_) => Err(Error::TaskCancelled),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::explorer::mock::{serve, Response};
+    use tokio::runtime::Builder;
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn batch_lookup_is_one_outputs_request() {
+        let inscribed: OutPoint =
+            "1111111111111111111111111111111111111111111111111111111111111111:0"
+                .parse()
+                .unwrap();
+        let plain: OutPoint = "2222222222222222222222222222222222222222222222222222222222222222:1"
+            .parse()
+            .unwrap();
+        let (url, server) = serve(vec![Response::ok(
+            r#"[{"inscriptions":["abc"]},{"inscriptions":[]}]"#,
+        )]);
+
+        let found = block_on(fetch_inscription_outputs_async(
+            &Client::new(),
+            &url,
+            &[inscribed, plain],
+        ))
+        .unwrap();
+
+        assert_eq!(found, HashSet::from([inscribed]));
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].starts_with("POST /outputs "), "{}", requests[0]);
+    }
+}
@@ -0,0 +1,172 @@
+use crate::psbt_v2::{deserialize_v2, is_v2, serialize_v2};
+use crate::{Error, Result};
+use bitcoin::base64;
+use bitcoin::psbt::Psbt;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/// The magic bytes every binary PSBT starts with.
+const PSBT_MAGIC: &[u8] = b"psbt\xff";
+
+/// How a PSBT is encoded on disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PsbtFormat {
+    /// The base64 text most wallets and RPCs exchange.
+    #[default]
+    Base64,
+    /// The raw BIP 174 serialization.
+    Binary,
+}
+
+impl FromStr for PsbtFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "base64" => Ok(PsbtFormat::Base64),
+            "binary" => Ok(PsbtFormat::Binary),
+            _ => Err(format!("unknown psbt format {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for PsbtFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PsbtFormat::Base64 => write!(f, "base64"),
+            PsbtFormat::Binary => write!(f, "binary"),
+        }
+    }
+}
+
+/// Which BIP a PSBT is serialized per.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PsbtVersion {
+    /// BIP 174, carrying the unsigned transaction; what nodes exchange.
+    #[default]
+    V0,
+    /// BIP 370, for signers that only take version 2.
+    V2,
+}
+
+impl FromStr for PsbtVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(PsbtVersion::V0),
+            "2" => Ok(PsbtVersion::V2),
+            _ => Err(format!("unsupported psbt version {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for PsbtVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PsbtVersion::V0 => write!(f, "0"),
+            PsbtVersion::V2 => write!(f, "2"),
+        }
+    }
+}
+
+/// The binary serialization of `psbt` as a PSBT of `version`.
+pub fn serialize_psbt(psbt: &Psbt, version: PsbtVersion) -> Vec<u8> {
+    match version {
+        PsbtVersion::V0 => psbt.serialize(),
+        PsbtVersion::V2 => serialize_v2(psbt),
+    }
+}
+
+/// `psbt` as a PSBT of `version` in base64.
+pub fn psbt_to_base64(psbt: &Psbt, version: PsbtVersion) -> String {
+    base64::encode(serialize_psbt(psbt, version))
+}
+
+/// Deserializes a binary PSBT of either version.
+pub fn deserialize_psbt(bytes: &[u8]) -> Result<Psbt> {
+    if is_v2(bytes) {
+        return deserialize_v2(bytes);
+    }
+    Ok(Psbt::deserialize(bytes)?)
+}
+
+/// Writes `psbt` to `path` in the standard base64 encoding.
+pub fn save_psbt(psbt: &Psbt, path: &Path) -> Result<()> {
+    fs::write(path, format!("{}\n", psbt))?;
+    Ok(())
+}
+
+/// Writes `psbt` to `path` in its raw binary serialization.
+pub fn save_psbt_binary(psbt: &Psbt, path: &Path) -> Result<()> {
+    fs::write(path, psbt.serialize())?;
+    Ok(())
+}
+
+/// Writes `psbt` to `path` in the given `format`.
+pub fn save_psbt_as(psbt: &Psbt, path: &Path, format: PsbtFormat) -> Result<()> {
+    match format {
+        PsbtFormat::Base64 => save_psbt(psbt, path),
+        PsbtFormat::Binary => save_psbt_binary(psbt, path),
+    }
+}
+
+/// Writes `psbt` to `path` as a PSBT of `version` in the given `format`.
+pub fn save_psbt_version(
+    psbt: &Psbt,
+    path: &Path,
+    format: PsbtFormat,
+    version: PsbtVersion,
+) -> Result<()> {
+    match format {
+        PsbtFormat::Base64 => fs::write(path, format!("{}\n", psbt_to_base64(psbt, version)))?,
+        PsbtFormat::Binary => fs::write(path, serialize_psbt(psbt, version))?,
+    }
+    Ok(())
+}
+
+/// Reads a PSBT from `path`, accepting both encodings and both versions:
+/// files starting with the binary magic are deserialized, anything else is
+/// decoded as base64.
+pub fn load_psbt(path: &Path) -> Result<Psbt> {
+    let bytes = fs::read(path)?;
+    if bytes.starts_with(PSBT_MAGIC) {
+        return deserialize_psbt(&bytes);
+    }
+    let text = String::from_utf8(bytes)
+        .map_err(|e| Error::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, e)))?;
+    match base64::decode(text.trim()) {
+        Ok(bytes) if is_v2(&bytes) => deserialize_v2(&bytes),
+        _ => Ok(Psbt::from_str(text.trim())?),
+    }
+}
+
+/// Reads a PSBT from `path` in its raw binary serialization.
+pub fn load_psbt_binary(path: &Path) -> Result<Psbt> {
+    Ok(Psbt::deserialize(&fs::read(path)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::mock::Market;
+    use std::env;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        env::temp_dir().join(format!("test-psbt-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn saved_psbt_reloads_identically() {
+        let (psbt, _) = Market::new().list();
+        for format in [PsbtFormat::Base64, PsbtFormat::Binary] {
+            let path = temp_path(&format!("round-trip.{}", format));
+            save_psbt_as(&psbt, &path, format).unwrap();
+            let loaded = load_psbt(&path);
+            fs::remove_file(&path).unwrap();
+            assert_eq!(loaded.unwrap(), psbt);
+        }
+    }
+}
@@ -0,0 +1,309 @@
+//! PSBT version 2 (BIP 370) encoding. The PSBTs built here are version 0
+//! ones; these convert their serialization to and from version 2, which
+//! replaces the global unsigned transaction with per-input and per-output
+//! fields, for signers that only take version 2.
+
+use crate::{Error, Result};
+use bitcoin::absolute::LockTime;
+use bitcoin::consensus::{self, Decodable, Encodable};
+use bitcoin::psbt::Psbt;
+use bitcoin::{OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, VarInt, Witness};
+
+/// The magic bytes every binary PSBT starts with.
+const PSBT_MAGIC: &[u8] = b"psbt\xff";
+
+const PSBT_GLOBAL_UNSIGNED_TX: u8 = 0x00;
+const PSBT_GLOBAL_TX_VERSION: u8 = 0x02;
+const PSBT_GLOBAL_FALLBACK_LOCKTIME: u8 = 0x03;
+const PSBT_GLOBAL_INPUT_COUNT: u8 = 0x04;
+const PSBT_GLOBAL_OUTPUT_COUNT: u8 = 0x05;
+const PSBT_GLOBAL_TX_MODIFIABLE: u8 = 0x06;
+const PSBT_GLOBAL_VERSION: u8 = 0xfb;
+const PSBT_IN_PREVIOUS_TXID: u8 = 0x0e;
+const PSBT_IN_OUTPUT_INDEX: u8 = 0x0f;
+const PSBT_IN_SEQUENCE: u8 = 0x10;
+const PSBT_IN_REQUIRED_TIME_LOCKTIME: u8 = 0x11;
+const PSBT_IN_REQUIRED_HEIGHT_LOCKTIME: u8 = 0x12;
+const PSBT_OUT_AMOUNT: u8 = 0x03;
+const PSBT_OUT_SCRIPT: u8 = 0x04;
+
+/// A PSBT map's key-value pairs in order, each key with its type byte.
+type Map = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Serializes `psbt` as a version 2 PSBT, carrying over every field of its
+/// version 0 serialization except the unsigned transaction, which is split
+/// into the per-input and per-output fields.
+pub fn serialize_v2(psbt: &Psbt) -> Vec<u8> {
+    let tx = &psbt.unsigned_tx;
+    let bytes = psbt.serialize();
+    let (global, inputs, outputs) = read_maps(&bytes, |_| Ok((tx.input.len(), tx.output.len())))
+        .expect("a serialized psbt has one map per input and output");
+
+    let mut v2_global = vec![
+        (
+            vec![PSBT_GLOBAL_TX_VERSION],
+            consensus::serialize(&tx.version),
+        ),
+        (
+            vec![PSBT_GLOBAL_FALLBACK_LOCKTIME],
+            consensus::serialize(&tx.lock_time.to_consensus_u32()),
+        ),
+        (
+            vec![PSBT_GLOBAL_INPUT_COUNT],
+            consensus::serialize(&VarInt(tx.input.len() as u64)),
+        ),
+        (
+            vec![PSBT_GLOBAL_OUTPUT_COUNT],
+            consensus::serialize(&VarInt(tx.output.len() as u64)),
+        ),
+        (vec![PSBT_GLOBAL_VERSION], consensus::serialize(&2u32)),
+    ];
+    v2_global.extend(global.into_iter().filter(|(key, _)| {
+        key[..] != [PSBT_GLOBAL_UNSIGNED_TX] && key[..] != [PSBT_GLOBAL_VERSION]
+    }));
+
+    let mut out = PSBT_MAGIC.to_vec();
+    write_map(&mut out, &v2_global);
+    for (txin, map) in tx.input.iter().zip(inputs) {
+        let mut v2_map = vec![
+            (
+                vec![PSBT_IN_PREVIOUS_TXID],
+                consensus::serialize(&txin.previous_output.txid),
+            ),
+            (
+                vec![PSBT_IN_OUTPUT_INDEX],
+                consensus::serialize(&txin.previous_output.vout),
+            ),
+            (
+                vec![PSBT_IN_SEQUENCE],
+                consensus::serialize(&txin.sequence.0),
+            ),
+        ];
+        v2_map.extend(map);
+        write_map(&mut out, &v2_map);
+    }
+    for (txout, map) in tx.output.iter().zip(outputs) {
+        let mut v2_map = vec![
+            (vec![PSBT_OUT_AMOUNT], consensus::serialize(&txout.value)),
+            (vec![PSBT_OUT_SCRIPT], txout.script_pubkey.to_bytes()),
+        ];
+        v2_map.extend(map);
+        write_map(&mut out, &v2_map);
+    }
+    out
+}
+
+/// Whether `bytes` are a binary PSBT declaring version 2.
+pub fn is_v2(bytes: &[u8]) -> bool {
+    let Some(mut rest) = bytes.strip_prefix(PSBT_MAGIC) else {
+        return false;
+    };
+    read_map(&mut rest).is_ok_and(|global| {
+        global
+            .iter()
+            .any(|(key, value)| key[..] == [PSBT_GLOBAL_VERSION] && value[..] == [2, 0, 0, 0])
+    })
+}
+
+/// Deserializes a version 2 PSBT into the version 0 form the rest of the
+/// crate works with, rebuilding the unsigned transaction from the
+/// per-input and per-output fields and picking its locktime as BIP 370 does.
+pub fn deserialize_v2(bytes: &[u8]) -> Result<Psbt> {
+    let (mut global, mut inputs, mut outputs) = read_maps(bytes, |global| {
+        Ok((
+            take::<VarInt>(global, PSBT_GLOBAL_INPUT_COUNT, "missing input count")?.0 as usize,
+            take::<VarInt>(global, PSBT_GLOBAL_OUTPUT_COUNT, "missing output count")?.0 as usize,
+        ))
+    })?;
+    if take::<u32>(&mut global, PSBT_GLOBAL_VERSION, "missing version")? != 2 {
+        return Err(Error::InvalidPsbtV2("not a version 2 psbt"));
+    }
+    let version = take(
+        &mut global,
+        PSBT_GLOBAL_TX_VERSION,
+        "missing transaction version",
+    )?;
+    let fallback_lock_time = take_opt::<u32>(&mut global, PSBT_GLOBAL_FALLBACK_LOCKTIME)?;
+    global.retain(|(key, _)| key[..] != [PSBT_GLOBAL_TX_MODIFIABLE]);
+
+    let mut input = Vec::with_capacity(inputs.len());
+    let (mut time_lock, mut height_lock) = (None::<u32>, None::<u32>);
+    for map in &mut inputs {
+        input.push(TxIn {
+            previous_output: OutPoint::new(
+                take::<Txid>(map, PSBT_IN_PREVIOUS_TXID, "missing input previous txid")?,
+                take(map, PSBT_IN_OUTPUT_INDEX, "missing input output index")?,
+            ),
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence(take_opt(map, PSBT_IN_SEQUENCE)?.unwrap_or(u32::MAX)),
+            witness: Witness::default(),
+        });
+        if let Some(time) = take_opt::<u32>(map, PSBT_IN_REQUIRED_TIME_LOCKTIME)? {
+            time_lock = time_lock.max(Some(time));
+        }
+        if let Some(height) = take_opt::<u32>(map, PSBT_IN_REQUIRED_HEIGHT_LOCKTIME)? {
+            height_lock = height_lock.max(Some(height));
+        }
+    }
+    let mut output = Vec::with_capacity(outputs.len());
+    for map in &mut outputs {
+        let value = take::<i64>(map, PSBT_OUT_AMOUNT, "missing output amount")?;
+        output.push(TxOut {
+            value: u64::try_from(value)
+                .map_err(|_| Error::InvalidPsbtV2("negative output amount"))?,
+            script_pubkey: ScriptBuf::from(take_raw(
+                map,
+                PSBT_OUT_SCRIPT,
+                "missing output script",
+            )?),
+        });
+    }
+    // a height lock is preferred when inputs accept either
+    let lock_time = height_lock
+        .or(time_lock)
+        .or(fallback_lock_time)
+        .unwrap_or(0);
+    let tx = Transaction {
+        version,
+        lock_time: LockTime::from_consensus(lock_time),
+        input,
+        output,
+    };
+
+    let mut v0_global = vec![(vec![PSBT_GLOBAL_UNSIGNED_TX], consensus::serialize(&tx))];
+    v0_global.extend(global);
+    let mut v0 = PSBT_MAGIC.to_vec();
+    write_map(&mut v0, &v0_global);
+    for map in inputs.iter().chain(&outputs) {
+        write_map(&mut v0, map);
+    }
+    Ok(Psbt::deserialize(&v0)?)
+}
+
+/// Splits a binary PSBT into its global, input and output maps, `counts`
+/// telling from the global map how many input and output maps follow.
+fn read_maps(
+    bytes: &[u8],
+    counts: impl FnOnce(&mut Map) -> Result<(usize, usize)>,
+) -> Result<(Map, Vec<Map>, Vec<Map>)> {
+    let mut rest = bytes
+        .strip_prefix(PSBT_MAGIC)
+        .ok_or(Error::InvalidPsbtV2("missing magic bytes"))?;
+    let mut global = read_map(&mut rest)?;
+    let (input_count, output_count) = counts(&mut global)?;
+    let inputs = (0..input_count)
+        .map(|_| read_map(&mut rest))
+        .collect::<Result<Vec<_>>>()?;
+    let outputs = (0..output_count)
+        .map(|_| read_map(&mut rest))
+        .collect::<Result<Vec<_>>>()?;
+    if !rest.is_empty() {
+        return Err(Error::InvalidPsbtV2("trailing data"));
+    }
+    Ok((global, inputs, outputs))
+}
+
+/// Reads key-value pairs up to the separator ending a map.
+fn read_map(rest: &mut &[u8]) -> Result<Map> {
+    let mut map = Map::new();
+    loop {
+        let key = read_bytes(rest)?;
+        if key.is_empty() {
+            return Ok(map);
+        }
+        let value = read_bytes(rest)?;
+        map.push((key, value));
+    }
+}
+
+/// Reads a compact size length and that many bytes.
+fn read_bytes(rest: &mut &[u8]) -> Result<Vec<u8>> {
+    let truncated = || Error::InvalidPsbtV2("truncated");
+    let len = VarInt::consensus_decode(rest).map_err(|_| truncated())?.0 as usize;
+    if rest.len() < len {
+        return Err(truncated());
+    }
+    let (bytes, tail) = rest.split_at(len);
+    *rest = tail;
+    Ok(bytes.to_vec())
+}
+
+fn write_map(out: &mut Vec<u8>, map: &Map) {
+    for (key, value) in map {
+        for bytes in [key, value] {
+            VarInt(bytes.len() as u64)
+                .consensus_encode(out)
+                .expect("writing to a vec can't fail");
+            out.extend_from_slice(bytes);
+        }
+    }
+    out.push(0x00);
+}
+
+/// Removes the keydata-less field `key_type` from `map`, returning its raw
+/// value if present.
+fn take_raw_opt(map: &mut Map, key_type: u8) -> Option<Vec<u8>> {
+    let index = map.iter().position(|(key, _)| key[..] == [key_type])?;
+    Some(map.remove(index).1)
+}
+
+fn take_raw(map: &mut Map, key_type: u8, name: &'static str) -> Result<Vec<u8>> {
+    take_raw_opt(map, key_type).ok_or(Error::InvalidPsbtV2(name))
+}
+
+fn take_opt<T: Decodable>(map: &mut Map, key_type: u8) -> Result<Option<T>> {
+    take_raw_opt(map, key_type)
+        .map(|value| {
+            consensus::deserialize(&value).map_err(|_| Error::InvalidPsbtV2("malformed field"))
+        })
+        .transpose()
+}
+
+fn take<T: Decodable>(map: &mut Map, key_type: u8, name: &'static str) -> Result<T> {
+    take_opt(map, key_type)?.ok_or(Error::InvalidPsbtV2(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::mock::Market;
+
+    #[test]
+    fn purchase_round_trips_through_version_2() {
+        let market = Market::new();
+        let psbt = market.buy(market.list()).unwrap();
+        let bytes = serialize_v2(&psbt);
+        assert!(is_v2(&bytes));
+        assert!(!is_v2(&psbt.serialize()));
+
+        // laid out as BIP 370 asks: no unsigned transaction, but counts
+        // up front and the outpoints and amounts in their own fields
+        let (mut global, mut inputs, mut outputs) = read_maps(&bytes, |global| {
+            Ok((
+                take::<VarInt>(global, PSBT_GLOBAL_INPUT_COUNT, "input count")?.0 as usize,
+                take::<VarInt>(global, PSBT_GLOBAL_OUTPUT_COUNT, "output count")?.0 as usize,
+            ))
+        })
+        .unwrap();
+        assert!(take_raw_opt(&mut global, PSBT_GLOBAL_UNSIGNED_TX).is_none());
+        assert_eq!(
+            take::<u32>(&mut global, PSBT_GLOBAL_VERSION, "").unwrap(),
+            2
+        );
+        let tx = &psbt.unsigned_tx;
+        assert_eq!(
+            (inputs.len(), outputs.len()),
+            (tx.input.len(), tx.output.len())
+        );
+        for (map, txin) in inputs.iter_mut().zip(&tx.input) {
+            let txid = take::<Txid>(map, PSBT_IN_PREVIOUS_TXID, "").unwrap();
+            let vout = take::<u32>(map, PSBT_IN_OUTPUT_INDEX, "").unwrap();
+            assert_eq!(OutPoint::new(txid, vout), txin.previous_output);
+        }
+        for (map, txout) in outputs.iter_mut().zip(&tx.output) {
+            assert_eq!(take::<u64>(map, PSBT_OUT_AMOUNT, "").unwrap(), txout.value);
+        }
+
+        assert_eq!(deserialize_v2(&bytes).unwrap(), psbt);
+    }
+}
@@ -0,0 +1,202 @@
+//! End-to-end purchase against a regtest bitcoind, behind the `integration`
+//! feature. The node is spawned from `BITCOIND_EXE`, or `bitcoind` on the
+//! `PATH`, in a fresh data directory with a seller, a buyer and a
+//! marketplace wallet. ord is replaced by a predicate that knows the one
+//! fake inscription.
+
+use crate::buyer::{create_buyer_psbt, ChangePolicy, SortPolicy};
+use crate::config::Config;
+use crate::finalize::finalize;
+use crate::seller::{create_seller_psbt, DEFAULT_SELLER_SIGHASH};
+use crate::verify::INSCRIPTION_CARRIER_OUTPUT;
+use crate::MarketplaceContext;
+use bitcoin::{consensus, Address, Amount, Network, OutPoint};
+use bitcoincore_rpc::{Auth, Client, RpcApi};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{self, Child, Command, Stdio};
+use std::time::{Duration, Instant};
+use std::{env, fs, thread};
+
+const RPC_USER: &str = "test-psbt";
+const RPC_PASS: &str = "test-psbt";
+
+/// A regtest bitcoind, killed and its data directory removed on drop.
+struct Bitcoind {
+    process: Child,
+    datadir: PathBuf,
+    rpc_url: String,
+}
+
+impl Bitcoind {
+    /// Starts a node and waits until it answers RPC calls.
+    fn start() -> Self {
+        let exe = env::var("BITCOIND_EXE").unwrap_or_else(|_| "bitcoind".to_string());
+        let rpc_port = free_port();
+        let datadir =
+            env::temp_dir().join(format!("test-psbt-regtest-{}-{}", process::id(), rpc_port));
+        fs::create_dir_all(&datadir).unwrap();
+        let process = Command::new(&exe)
+            .arg("-regtest")
+            .arg("-server")
+            .arg("-txindex")
+            .arg("-listen=0")
+            .arg("-printtoconsole=0")
+            .arg(format!("-datadir={}", datadir.display()))
+            .arg(format!("-rpcport={}", rpc_port))
+            .arg(format!("-rpcuser={}", RPC_USER))
+            .arg(format!("-rpcpassword={}", RPC_PASS))
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap_or_else(|e| panic!("cannot start {}: {}", exe, e));
+        let node = Bitcoind {
+            process,
+            datadir,
+            rpc_url: format!("http://127.0.0.1:{}", rpc_port),
+        };
+
+        let client = node.client(None);
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while client.get_blockchain_info().is_err() {
+            assert!(Instant::now() < deadline, "bitcoind did not start");
+            thread::sleep(Duration::from_millis(100));
+        }
+        node
+    }
+
+    /// A client of the node, or of one of its wallets.
+    fn client(&self, wallet: Option<&str>) -> Client {
+        let url = match wallet {
+            Some(wallet) => format!("{}/wallet/{}", self.rpc_url, wallet),
+            None => self.rpc_url.clone(),
+        };
+        Client::new(&url, Auth::UserPass(RPC_USER.into(), RPC_PASS.into())).unwrap()
+    }
+
+    /// Creates the wallet `name` and returns a client of it.
+    fn wallet(&self, name: &str) -> Client {
+        self.client(None)
+            .create_wallet(name, None, None, None, None)
+            .unwrap();
+        self.client(Some(name))
+    }
+}
+
+impl Drop for Bitcoind {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        let _ = fs::remove_dir_all(&self.datadir);
+    }
+}
+
+/// A port nothing listens on right now.
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn new_address(wallet: &Client) -> Address {
+    wallet
+        .get_new_address(None, None)
+        .unwrap()
+        .require_network(Network::Regtest)
+        .unwrap()
+}
+
+#[test]
+fn purchase_confirms_on_regtest() {
+    let node = Bitcoind::start();
+    let full = node.client(None);
+    let seller = node.wallet("seller");
+    let buyer = node.wallet("buyer");
+    let market = node.wallet("market");
+    let seller_address = new_address(&seller);
+    let buyer_address = new_address(&buyer);
+
+    // enough mature coinbases for the inscription, the dummy split and the
+    // payment
+    buyer.generate_to_address(110, &buyer_address).unwrap();
+    let inscription_txid = buyer
+        .send_to_address(
+            &seller_address,
+            Amount::from_sat(10_000),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+    buyer.generate_to_address(1, &buyer_address).unwrap();
+    let inscription_tx = full.get_raw_transaction(&inscription_txid, None).unwrap();
+    let vout = inscription_tx
+        .output
+        .iter()
+        .position(|output| output.script_pubkey == seller_address.script_pubkey())
+        .unwrap();
+    let inscription_utxo = OutPoint::new(inscription_txid, vout as u32);
+
+    let mut config = Config::new(
+        Network::Regtest,
+        seller_address,
+        buyer_address.clone(),
+        new_address(&market),
+        "http://127.0.0.1:1/".parse().unwrap(),
+    );
+    config.price = Amount::from_sat(50_000);
+    let price = config.price;
+    let mut ctx = MarketplaceContext::new(
+        node.client(None),
+        node.client(Some("seller")),
+        node.client(Some("buyer")),
+        config,
+    );
+    ctx.inscription_index = Box::new(move |out: OutPoint| Ok(out == inscription_utxo));
+    // regtest has no fee estimates, and the fallback of 1 sat/vB leaves no
+    // room under the relay minimum
+    ctx.fee.min_fee_rate = Amount::from_sat(2);
+    ctx.fee.fallback_fee_rate = Amount::from_sat(2);
+
+    let (seller_psbt, inscription_tx_out) = create_seller_psbt(
+        &ctx,
+        inscription_utxo,
+        price,
+        DEFAULT_SELLER_SIGHASH,
+        None,
+        None,
+    )
+    .unwrap();
+    let mut buyer_psbt = create_buyer_psbt(
+        &ctx,
+        seller_psbt,
+        inscription_utxo,
+        inscription_tx_out,
+        price,
+        None,
+        true,
+        ChangePolicy::default(),
+        SortPolicy::AsBuilt,
+    )
+    .unwrap();
+    let tx = finalize(&mut buyer_psbt).unwrap();
+    let txid = buyer
+        .send_raw_transaction(&consensus::serialize(&tx))
+        .unwrap();
+    buyer.generate_to_address(1, &buyer_address).unwrap();
+
+    let confirmations = full
+        .get_raw_transaction_info(&txid, None)
+        .unwrap()
+        .confirmations;
+    assert_eq!(confirmations, Some(1));
+    // the inscription went to the buyer
+    assert_eq!(
+        tx.output[INSCRIPTION_CARRIER_OUTPUT].script_pubkey,
+        buyer_address.script_pubkey()
+    );
+}
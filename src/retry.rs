@@ -0,0 +1,93 @@
+use crate::{env_or, Result};
+use bitcoincore_rpc::jsonrpc;
+use std::thread;
+use std::time::Duration;
+
+/// Attempts made when `RPC_RETRY_ATTEMPTS` is unset.
+pub const DEFAULT_RETRY_ATTEMPTS: u32 = 3;
+/// First backoff delay when `RPC_RETRY_BASE_DELAY_MS` is unset.
+pub const DEFAULT_RETRY_BASE_DELAY: Duration = Duration::from_millis(250);
+
+/// bitcoind's "RPC in warm-up" error code.
+const RPC_IN_WARMUP: i32 = -28;
+
+/// How often and how patiently idempotent RPC reads are retried.
+///
+/// Only reads go through this; broadcasts and wallet signing are never
+/// retried, since a request that timed out may still have gone through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    /// Delay before the second attempt, doubled for every further one.
+    pub base_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: DEFAULT_RETRY_ATTEMPTS,
+            base_delay: DEFAULT_RETRY_BASE_DELAY,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Reads `RPC_RETRY_ATTEMPTS` and `RPC_RETRY_BASE_DELAY_MS`.
+    pub fn from_env() -> Result<Self> {
+        Ok(RetryPolicy {
+            max_attempts: env_or("RPC_RETRY_ATTEMPTS", DEFAULT_RETRY_ATTEMPTS)?,
+            base_delay: Duration::from_millis(env_or(
+                "RPC_RETRY_BASE_DELAY_MS",
+                DEFAULT_RETRY_BASE_DELAY.as_millis() as u64,
+            )?),
+        })
+    }
+
+    /// Runs `op`, retrying transient failures with exponential backoff.
+    pub fn run<T>(
+        &self,
+        op: impl Fn() -> std::result::Result<T, bitcoincore_rpc::Error>,
+    ) -> Result<T> {
+        let mut attempt = 1;
+        loop {
+            match op() {
+                Err(e) if attempt < self.max_attempts && is_transient(&e) => {
+                    let delay = self.base_delay * 2u32.saturating_pow(attempt - 1);
+                    log::warn!(
+                        "rpc call failed, retrying attempt={} delay_ms={} error={}",
+                        attempt,
+                        delay.as_millis(),
+                        e
+                    );
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                result => return Ok(result?),
+            }
+        }
+    }
+}
+
+/// Runs the idempotent RPC `op` up to `max_attempts` times, backing off
+/// exponentially from [`DEFAULT_RETRY_BASE_DELAY`] between transient failures.
+pub fn with_retry<T>(
+    op: impl Fn() -> std::result::Result<T, bitcoincore_rpc::Error>,
+    max_attempts: u32,
+) -> Result<T> {
+    RetryPolicy {
+        max_attempts,
+        ..RetryPolicy::default()
+    }
+    .run(op)
+}
+
+/// Connection problems and a node still warming up are worth retrying;
+/// anything the node actually answered is not.
+fn is_transient(e: &bitcoincore_rpc::Error) -> bool {
+    match e {
+        bitcoincore_rpc::Error::Io(_) => true,
+        bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Transport(_)) => true,
+        bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(rpc)) => rpc.code == RPC_IN_WARMUP,
+        _ => false,
+    }
+}
@@ -0,0 +1,925 @@
+use crate::{Error, MarketplaceContext, Result};
+use bitcoin::{consensus, Address, Amount, BlockHash, Network, Transaction, Txid};
+use bitcoincore_rpc::json::{
+    EstimateMode, EstimateSmartFeeResult, FinalizePsbtResult, GetTransactionResult, GetTxOutResult,
+    ListUnspentQueryOptions, ListUnspentResultEntry, SigHashType, TestMempoolAcceptResult,
+    WalletProcessPsbtResult,
+};
+use bitcoincore_rpc::{jsonrpc, Client, RpcApi};
+use serde::Deserialize;
+use serde_json::json;
+
+pub type RpcResult<T> = std::result::Result<T, bitcoincore_rpc::Error>;
+
+/// bitcoind's "invalid address or key" error code, which is also what it
+/// answers for a transaction it can't find.
+pub(crate) const RPC_INVALID_ADDRESS_OR_KEY: i32 = -5;
+
+/// The node RPCs the marketplace flows use, so they can run against
+/// something other than a live node. Methods mirror their [`RpcApi`]
+/// counterparts. Implementations are shared across threads by the async
+/// flow.
+pub trait BitcoinRpc: Send + Sync {
+    fn get_raw_transaction(
+        &self,
+        txid: &Txid,
+        block_hash: Option<&BlockHash>,
+    ) -> RpcResult<Transaction>;
+
+    fn get_tx_out(
+        &self,
+        txid: &Txid,
+        vout: u32,
+        include_mempool: Option<bool>,
+    ) -> RpcResult<Option<GetTxOutResult>>;
+
+    fn list_unspent(
+        &self,
+        minconf: Option<usize>,
+        maxconf: Option<usize>,
+        addresses: Option<&[&Address]>,
+        include_unsafe: Option<bool>,
+        query_options: Option<ListUnspentQueryOptions>,
+    ) -> RpcResult<Vec<ListUnspentResultEntry>>;
+
+    fn get_balance(
+        &self,
+        minconf: Option<usize>,
+        include_watchonly: Option<bool>,
+    ) -> RpcResult<Amount>;
+
+    fn wallet_process_psbt(
+        &self,
+        psbt: &str,
+        sign: Option<bool>,
+        sighash_type: Option<SigHashType>,
+        bip32derivs: Option<bool>,
+    ) -> RpcResult<WalletProcessPsbtResult>;
+
+    fn finalize_psbt(&self, psbt: &str, extract: Option<bool>) -> RpcResult<FinalizePsbtResult>;
+
+    fn send_raw_transaction(&self, tx: &[u8]) -> RpcResult<Txid>;
+
+    fn test_mempool_accept(&self, rawtxs: &[&[u8]]) -> RpcResult<Vec<TestMempoolAcceptResult>>;
+
+    fn estimate_smart_fee(
+        &self,
+        conf_target: u16,
+        estimate_mode: Option<EstimateMode>,
+    ) -> RpcResult<EstimateSmartFeeResult>;
+
+    /// `getaddressinfo` as raw JSON; the typed result lacks the descriptor.
+    fn get_address_info(&self, address: &Address) -> RpcResult<serde_json::Value>;
+
+    fn get_transaction(
+        &self,
+        txid: &Txid,
+        include_watchonly: Option<bool>,
+    ) -> RpcResult<GetTransactionResult>;
+
+    /// `psbtbumpfee` at `fee_rate` sat/vB, returning the unsigned base64
+    /// replacement PSBT.
+    fn psbt_bump_fee(&self, txid: &Txid, fee_rate: Amount) -> RpcResult<String>;
+
+    /// `getblockchaininfo` as raw JSON; the typed result fails to parse the
+    /// replies of newer nodes.
+    fn get_blockchain_info(&self) -> RpcResult<serde_json::Value>;
+}
+
+/// Whether `client` already has `txid` in its mempool or, as far as it can
+/// look it up, in the chain, so a rerun doesn't broadcast the same
+/// transaction twice.
+pub fn already_broadcast(client: &dyn BitcoinRpc, txid: Txid) -> Result<bool> {
+    match client.get_raw_transaction(&txid, None) {
+        Ok(_) => Ok(true),
+        Err(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(e)))
+            if e.code == RPC_INVALID_ADDRESS_OR_KEY =>
+        {
+            Ok(false)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Asks each of the context's nodes for its chain state, failing with
+/// [`Error::NodeUnreachable`] naming the first that doesn't answer or
+/// [`Error::NetworkMismatch`] if one is on another chain than the configured
+/// network, so a down or misconfigured node is reported before any work
+/// starts.
+pub fn ping_nodes(ctx: &MarketplaceContext) -> Result<()> {
+    let expected = ctx.config.network;
+    for (which, node) in [
+        ("full", &ctx.full_node),
+        ("seller", &ctx.seller_node),
+        ("buyer", &ctx.buyer_node),
+    ] {
+        let info = node
+            .get_blockchain_info()
+            .map_err(|source| Error::NodeUnreachable { which, source })?;
+        let chain = info["chain"].as_str().unwrap_or_default();
+        if Network::from_core_arg(chain).ok() != Some(expected) {
+            return Err(Error::NetworkMismatch {
+                node: which,
+                expected,
+                actual: chain.to_string(),
+            });
+        }
+        log::debug!("node reachable which={} chain={}", which, chain);
+    }
+    Ok(())
+}
+
+/// Asks `client` whether its mempool would accept `tx`, without relaying it,
+/// and fails with the node's reject reason if not.
+pub fn test_accept(client: &dyn BitcoinRpc, tx: &Transaction) -> Result<TestMempoolAcceptResult> {
+    let raw_tx = consensus::serialize(tx);
+    let result = client
+        .test_mempool_accept(&[&raw_tx])?
+        .pop()
+        .ok_or_else(|| Error::MempoolRejected(tx.txid(), "no result".to_string()))?;
+    if !result.allowed {
+        return Err(Error::MempoolRejected(
+            tx.txid(),
+            result
+                .reject_reason
+                .unwrap_or_else(|| "no reason given".to_string()),
+        ));
+    }
+    Ok(result)
+}
+
+/// The part of `psbtbumpfee`'s response we care about.
+#[derive(Debug, Deserialize)]
+struct PsbtBumpFeeResult {
+    psbt: String,
+}
+
+impl BitcoinRpc for Client {
+    fn get_raw_transaction(
+        &self,
+        txid: &Txid,
+        block_hash: Option<&BlockHash>,
+    ) -> RpcResult<Transaction> {
+        RpcApi::get_raw_transaction(self, txid, block_hash)
+    }
+
+    fn get_tx_out(
+        &self,
+        txid: &Txid,
+        vout: u32,
+        include_mempool: Option<bool>,
+    ) -> RpcResult<Option<GetTxOutResult>> {
+        RpcApi::get_tx_out(self, txid, vout, include_mempool)
+    }
+
+    fn list_unspent(
+        &self,
+        minconf: Option<usize>,
+        maxconf: Option<usize>,
+        addresses: Option<&[&Address]>,
+        include_unsafe: Option<bool>,
+        query_options: Option<ListUnspentQueryOptions>,
+    ) -> RpcResult<Vec<ListUnspentResultEntry>> {
+        RpcApi::list_unspent(
+            self,
+            minconf,
+            maxconf,
+            addresses,
+            include_unsafe,
+            query_options,
+        )
+    }
+
+    fn get_balance(
+        &self,
+        minconf: Option<usize>,
+        include_watchonly: Option<bool>,
+    ) -> RpcResult<Amount> {
+        RpcApi::get_balance(self, minconf, include_watchonly)
+    }
+
+    fn wallet_process_psbt(
+        &self,
+        psbt: &str,
+        sign: Option<bool>,
+        sighash_type: Option<SigHashType>,
+        bip32derivs: Option<bool>,
+    ) -> RpcResult<WalletProcessPsbtResult> {
+        RpcApi::wallet_process_psbt(self, psbt, sign, sighash_type, bip32derivs)
+    }
+
+    fn finalize_psbt(&self, psbt: &str, extract: Option<bool>) -> RpcResult<FinalizePsbtResult> {
+        RpcApi::finalize_psbt(self, psbt, extract)
+    }
+
+    fn send_raw_transaction(&self, tx: &[u8]) -> RpcResult<Txid> {
+        RpcApi::send_raw_transaction(self, tx)
+    }
+
+    fn test_mempool_accept(&self, rawtxs: &[&[u8]]) -> RpcResult<Vec<TestMempoolAcceptResult>> {
+        RpcApi::test_mempool_accept(self, rawtxs)
+    }
+
+    fn estimate_smart_fee(
+        &self,
+        conf_target: u16,
+        estimate_mode: Option<EstimateMode>,
+    ) -> RpcResult<EstimateSmartFeeResult> {
+        RpcApi::estimate_smart_fee(self, conf_target, estimate_mode)
+    }
+
+    fn get_address_info(&self, address: &Address) -> RpcResult<serde_json::Value> {
+        self.call("getaddressinfo", &[address.to_string().into()])
+    }
+
+    fn get_transaction(
+        &self,
+        txid: &Txid,
+        include_watchonly: Option<bool>,
+    ) -> RpcResult<GetTransactionResult> {
+        RpcApi::get_transaction(self, txid, include_watchonly)
+    }
+
+    fn psbt_bump_fee(&self, txid: &Txid, fee_rate: Amount) -> RpcResult<String> {
+        let bumped: PsbtBumpFeeResult = self.call(
+            "psbtbumpfee",
+            &[
+                txid.to_string().into(),
+                json!({ "fee_rate": fee_rate.to_sat() }),
+            ],
+        )?;
+        Ok(bumped.psbt)
+    }
+
+    fn get_blockchain_info(&self) -> RpcResult<serde_json::Value> {
+        self.call("getblockchaininfo", &[])
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod mock {
+    //! A node answering from canned data, so the flows can be tested
+    //! without bitcoind.
+
+    use super::{BitcoinRpc, RpcResult, RPC_INVALID_ADDRESS_OR_KEY};
+    use crate::config::Config;
+    use crate::MarketplaceContext;
+    use bitcoin::absolute::LockTime;
+    use bitcoin::hashes::Hash;
+    use bitcoin::psbt::Psbt;
+    use bitcoin::secp256k1::{Secp256k1, SecretKey};
+    use bitcoin::{
+        consensus, Address, Amount, BlockHash, Network, OutPoint, PrivateKey, ScriptBuf, Sequence,
+        Transaction, TxIn, TxOut, Txid, Witness,
+    };
+    use bitcoincore_rpc::json::{
+        EstimateMode, EstimateSmartFeeResult, FinalizePsbtResult,
+        GetRawTransactionResultVoutScriptPubKey, GetTransactionResult, GetTxOutResult,
+        ListUnspentQueryOptions, ListUnspentResultEntry, SigHashType, TestMempoolAcceptResult,
+        WalletProcessPsbtResult,
+    };
+    use bitcoincore_rpc::jsonrpc;
+    use std::collections::{HashMap, HashSet};
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex, MutexGuard};
+
+    pub(crate) const NETWORK: Network = Network::Regtest;
+
+    /// What a [`MockRpc`] answers from and what it was asked.
+    #[derive(Debug)]
+    pub(crate) struct MockState {
+        /// Transactions known to `getrawtransaction` and `gettxout`.
+        pub txs: HashMap<Txid, Transaction>,
+        /// Confirmations of `txs`, 0 when missing.
+        pub confirmations: HashMap<Txid, u32>,
+        /// Outputs of `txs` that `gettxout` reports spent.
+        pub spent: HashSet<OutPoint>,
+        /// The wallet's UTXOs, filtered by `listunspent`'s arguments.
+        pub unspent: Vec<ListUnspentResultEntry>,
+        /// Scripts the wallet owns, those of every UTXO it was given.
+        pub scripts: HashSet<ScriptBuf>,
+        /// `getbalance`'s answer, by default the confirmed UTXOs' total.
+        pub balance: Option<Amount>,
+        /// `estimatesmartfee`'s rate per kvB for each target, falling back
+        /// to `fee_rate`.
+        pub fee_rates: HashMap<u16, Amount>,
+        pub fee_rate: Option<Amount>,
+        /// `getaddressinfo`'s answers by address, `{}` for others.
+        pub address_info: HashMap<String, serde_json::Value>,
+        /// `testmempoolaccept` rejects everything for this reason if set.
+        pub reject_reason: Option<String>,
+        /// What `getblockchaininfo` reports, failing when `None`.
+        pub chain: Option<String>,
+        /// PSBTs passed to `walletprocesspsbt`, as given.
+        pub processed: Vec<Psbt>,
+        /// Transactions passed to `sendrawtransaction`.
+        pub sent: Vec<Transaction>,
+        /// How often each method was called.
+        pub calls: HashMap<&'static str, usize>,
+        /// How many more calls of each method fail with a transport error.
+        pub failures: HashMap<&'static str, usize>,
+    }
+
+    impl Default for MockState {
+        fn default() -> Self {
+            MockState {
+                txs: HashMap::new(),
+                confirmations: HashMap::new(),
+                spent: HashSet::new(),
+                unspent: Vec::new(),
+                scripts: HashSet::new(),
+                balance: None,
+                fee_rates: HashMap::new(),
+                // 1 sat/vB
+                fee_rate: Some(Amount::from_sat(1000)),
+                address_info: HashMap::new(),
+                reject_reason: None,
+                chain: Some("regtest".to_string()),
+                processed: Vec::new(),
+                sent: Vec::new(),
+                calls: HashMap::new(),
+                failures: HashMap::new(),
+            }
+        }
+    }
+
+    /// A [`BitcoinRpc`] answering from a [`MockState`]. Clones share the
+    /// state, so a test can keep one to inspect after handing another to a
+    /// context.
+    #[derive(Debug, Clone, Default)]
+    pub(crate) struct MockRpc(Arc<Mutex<MockState>>);
+
+    impl MockRpc {
+        pub(crate) fn state(&self) -> MutexGuard<'_, MockState> {
+            self.0.lock().unwrap()
+        }
+
+        pub(crate) fn calls(&self, method: &str) -> usize {
+            self.state().calls.get(method).copied().unwrap_or(0)
+        }
+
+        /// Makes `tx` known with `confirmations`.
+        pub(crate) fn add_tx(&self, tx: &Transaction, confirmations: u32) {
+            let mut state = self.state();
+            state.txs.insert(tx.txid(), tx.clone());
+            state.confirmations.insert(tx.txid(), confirmations);
+        }
+
+        /// Makes output `vout` of `tx` one of the wallet's UTXOs.
+        pub(crate) fn add_unspent(&self, tx: &Transaction, vout: u32, confirmations: u32) {
+            self.add_tx(tx, confirmations);
+            let mut state = self.state();
+            let mut utxo = unspent_entry(tx, vout, confirmations);
+            // only the wallet's own unconfirmed outputs are safe to spend
+            utxo.safe = confirmations > 0;
+            state.scripts.insert(utxo.script_pub_key.clone());
+            state.unspent.push(utxo);
+        }
+
+        /// Counts a call of `method`, failing it if asked to.
+        fn call(&self, method: &'static str) -> RpcResult<()> {
+            let mut state = self.state();
+            *state.calls.entry(method).or_default() += 1;
+            if let Some(failures) = state.failures.get_mut(method).filter(|left| **left > 0) {
+                *failures -= 1;
+                return Err(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Transport(
+                    "connection refused".into(),
+                )));
+            }
+            Ok(())
+        }
+    }
+
+    /// Output `vout` of `tx` as `listunspent` lists it.
+    pub(crate) fn unspent_entry(
+        tx: &Transaction,
+        vout: u32,
+        confirmations: u32,
+    ) -> ListUnspentResultEntry {
+        let output = &tx.output[vout as usize];
+        ListUnspentResultEntry {
+            txid: tx.txid(),
+            vout,
+            address: Address::from_script(&output.script_pubkey, NETWORK)
+                .ok()
+                .map(|address| address.to_string().parse().expect("valid address")),
+            label: None,
+            redeem_script: None,
+            witness_script: None,
+            script_pub_key: output.script_pubkey.clone(),
+            amount: Amount::from_sat(output.value),
+            confirmations,
+            spendable: true,
+            solvable: true,
+            descriptor: None,
+            safe: true,
+        }
+    }
+
+    /// The error bitcoind answers with for an unknown transaction.
+    pub(crate) fn not_found() -> bitcoincore_rpc::Error {
+        bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(jsonrpc::error::RpcError {
+            code: RPC_INVALID_ADDRESS_OR_KEY,
+            message: "No such mempool or blockchain transaction".to_string(),
+            data: None,
+        }))
+    }
+
+    impl BitcoinRpc for MockRpc {
+        fn get_raw_transaction(
+            &self,
+            txid: &Txid,
+            _block_hash: Option<&BlockHash>,
+        ) -> RpcResult<Transaction> {
+            self.call("getrawtransaction")?;
+            let state = self.state();
+            state.txs.get(txid).cloned().ok_or_else(not_found)
+        }
+
+        fn get_tx_out(
+            &self,
+            txid: &Txid,
+            vout: u32,
+            _include_mempool: Option<bool>,
+        ) -> RpcResult<Option<GetTxOutResult>> {
+            self.call("gettxout")?;
+            let state = self.state();
+            if state.spent.contains(&OutPoint::new(*txid, vout)) {
+                return Ok(None);
+            }
+            let Some(output) = state
+                .txs
+                .get(txid)
+                .and_then(|tx| tx.output.get(vout as usize))
+            else {
+                return Ok(None);
+            };
+            Ok(Some(GetTxOutResult {
+                bestblock: BlockHash::all_zeros(),
+                confirmations: state.confirmations.get(txid).copied().unwrap_or(0),
+                value: Amount::from_sat(output.value),
+                script_pub_key: GetRawTransactionResultVoutScriptPubKey {
+                    asm: String::new(),
+                    hex: output.script_pubkey.to_bytes(),
+                    req_sigs: None,
+                    type_: None,
+                    addresses: Vec::new(),
+                    address: None,
+                },
+                coinbase: false,
+            }))
+        }
+
+        fn list_unspent(
+            &self,
+            minconf: Option<usize>,
+            maxconf: Option<usize>,
+            addresses: Option<&[&Address]>,
+            include_unsafe: Option<bool>,
+            _query_options: Option<ListUnspentQueryOptions>,
+        ) -> RpcResult<Vec<ListUnspentResultEntry>> {
+            self.call("listunspent")?;
+            let state = self.state();
+            let minconf = minconf.unwrap_or(1);
+            let maxconf = maxconf.unwrap_or(usize::MAX);
+            Ok(state
+                .unspent
+                .iter()
+                .filter(|utxo| (minconf..=maxconf).contains(&(utxo.confirmations as usize)))
+                .filter(|utxo| {
+                    addresses.is_none_or(|addresses| {
+                        addresses
+                            .iter()
+                            .any(|address| address.script_pubkey() == utxo.script_pub_key)
+                    })
+                })
+                .filter(|utxo| utxo.safe || include_unsafe.unwrap_or(true))
+                .cloned()
+                .collect())
+        }
+
+        fn get_balance(
+            &self,
+            minconf: Option<usize>,
+            _include_watchonly: Option<bool>,
+        ) -> RpcResult<Amount> {
+            self.call("getbalance")?;
+            let state = self.state();
+            let minconf = minconf.unwrap_or(0) as u32;
+            Ok(state.balance.unwrap_or_else(|| {
+                state
+                    .unspent
+                    .iter()
+                    .filter(|utxo| utxo.confirmations >= minconf)
+                    .map(|utxo| utxo.amount)
+                    .sum()
+            }))
+        }
+
+        /// Unless told not to sign, finalizes the inputs spending the
+        /// wallet's UTXOs with a placeholder witness of a P2WPKH signature's
+        /// size, leaving the others alone.
+        fn wallet_process_psbt(
+            &self,
+            psbt: &str,
+            sign: Option<bool>,
+            _sighash_type: Option<SigHashType>,
+            _bip32derivs: Option<bool>,
+        ) -> RpcResult<WalletProcessPsbtResult> {
+            self.call("walletprocesspsbt")?;
+            let mut state = self.state();
+            let mut psbt = Psbt::from_str(psbt).expect("valid psbt");
+            state.processed.push(psbt.clone());
+            if sign.unwrap_or(true) {
+                for (txin, input) in psbt.unsigned_tx.input.iter().zip(&mut psbt.inputs) {
+                    let outpoint = txin.previous_output;
+                    if state
+                        .unspent
+                        .iter()
+                        .any(|utxo| OutPoint::new(utxo.txid, utxo.vout) == outpoint)
+                    {
+                        input.final_script_witness =
+                            Some(Witness::from_slice(&[vec![0; 72], vec![0; 33]]));
+                    }
+                }
+            }
+            let complete = psbt.inputs.iter().all(|input| {
+                input.final_script_sig.is_some() || input.final_script_witness.is_some()
+            });
+            Ok(WalletProcessPsbtResult {
+                psbt: psbt.to_string(),
+                complete,
+            })
+        }
+
+        fn finalize_psbt(
+            &self,
+            psbt: &str,
+            extract: Option<bool>,
+        ) -> RpcResult<FinalizePsbtResult> {
+            self.call("finalizepsbt")?;
+            let parsed = Psbt::from_str(psbt).expect("valid psbt");
+            let complete = parsed.inputs.iter().all(|input| {
+                input.final_script_sig.is_some() || input.final_script_witness.is_some()
+            });
+            let hex = (complete && extract != Some(false))
+                .then(|| consensus::serialize(&parsed.extract_tx()));
+            Ok(FinalizePsbtResult {
+                psbt: Some(psbt.to_string()),
+                hex,
+                complete,
+            })
+        }
+
+        fn send_raw_transaction(&self, tx: &[u8]) -> RpcResult<Txid> {
+            self.call("sendrawtransaction")?;
+            let mut state = self.state();
+            let tx: Transaction = consensus::deserialize(tx).expect("valid transaction");
+            let txid = tx.txid();
+            // the wallet stops listing what the transaction spends and
+            // lists what it pays the wallet's scripts, unconfirmed but safe
+            for input in &tx.input {
+                state.spent.insert(input.previous_output);
+            }
+            let spent = state.spent.clone();
+            state
+                .unspent
+                .retain(|utxo| !spent.contains(&OutPoint::new(utxo.txid, utxo.vout)));
+            for (vout, output) in tx.output.iter().enumerate() {
+                if state.scripts.contains(&output.script_pubkey) {
+                    state.unspent.push(unspent_entry(&tx, vout as u32, 0));
+                }
+            }
+            state.txs.insert(txid, tx.clone());
+            state.confirmations.insert(txid, 0);
+            state.sent.push(tx);
+            Ok(txid)
+        }
+
+        fn test_mempool_accept(&self, rawtxs: &[&[u8]]) -> RpcResult<Vec<TestMempoolAcceptResult>> {
+            self.call("testmempoolaccept")?;
+            let state = self.state();
+            Ok(rawtxs
+                .iter()
+                .map(|raw| {
+                    let tx: Transaction = consensus::deserialize(raw).expect("valid transaction");
+                    TestMempoolAcceptResult {
+                        txid: tx.txid(),
+                        allowed: state.reject_reason.is_none(),
+                        reject_reason: state.reject_reason.clone(),
+                        vsize: None,
+                        fees: None,
+                    }
+                })
+                .collect())
+        }
+
+        fn estimate_smart_fee(
+            &self,
+            conf_target: u16,
+            _estimate_mode: Option<EstimateMode>,
+        ) -> RpcResult<EstimateSmartFeeResult> {
+            self.call("estimatesmartfee")?;
+            let state = self.state();
+            let fee_rate = state
+                .fee_rates
+                .get(&conf_target)
+                .copied()
+                .or(state.fee_rate);
+            Ok(EstimateSmartFeeResult {
+                fee_rate,
+                errors: fee_rate
+                    .is_none()
+                    .then(|| vec!["Insufficient data".to_string()]),
+                blocks: conf_target.into(),
+            })
+        }
+
+        fn get_address_info(&self, address: &Address) -> RpcResult<serde_json::Value> {
+            self.call("getaddressinfo")?;
+            let state = self.state();
+            Ok(state
+                .address_info
+                .get(&address.to_string())
+                .cloned()
+                .unwrap_or_else(|| serde_json::json!({})))
+        }
+
+        fn get_transaction(
+            &self,
+            _txid: &Txid,
+            _include_watchonly: Option<bool>,
+        ) -> RpcResult<GetTransactionResult> {
+            self.call("gettransaction")?;
+            Err(not_found())
+        }
+
+        fn psbt_bump_fee(&self, _txid: &Txid, _fee_rate: Amount) -> RpcResult<String> {
+            self.call("psbtbumpfee")?;
+            Err(not_found())
+        }
+
+        fn get_blockchain_info(&self) -> RpcResult<serde_json::Value> {
+            self.call("getblockchaininfo")?;
+            let state = self.state();
+            match &state.chain {
+                Some(chain) => Ok(serde_json::json!({ "chain": chain })),
+                None => Err(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Transport(
+                    "connection refused".into(),
+                ))),
+            }
+        }
+    }
+
+    /// A deterministic key, distinct for every `seed`.
+    pub(crate) fn key(seed: u8) -> PrivateKey {
+        PrivateKey::new(
+            SecretKey::from_slice(&[seed.max(1); 32]).expect("valid key"),
+            NETWORK,
+        )
+    }
+
+    pub(crate) fn p2wpkh(seed: u8) -> Address {
+        let secp = Secp256k1::new();
+        Address::p2wpkh(&key(seed).public_key(&secp), NETWORK).expect("compressed key")
+    }
+
+    pub(crate) fn p2tr(seed: u8) -> Address {
+        let secp = Secp256k1::new();
+        let (internal_key, _) = key(seed).inner.x_only_public_key(&secp);
+        Address::p2tr(&secp, internal_key, None, NETWORK)
+    }
+
+    /// A transaction paying `outputs`, spending an outpoint made up from
+    /// `seed` so transactions with different seeds get different txids.
+    pub(crate) fn funding_tx(seed: u32, outputs: &[(u64, &Address)]) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::all_zeros(), seed),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::default(),
+            }],
+            output: outputs
+                .iter()
+                .map(|(value, address)| TxOut {
+                    value: *value,
+                    script_pubkey: address.script_pubkey(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Seller, buyer and marketplace at distinct regtest addresses, with the
+    /// default flat service fee.
+    pub(crate) fn config() -> Config {
+        Config::new(
+            NETWORK,
+            p2wpkh(1),
+            p2wpkh(2),
+            p2wpkh(3),
+            "http://127.0.0.1:1/".parse().expect("valid url"),
+        )
+    }
+
+    /// Where the seller's inscription sits and what it is worth.
+    pub(crate) const INSCRIPTION_VALUE: u64 = 10_000;
+    pub(crate) const PRICE: Amount = Amount::from_sat(50_000);
+
+    /// A seller holding one confirmed inscription at [`p2wpkh`]`(1)`, signing
+    /// with [`key`]`(1)`, and a buyer at [`p2wpkh`]`(2)` with a confirmed
+    /// dummy and one confirmed 100 000 sat UTXO, all served by mock nodes.
+    /// Only the inscription is one as far as the context's index knows.
+    pub(crate) struct Market {
+        pub ctx: MarketplaceContext,
+        pub full: MockRpc,
+        pub seller: MockRpc,
+        pub buyer: MockRpc,
+        pub inscription_utxo: OutPoint,
+        pub dummy_utxo: OutPoint,
+        /// Outputs the context's index reports as inscriptions.
+        pub inscriptions: Arc<Mutex<HashSet<OutPoint>>>,
+    }
+
+    impl Market {
+        pub(crate) fn new() -> Self {
+            Market::with_config(config())
+        }
+
+        pub(crate) fn with_config(config: Config) -> Self {
+            let (full, seller, buyer) =
+                (MockRpc::default(), MockRpc::default(), MockRpc::default());
+            let mut ctx =
+                MarketplaceContext::new(full.clone(), seller.clone(), buyer.clone(), config);
+            ctx.seller_key = Some(key(1));
+            let inscriptions = Arc::new(Mutex::new(HashSet::new()));
+            let index = Arc::clone(&inscriptions);
+            ctx.inscription_index =
+                Box::new(move |outpoint: OutPoint| Ok(index.lock().unwrap().contains(&outpoint)));
+
+            let mut market = Market {
+                ctx,
+                full,
+                seller,
+                buyer,
+                inscription_utxo: OutPoint::null(),
+                dummy_utxo: OutPoint::null(),
+                inscriptions,
+            };
+            market.inscription_utxo = market.add_inscription(1, 6);
+            market.dummy_utxo = market.fund_buyer(2, market.ctx.dummy_value.to_sat(), 6);
+            market.fund_buyer(3, 100_000, 6);
+            market
+        }
+
+        /// Gives the seller another inscription of [`INSCRIPTION_VALUE`],
+        /// returning its outpoint.
+        pub(crate) fn add_inscription(&self, seed: u32, confirmations: u32) -> OutPoint {
+            let tx = funding_tx(
+                seed,
+                &[(INSCRIPTION_VALUE, &self.ctx.config.seller_address)],
+            );
+            self.full.add_tx(&tx, confirmations);
+            self.seller.add_unspent(&tx, 0, confirmations);
+            let outpoint = OutPoint::new(tx.txid(), 0);
+            self.inscriptions.lock().unwrap().insert(outpoint);
+            outpoint
+        }
+
+        /// Gives the buyer a UTXO of `value` at its address, returning it.
+        pub(crate) fn fund_buyer(&self, seed: u32, value: u64, confirmations: u32) -> OutPoint {
+            let tx = funding_tx(seed, &[(value, &self.ctx.config.buyer_address)]);
+            self.full.add_tx(&tx, confirmations);
+            self.buyer.add_unspent(&tx, 0, confirmations);
+            OutPoint::new(tx.txid(), 0)
+        }
+
+        /// Takes away every buyer UTXO but the dummies.
+        pub(crate) fn clear_payment_utxos(&self) {
+            let dummy_value = self.ctx.dummy_value;
+            self.buyer
+                .state()
+                .unspent
+                .retain(|utxo| utxo.amount == dummy_value);
+        }
+
+        /// Lists the inscription for [`PRICE`] with the default sighash.
+        pub(crate) fn list(&self) -> (Psbt, TxOut) {
+            crate::seller::create_seller_psbt(
+                &self.ctx,
+                self.inscription_utxo,
+                PRICE,
+                crate::seller::DEFAULT_SELLER_SIGHASH,
+                None,
+                None,
+            )
+            .expect("listing")
+        }
+
+        /// Buys `listing` with the default options.
+        pub(crate) fn buy(&self, listing: (Psbt, TxOut)) -> crate::Result<Psbt> {
+            self.buy_with(listing, crate::buyer::ChangePolicy::default())
+        }
+
+        pub(crate) fn buy_with(
+            &self,
+            (seller_psbt, inscription_output): (Psbt, TxOut),
+            change_policy: crate::buyer::ChangePolicy,
+        ) -> crate::Result<Psbt> {
+            crate::buyer::create_buyer_psbt(
+                &self.ctx,
+                seller_psbt,
+                self.inscription_utxo,
+                inscription_output,
+                PRICE,
+                None,
+                false,
+                change_policy,
+                crate::buyer::SortPolicy::default(),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::{funding_tx, p2wpkh, Market, MockRpc};
+    use super::*;
+    use bitcoincore_rpc::Auth;
+
+    #[test]
+    fn test_accept_surfaces_the_rejection_reason() {
+        let node = MockRpc::default();
+        let tx = funding_tx(10, &[(10_000, &p2wpkh(1))]);
+        assert!(test_accept(&node, &tx).unwrap().allowed);
+
+        node.state().reject_reason = Some("min relay fee not met".to_string());
+        match test_accept(&node, &tx) {
+            Err(Error::MempoolRejected(txid, reason)) => {
+                assert_eq!(txid, tx.txid());
+                assert_eq!(reason, "min relay fee not met");
+            }
+            other => panic!("expected a rejection, got {:?}", other),
+        }
+        assert!(node.state().sent.is_empty());
+    }
+
+    #[test]
+    fn unreachable_node_is_named() {
+        let mut market = Market::new();
+        ping_nodes(&market.ctx).unwrap();
+
+        // nothing listens on port 1
+        let auth = Auth::UserPass("user".to_string(), "pass".to_string());
+        market.ctx.seller_node = Box::new(Client::new("http://127.0.0.1:1", auth).unwrap());
+        assert!(matches!(
+            ping_nodes(&market.ctx),
+            Err(Error::NodeUnreachable {
+                which: "seller",
+                ..
+            })
+        ));
+
+        // a node failing at the transport level is unreachable too
+        market.ctx.seller_node = Box::new(market.seller.clone());
+        market.buyer.state().chain = None;
+        assert!(matches!(
+            ping_nodes(&market.ctx),
+            Err(Error::NodeUnreachable { which: "buyer", .. })
+        ));
+    }
+
+    #[test]
+    fn node_on_another_chain_is_a_network_mismatch() {
+        let market = Market::new();
+        market.full.state().chain = Some("main".to_string());
+        match ping_nodes(&market.ctx) {
+            Err(Error::NetworkMismatch {
+                node,
+                expected,
+                actual,
+            }) => {
+                assert_eq!(node, "full");
+                assert_eq!(expected, Network::Regtest);
+                assert_eq!(actual, "main");
+            }
+            other => panic!("expected a network mismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn transaction_in_the_mempool_is_already_broadcast() {
+        let node = MockRpc::default();
+        let tx = funding_tx(10, &[(10_000, &p2wpkh(1))]);
+        assert!(!already_broadcast(&node, tx.txid()).unwrap());
+
+        node.add_tx(&tx, 0);
+        assert!(already_broadcast(&node, tx.txid()).unwrap());
+
+        // only "not found" means it wasn't broadcast
+        node.state().failures.insert("getrawtransaction", 1);
+        assert!(already_broadcast(&node, tx.txid()).is_err());
+    }
+}
@@ -0,0 +1,399 @@
+use crate::fee::dust_limit;
+use crate::input::InputKind;
+use crate::utxo::SpendableUtxo;
+use crate::{Error, Result};
+use bitcoin::{Amount, ScriptBuf};
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::str::FromStr;
+
+/// Upper bound on the branches explored before giving up on a changeless
+/// match, as in Bitcoin Core.
+const BNB_MAX_TRIES: usize = 100_000;
+
+/// How payment UTXOs are picked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CoinSelection {
+    /// A changeless match if there is one, else Bitcoin Core's knapsack.
+    #[default]
+    BranchAndBound,
+    /// The largest UTXOs until the target and change are covered, spending
+    /// few inputs.
+    LargestFirst,
+    /// The smallest UTXOs until the target and change are covered,
+    /// consolidating dust along the way.
+    SmallestFirst,
+    /// UTXOs in random order, so selections don't reveal the wallet's shape.
+    Random,
+}
+
+impl FromStr for CoinSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "bnb" => Ok(CoinSelection::BranchAndBound),
+            "largest-first" => Ok(CoinSelection::LargestFirst),
+            "smallest-first" => Ok(CoinSelection::SmallestFirst),
+            "random" => Ok(CoinSelection::Random),
+            _ => Err(format!("unknown coin selection {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for CoinSelection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoinSelection::BranchAndBound => write!(f, "bnb"),
+            CoinSelection::LargestFirst => write!(f, "largest-first"),
+            CoinSelection::SmallestFirst => write!(f, "smallest-first"),
+            CoinSelection::Random => write!(f, "random"),
+        }
+    }
+}
+
+/// Picks payment UTXOs whose value, net of the fee to spend each of them at
+/// `fee_rate` sat/vB, covers `target`, weighing at most `max_weight` weight
+/// units together.
+///
+/// `target` is everything the payment inputs must fund other than their own
+/// fee: price, service fee, new dummy and the fee of the rest of the
+/// transaction. With [`CoinSelection::BranchAndBound`] a search first looks
+/// for a selection that lands within the cost of a change output of
+/// `target`, so no change needs to be created; failing that a knapsack pass
+/// picks a selection that leaves room for change. The other strategies take
+/// UTXOs in their order until target and change are covered. When the funds
+/// are there but only in more inputs than `max_weight` allows, fails with
+/// [`Error::TooManyInputs`] rather than building a transaction nodes won't
+/// relay.
+pub fn select_payment_utxos(
+    utxos: &[SpendableUtxo],
+    target: Amount,
+    fee_rate: Amount,
+    max_weight: usize,
+    strategy: CoinSelection,
+) -> Result<Vec<SpendableUtxo>> {
+    let target = target.to_sat() as i64;
+    let mut candidates = utxos
+        .iter()
+        .map(|utxo| (effective_value(utxo, fee_rate), utxo))
+        .filter(|(value, _)| *value > 0)
+        .collect::<Vec<_>>();
+    candidates.sort_by_key(|(value, _)| -value);
+    let values = candidates
+        .iter()
+        .map(|(value, _)| *value)
+        .collect::<Vec<_>>();
+    let weights = candidates
+        .iter()
+        .map(|(_, utxo)| InputKind::from_script(&utxo.script_pubkey).weight())
+        .collect::<Vec<_>>();
+
+    let change_script = utxos
+        .first()
+        .map(|utxo| utxo.script_pubkey.clone())
+        .unwrap_or_default();
+    let limits = Limits {
+        target,
+        cost_of_change: cost_of_change(&change_script, fee_rate),
+        max_weight,
+    };
+
+    // candidates are sorted largest first
+    let order = match strategy {
+        CoinSelection::BranchAndBound => None,
+        CoinSelection::LargestFirst => Some((0..values.len()).collect::<Vec<_>>()),
+        CoinSelection::SmallestFirst => Some((0..values.len()).rev().collect()),
+        CoinSelection::Random => {
+            let mut order = (0..values.len()).collect::<Vec<_>>();
+            shuffle(&mut order);
+            Some(order)
+        }
+    };
+    let selection = match order {
+        Some(order) => accumulate(&order, &values, &weights, &limits),
+        None => branch_and_bound(&values, &weights, &limits)
+            .or_else(|| knapsack(&values, &weights, &limits)),
+    }
+    .ok_or_else(|| {
+        // what spending every candidate would leave after its input fee
+        let available = values.iter().sum::<i64>();
+        if available >= target {
+            return Error::TooManyInputs(max_weight);
+        }
+        Error::insufficient_funds(
+            Amount::from_sat(target as u64),
+            Amount::from_sat(available as u64),
+        )
+    })?;
+
+    Ok(selection
+        .into_iter()
+        .map(|i| candidates[i].1.clone())
+        .collect())
+}
+
+/// The UTXO's value minus what it costs to spend it.
+fn effective_value(utxo: &SpendableUtxo, fee_rate: Amount) -> i64 {
+    let input_vsize = InputKind::from_script(&utxo.script_pubkey)
+        .weight()
+        .div_ceil(4) as u64;
+    utxo.amount.to_sat() as i64 - (fee_rate.to_sat() * input_vsize) as i64
+}
+
+/// What creating a change output paying `script` and later spending it costs,
+/// below which surplus is better given to the miner.
+fn cost_of_change(script: &ScriptBuf, fee_rate: Amount) -> i64 {
+    let output_vsize = 8 + 1 + script.len() as u64;
+    let spend_vsize = InputKind::from_script(script).weight().div_ceil(4) as u64;
+    (fee_rate.to_sat() * (output_vsize + spend_vsize) + dust_limit(script).to_sat()) as i64
+}
+
+/// What a selection must cover and stay within.
+#[derive(Clone, Copy)]
+struct Limits {
+    target: i64,
+    cost_of_change: i64,
+    max_weight: usize,
+}
+
+/// Depth-first search over include/exclude decisions on `values` (sorted
+/// descending) for a subset summing into `[target, target + cost_of_change]`
+/// whose `weights` stay within `max_weight`, keeping the one that wastes the
+/// least.
+fn branch_and_bound(values: &[i64], weights: &[usize], limits: &Limits) -> Option<Vec<usize>> {
+    let Limits {
+        target,
+        cost_of_change,
+        max_weight,
+    } = *limits;
+    let mut remaining = values.iter().sum::<i64>();
+    if remaining < target {
+        return None;
+    }
+
+    let mut best: Option<(i64, Vec<usize>)> = None;
+    let mut selected: Vec<usize> = Vec::new();
+    let mut selected_value = 0;
+    let mut selected_weight = 0;
+    let mut index = 0;
+
+    for _ in 0..BNB_MAX_TRIES {
+        let backtrack = if selected_value + remaining < target
+            || selected_value > target + cost_of_change
+            || selected_weight > max_weight
+        {
+            true
+        } else if selected_value >= target {
+            let waste = selected_value - target;
+            if best
+                .as_ref()
+                .is_none_or(|(best_waste, _)| waste < *best_waste)
+            {
+                best = Some((waste, selected.clone()));
+                if waste == 0 {
+                    break;
+                }
+            }
+            true
+        } else {
+            false
+        };
+
+        if backtrack {
+            // give back the values skipped since the last inclusion
+            while index > 0 && selected.last() != Some(&(index - 1)) {
+                index -= 1;
+                remaining += values[index];
+            }
+            match selected.pop() {
+                // exclude the last included value and try the next branch
+                Some(last) => {
+                    selected_value -= values[last];
+                    selected_weight -= weights[last];
+                    index = last + 1;
+                }
+                None => break,
+            }
+        } else if index < values.len() {
+            remaining -= values[index];
+            selected.push(index);
+            selected_value += values[index];
+            selected_weight += weights[index];
+            index += 1;
+        } else {
+            break;
+        }
+    }
+
+    best.map(|(_, selection)| selection)
+}
+
+/// Takes `values` in `order`, skipping those that would push the weight
+/// past `max_weight`, until the target and a change output are covered, or
+/// at least the target once they run out.
+fn accumulate(
+    order: &[usize],
+    values: &[i64],
+    weights: &[usize],
+    limits: &Limits,
+) -> Option<Vec<usize>> {
+    let mut selected = Vec::new();
+    let mut selected_value = 0;
+    let mut selected_weight = 0;
+    for &i in order {
+        if selected_weight + weights[i] > limits.max_weight {
+            continue;
+        }
+        selected.push(i);
+        selected_value += values[i];
+        selected_weight += weights[i];
+        if selected_value >= limits.target + limits.cost_of_change {
+            break;
+        }
+    }
+    (selected_value >= limits.target).then_some(selected)
+}
+
+/// Shuffles `order` with xorshift seeded from the standard library's random
+/// hasher keys, unpredictable enough for picking coins.
+fn shuffle(order: &mut [usize]) {
+    let mut state = RandomState::new().build_hasher().finish() | 1;
+    for i in (1..order.len()).rev() {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        order.swap(i, (state % (i as u64 + 1)) as usize);
+    }
+}
+
+/// Approximates Bitcoin Core's knapsack: take the smallest single UTXO that
+/// covers the target with room for change, unless combining the smaller ones,
+/// largest first and only as many as `max_weight` allows, gets closer.
+fn knapsack(values: &[i64], weights: &[usize], limits: &Limits) -> Option<Vec<usize>> {
+    let target = limits.target;
+    let wanted = target + limits.cost_of_change;
+    let lowest_larger = (0..values.len())
+        .rev()
+        .find(|&i| values[i] >= wanted && weights[i] <= limits.max_weight);
+
+    let mut combined = Vec::new();
+    let mut combined_value = 0;
+    let mut combined_weight = 0;
+    for (i, value) in values.iter().enumerate() {
+        if *value >= wanted {
+            continue;
+        }
+        if combined_weight + weights[i] > limits.max_weight {
+            break;
+        }
+        combined.push(i);
+        combined_value += value;
+        combined_weight += weights[i];
+        if combined_value >= wanted {
+            break;
+        }
+    }
+
+    match lowest_larger {
+        Some(i) if combined_value < wanted || values[i] <= combined_value => Some(vec![i]),
+        _ if combined_value >= target => Some(combined),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::mock::p2wpkh;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{OutPoint, Txid};
+
+    /// What a P2WPKH input costs at 1 sat/vB.
+    const INPUT_FEE: u64 = 68;
+
+    fn utxos(values: &[u64]) -> Vec<SpendableUtxo> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(vout, &value)| SpendableUtxo {
+                outpoint: OutPoint::new(Txid::all_zeros(), vout as u32),
+                amount: Amount::from_sat(value),
+                script_pubkey: p2wpkh(2).script_pubkey(),
+                address: None,
+                confirmations: 6,
+                is_inscription: false,
+            })
+            .collect()
+    }
+
+    fn amounts(selection: &[SpendableUtxo]) -> Vec<u64> {
+        let mut amounts = selection
+            .iter()
+            .map(|utxo| utxo.amount.to_sat())
+            .collect::<Vec<_>>();
+        amounts.sort();
+        amounts
+    }
+
+    #[test]
+    fn branch_and_bound_prefers_a_changeless_match() {
+        let utxos = utxos(&[100_000, 30_000 + INPUT_FEE, 20_000 + INPUT_FEE]);
+        let selection = select_payment_utxos(
+            &utxos,
+            Amount::from_sat(50_000),
+            Amount::from_sat(1),
+            usize::MAX,
+            CoinSelection::BranchAndBound,
+        )
+        .unwrap();
+        assert_eq!(
+            amounts(&selection),
+            [20_000 + INPUT_FEE, 30_000 + INPUT_FEE]
+        );
+    }
+
+    #[test]
+    fn branch_and_bound_falls_back_to_knapsack() {
+        let utxos = utxos(&[100_000, 30_000, 25_000]);
+        let selection = select_payment_utxos(
+            &utxos,
+            Amount::from_sat(60_000),
+            Amount::from_sat(1),
+            usize::MAX,
+            CoinSelection::BranchAndBound,
+        )
+        .unwrap();
+        assert_eq!(amounts(&selection), [100_000]);
+    }
+
+    #[test]
+    fn ordered_strategies_take_utxos_in_their_order() {
+        let utxos = utxos(&[40_000, 10_000, 80_000, 20_000]);
+        let select = |strategy| {
+            select_payment_utxos(
+                &utxos,
+                Amount::from_sat(50_000),
+                Amount::from_sat(1),
+                usize::MAX,
+                strategy,
+            )
+            .unwrap()
+        };
+
+        assert_eq!(amounts(&select(CoinSelection::LargestFirst)), [80_000]);
+        assert_eq!(
+            amounts(&select(CoinSelection::SmallestFirst)),
+            [10_000, 20_000, 40_000]
+        );
+        for _ in 0..20 {
+            let selection = select(CoinSelection::Random);
+            let net = selection
+                .iter()
+                .map(|utxo| utxo.amount.to_sat() - INPUT_FEE)
+                .sum::<u64>();
+            assert!(net >= 50_000);
+        }
+    }
+}
@@ -0,0 +1,1154 @@
+use crate::config::Config;
+use crate::fee::{
+    check_dust, checked_sum, dust_limit, estimate_fee_rate, estimate_vsize, fee_for_vsize,
+    marketplace_fee, seller_payout, service_fee_outputs, FeePayer,
+};
+use crate::finalize::finalize;
+use crate::input::{set_input_utxo, set_taproot_key, InputKind};
+use crate::signer::process_psbt;
+use crate::utxo::utxo_address;
+use crate::{Error, MarketplaceContext, Result};
+use bitcoin::absolute::LockTime;
+use bitcoin::consensus;
+use bitcoin::key::{KeyPair, TapTweak};
+use bitcoin::psbt::{Psbt, PsbtSighashType};
+use bitcoin::secp256k1::{Message, Secp256k1};
+use bitcoin::sighash::{EcdsaSighashType, Prevouts, SighashCache, TapSighashType};
+use bitcoin::{
+    ecdsa, taproot, Address, Amount, OutPoint, PrivateKey, ScriptBuf, Sequence, Transaction, TxIn,
+    TxOut, Txid, Witness,
+};
+use bitcoincore_rpc::json::{ListUnspentResultEntry, SigHashType};
+use std::fmt;
+
+/// The sighash type sellers sign listings with unless told otherwise.
+pub const DEFAULT_SELLER_SIGHASH: EcdsaSighashType = EcdsaSighashType::SinglePlusAnyoneCanPay;
+
+/// A creator royalty taken out of a listing's price.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Royalty {
+    pub recipient: Address,
+    pub amount: Amount,
+}
+
+impl Royalty {
+    /// `basis_points` of `price`, in hundredths of a percent, to `recipient`.
+    pub fn from_basis_points(recipient: Address, price: Amount, basis_points: u16) -> Self {
+        Royalty {
+            recipient,
+            amount: marketplace_fee(price, basis_points),
+        }
+    }
+}
+
+/// What a sale leaves the seller, see [`seller_proceeds`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SellerProceeds {
+    /// The listing price.
+    pub price: Amount,
+    /// The marketplace fee on the sale.
+    pub marketplace_fee: Amount,
+    /// Whether the buyer pays `marketplace_fee` on top of `price`, leaving
+    /// the seller the full price, or the seller out of it.
+    pub fee_payer: FeePayer,
+    /// The creator royalty, which comes out of `price`.
+    pub royalty: Amount,
+    /// What reaches the seller: `price` less the `royalty` and, when the
+    /// seller pays it, the `marketplace_fee`.
+    pub net: Amount,
+}
+
+impl fmt::Display for SellerProceeds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "price: {}", self.price)?;
+        writeln!(f, "royalty: {}", self.royalty)?;
+        writeln!(f, "net to seller: {}", self.net)?;
+        write!(
+            f,
+            "marketplace fee, paid by the {}: {}",
+            self.fee_payer, self.marketplace_fee
+        )
+    }
+}
+
+/// Works out what selling at `price` leaves the seller under the marketplace
+/// fee settings of `config`. A `royalty` is always taken out of the price,
+/// the marketplace fee only when the config's `fee_payer` is the seller.
+/// Fails like the purchase would when the fee outputs are dust or exceed the
+/// price.
+pub fn seller_proceeds(
+    config: &Config,
+    price: Amount,
+    royalty: Option<&Royalty>,
+) -> Result<SellerProceeds> {
+    let marketplace_fee = checked_sum(
+        service_fee_outputs(config, &[price])?
+            .iter()
+            .map(|output| Amount::from_sat(output.value)),
+    )?;
+    let royalty = royalty.map_or(Amount::ZERO, |royalty| royalty.amount);
+    let net = seller_payout(config, price)?
+        .checked_sub(royalty)
+        .ok_or(Error::RoyaltyTooHigh { royalty, price })?;
+    Ok(SellerProceeds {
+        price,
+        marketplace_fee,
+        fee_payer: config.fee_payer,
+        royalty,
+        net,
+    })
+}
+
+/// Seller PSBTs listing several inscriptions, see [`create_seller_psbts`].
+#[derive(Debug, Clone)]
+pub enum SellerPsbts {
+    /// One PSBT per inscription, in the order given, each with the
+    /// inscription output it spends. Every PSBT is combined by the buyer
+    /// exactly like one from [`create_seller_psbt`], so inscriptions can be
+    /// bought independently.
+    Separate(Vec<(Psbt, TxOut)>),
+    /// A single PSBT whose input `i` spends the `i`-th inscription and whose
+    /// output `i` pays its price, plus the inscription outputs in the same
+    /// order. Each pair is signed SINGLE|ANYONECANPAY, committing only to
+    /// itself, so the buyer splits the bundle with
+    /// [`SellerPsbts::into_listings`] and combines the pairs like separate
+    /// listings.
+    Bundle(Psbt, Vec<TxOut>),
+}
+
+impl SellerPsbts {
+    /// The listings one per inscription, as
+    /// [`create_buyer_psbt_multi`](crate::buyer::create_buyer_psbt_multi)
+    /// takes them. A bundle's pairs become single-pair PSBTs carrying the
+    /// input and output data of their pair; their signatures stay valid
+    /// because they commit to nothing else.
+    pub fn into_listings(self) -> Result<Vec<(Psbt, TxOut)>> {
+        let (psbt, inscription_outputs) = match self {
+            SellerPsbts::Separate(listings) => return Ok(listings),
+            SellerPsbts::Bundle(psbt, inscription_outputs) => (psbt, inscription_outputs),
+        };
+        let tx = &psbt.unsigned_tx;
+        if tx.input.len() != inscription_outputs.len() || tx.output.len() != tx.input.len() {
+            return Err(Error::SellerPsbtMismatch(format!(
+                "bundle of {} inscriptions has {} inputs and {} outputs",
+                inscription_outputs.len(),
+                tx.input.len(),
+                tx.output.len()
+            )));
+        }
+        inscription_outputs
+            .into_iter()
+            .enumerate()
+            .map(|(i, inscription_output)| {
+                let mut listing = Psbt::from_unsigned_tx(Transaction {
+                    version: tx.version,
+                    lock_time: tx.lock_time,
+                    input: vec![tx.input[i].clone()],
+                    output: vec![tx.output[i].clone()],
+                })?;
+                listing.inputs[0] = psbt.inputs[i].clone();
+                listing.outputs[0] = psbt.outputs[i].clone();
+                listing.xpub = psbt.xpub.clone();
+                Ok((listing, inscription_output))
+            })
+            .collect()
+    }
+}
+
+/// Builds a seller PSBT listing the inscription at `inscription_utxo` for `price`,
+/// signed by the context's seller node with `sighash`. Returns the PSBT and
+/// the inscription output it spends.
+///
+/// `sighash` must be ANYONECANPAY so the buyer can add inputs. SINGLE only
+/// commits to the seller's own output; ALL commits to every output, so the
+/// buyer can't add outputs of their own. NONE is rejected because it would
+/// let the buyer redirect the payment and take the inscription for free.
+///
+/// `price` is paid from the buyer's funds while the inscription output's
+/// own value, its postage, goes to the buyer along with the inscription, so
+/// the price must exceed that value by at least the dust limit. When the
+/// config's `fee_payer` is the seller, the marketplace fee is taken out of
+/// the price output, so the limit applies to what is left.
+///
+/// Every sighash type commits to the locktime, so with `lock_time` set the
+/// purchase must carry it too and can't be mined before it. The seller input
+/// then gets a non-final sequence for the locktime to be enforced.
+///
+/// A `royalty` is paid out of `price` by a second output the seller signs
+/// for. SINGLE only commits to the output at the signed input's index, so
+/// the royalty output is paired with a second input, the seller's smallest
+/// spendable UTXO worth at least the royalty, whose value is handed back
+/// through the price output. Input 0 doesn't commit to the royalty pair, so
+/// a smaller one would let the buyer drop the pair and pay less. This
+/// keeps the listing combinable with any buyer inputs and outputs, at the
+/// cost of a seller UTXO locked into the listing and a larger purchase. The
+/// alternative, ALL|ANYONECANPAY over both outputs, would need no extra
+/// input but commit to every output of the purchase, leaving the buyer no
+/// room for the dummy, fee or change outputs, so only SINGLE|ANYONECANPAY
+/// listings can carry a royalty.
+pub fn create_seller_psbt(
+    ctx: &MarketplaceContext,
+    inscription_utxo: OutPoint,
+    price: Amount,
+    sighash: EcdsaSighashType,
+    lock_time: Option<LockTime>,
+    royalty: Option<&Royalty>,
+) -> Result<(Psbt, TxOut)> {
+    let (psbt, mut inscription_outputs) =
+        build_seller_psbt(ctx, &[inscription_utxo], price, sighash, lock_time, royalty)?;
+    Ok((psbt, inscription_outputs.remove(0)))
+}
+
+/// Lists every inscription in `inscription_utxos` for `price` each, either as
+/// one PSBT per inscription or, with `bundle`, as one PSBT holding an
+/// input/output pair per inscription. `sighash` and `lock_time` are handled
+/// as in [`create_seller_psbt`]; a bundle has to be signed
+/// SINGLE|ANYONECANPAY so its pairs can be split apart and combined
+/// independently.
+pub fn create_seller_psbts(
+    ctx: &MarketplaceContext,
+    inscription_utxos: &[OutPoint],
+    price: Amount,
+    sighash: EcdsaSighashType,
+    lock_time: Option<LockTime>,
+    bundle: bool,
+) -> Result<SellerPsbts> {
+    if bundle {
+        if sighash != EcdsaSighashType::SinglePlusAnyoneCanPay {
+            return Err(Error::UnsupportedSighash(
+                sighash,
+                "a bundle's pairs must each commit to their own output only",
+            ));
+        }
+        let (psbt, inscription_outputs) =
+            build_seller_psbt(ctx, inscription_utxos, price, sighash, lock_time, None)?;
+        return Ok(SellerPsbts::Bundle(psbt, inscription_outputs));
+    }
+    inscription_utxos
+        .iter()
+        .map(|&inscription_utxo| {
+            create_seller_psbt(ctx, inscription_utxo, price, sighash, lock_time, None)
+        })
+        .collect::<Result<_>>()
+        .map(SellerPsbts::Separate)
+}
+
+/// Builds and signs a PSBT whose input `i` spends `inscription_utxos[i]` and
+/// whose output `i` pays `price` back to that inscription's script, less the
+/// marketplace fee when the seller pays it, followed by the royalty's
+/// input/output pair if there is one.
+fn build_seller_psbt(
+    ctx: &MarketplaceContext,
+    inscription_utxos: &[OutPoint],
+    price: Amount,
+    sighash: EcdsaSighashType,
+    lock_time: Option<LockTime>,
+    royalty: Option<&Royalty>,
+) -> Result<(Psbt, Vec<TxOut>)> {
+    check_seller_sighash(sighash)?;
+    // what the buyer pays the seller, signed over so the fee model can't
+    // be switched on the seller afterwards
+    let price = seller_payout(&ctx.config, price)?;
+
+    let mut prev_txs = Vec::with_capacity(inscription_utxos.len());
+    let mut inscription_outputs = Vec::with_capacity(inscription_utxos.len());
+    for &inscription_utxo in inscription_utxos {
+        let tx = ctx.full_node_transaction(inscription_utxo.txid)?;
+        let inscription_output = tx
+            .output
+            .get(inscription_utxo.vout as usize)
+            .cloned()
+            .ok_or(Error::MissingOutput(inscription_utxo))?;
+
+        let confirmations = check_unspent(ctx, inscription_utxo, &inscription_output)?;
+        // a parent still in the mempool could be replaced, taking the
+        // inscription elsewhere
+        if confirmations < ctx.seller_min_conf {
+            return Err(Error::InscriptionUnconfirmed {
+                outpoint: inscription_utxo,
+                confirmations,
+                required: ctx.seller_min_conf,
+            });
+        }
+
+        let dust = dust_limit(&inscription_output.script_pubkey);
+        if price < dust {
+            return Err(Error::PriceBelowDust { price, dust });
+        }
+        check_postage(price, &inscription_output)?;
+        prev_txs.push(tx);
+        inscription_outputs.push(inscription_output);
+    }
+
+    let funding = royalty
+        .map(|royalty| royalty_funding(ctx, inscription_utxos, price, sighash, royalty))
+        .transpose()?;
+    // the royalty comes out of the price and the funding UTXO goes back to
+    // the seller with it
+    let payout = match (royalty, &funding) {
+        (Some(royalty), Some((funding_utxo, _))) => price - royalty.amount + funding_utxo.amount,
+        _ => price,
+    };
+
+    let sequence = match lock_time {
+        Some(_) => Sequence::ENABLE_LOCKTIME_NO_RBF,
+        None => Sequence::MAX,
+    };
+    let mut tx_sell = Transaction {
+        version: 2,
+        lock_time: lock_time.unwrap_or(LockTime::ZERO),
+        input: inscription_utxos
+            .iter()
+            .map(|&inscription_utxo| TxIn {
+                previous_output: inscription_utxo,
+                script_sig: ScriptBuf::new(),
+                sequence,
+                witness: Witness::default(),
+            })
+            .collect(),
+        output: inscription_outputs
+            .iter()
+            .map(|inscription_output| TxOut {
+                value: payout.to_sat(),
+                script_pubkey: inscription_output.script_pubkey.clone(),
+            })
+            .collect(),
+    };
+    if let (Some(royalty), Some((funding_utxo, _))) = (royalty, &funding) {
+        tx_sell.input.push(TxIn {
+            previous_output: OutPoint::new(funding_utxo.txid, funding_utxo.vout),
+            script_sig: ScriptBuf::new(),
+            sequence,
+            witness: Witness::default(),
+        });
+        tx_sell.output.push(TxOut {
+            value: royalty.amount.to_sat(),
+            script_pubkey: royalty.recipient.script_pubkey(),
+        });
+    }
+
+    let mut psbt = Psbt::from_unsigned_tx(tx_sell)?;
+
+    for (i, inscription_output) in inscription_outputs.iter().enumerate() {
+        prepare_seller_input(
+            ctx,
+            &mut psbt.inputs[i],
+            &prev_txs[i],
+            inscription_utxos[i].vout,
+            &inscription_output.script_pubkey,
+            sighash,
+        )?;
+    }
+    if let Some((funding_utxo, funding_tx)) = &funding {
+        prepare_seller_input(
+            ctx,
+            &mut psbt.inputs[inscription_utxos.len()],
+            funding_tx,
+            funding_utxo.vout,
+            &funding_utxo.script_pub_key,
+            sighash,
+        )?;
+    }
+
+    let (seller_psbt, complete) = match &ctx.seller_key {
+        Some(key) if !ctx.no_sign => {
+            sign_seller_psbt_with_key(&mut psbt, key, sighash)?;
+            (psbt, true)
+        }
+        _ => {
+            let (psbt, complete) = process_psbt(
+                ctx,
+                ctx.seller_node.as_ref(),
+                &psbt,
+                Some(SigHashType::from(sighash)),
+            )?;
+            if !ctx.no_sign {
+                check_taproot_signatures(&psbt, sighash)?;
+            }
+            (psbt, complete)
+        }
+    };
+    for inscription_utxo in inscription_utxos {
+        log::info!(
+            "built seller psbt inscription={} price={} sighash={} complete={}",
+            inscription_utxo,
+            price.to_sat(),
+            sighash,
+            complete
+        );
+    }
+
+    Ok((seller_psbt, inscription_outputs))
+}
+
+/// Attaches the output `vout` of `prev_tx`, paying `script`, to a seller
+/// input along with what the seller node needs to sign it with `sighash`.
+fn prepare_seller_input(
+    ctx: &MarketplaceContext,
+    psbt_input: &mut bitcoin::psbt::Input,
+    prev_tx: &Transaction,
+    vout: u32,
+    script: &ScriptBuf,
+    sighash: EcdsaSighashType,
+) -> Result<()> {
+    let kind = InputKind::from_script(script);
+    set_input_utxo(psbt_input, prev_tx, vout, kind);
+    // a raw key is signed with directly, the wallet needn't find it
+    if kind == InputKind::P2tr && (ctx.seller_key.is_none() || ctx.no_sign) {
+        set_taproot_key(
+            ctx.seller_node.as_ref(),
+            psbt_input,
+            script,
+            ctx.config.network,
+        )?;
+    }
+    psbt_input.sighash_type = Some(kind.psbt_sighash(sighash)?);
+    Ok(())
+}
+
+/// Signs every input of the seller `psbt` with `key` and `sighash`, without
+/// a node wallet: ECDSA inputs get a partial signature, key-path P2TR inputs
+/// a taproot key signature, both left for the buyer to finalize. Every input
+/// must pay a P2PKH, P2SH-P2WPKH, P2WPKH or P2TR script of `key` and carry
+/// its previous output.
+pub fn sign_seller_psbt_with_key(
+    psbt: &mut Psbt,
+    key: &PrivateKey,
+    sighash: EcdsaSighashType,
+) -> Result<()> {
+    let secp = Secp256k1::new();
+    let pubkey = key.public_key(&secp);
+    let (internal_key, _) = key.inner.x_only_public_key(&secp);
+    let tx = psbt.unsigned_tx.clone();
+    let mut cache = SighashCache::new(&tx);
+    // taproot signatures without ANYONECANPAY commit to every input's prevout
+    let all_prevouts = (0..tx.input.len())
+        .map(|index| psbt.spend_utxo(index).ok().cloned())
+        .collect::<Option<Vec<_>>>();
+
+    for (index, txin) in tx.input.iter().enumerate() {
+        let outpoint = txin.previous_output;
+        let failed = |reason: String| Error::SellerSigning(outpoint, reason);
+        let prevout = psbt
+            .spend_utxo(index)
+            .map_err(|e| failed(format!("cannot find its prevout: {}", e)))?
+            .clone();
+        let script = &prevout.script_pubkey;
+        let kind = InputKind::from_script(script);
+
+        if kind == InputKind::P2tr {
+            if *script != ScriptBuf::new_v1_p2tr(&secp, internal_key, None) {
+                return Err(failed("it pays another key".to_string()));
+            }
+            let hash_ty = TapSighashType::from_consensus_u8(sighash.to_u32() as u8)
+                .map_err(|_| failed(format!("{} has no taproot counterpart", sighash)))?;
+            let prevouts = match hash_ty {
+                TapSighashType::AllPlusAnyoneCanPay
+                | TapSighashType::NonePlusAnyoneCanPay
+                | TapSighashType::SinglePlusAnyoneCanPay => Prevouts::One(index, prevout.clone()),
+                _ => Prevouts::All(all_prevouts.as_deref().ok_or_else(|| {
+                    failed(format!("{} needs the prevouts of every input", sighash))
+                })?),
+            };
+            let msg = cache
+                .taproot_key_spend_signature_hash(index, &prevouts, hash_ty)
+                .map_err(|e| failed(format!("cannot compute its sighash: {}", e)))?;
+            let keypair = KeyPair::from_secret_key(&secp, &key.inner)
+                .tap_tweak(&secp, None)
+                .to_inner();
+            let psbt_input = &mut psbt.inputs[index];
+            psbt_input.tap_internal_key = Some(internal_key);
+            psbt_input.tap_key_sig = Some(taproot::Signature {
+                sig: secp.sign_schnorr_no_aux_rand(&Message::from(msg), &keypair),
+                hash_ty,
+            });
+            continue;
+        }
+
+        let wpkh = pubkey
+            .wpubkey_hash()
+            .ok_or_else(|| failed("the key is uncompressed".to_string()))?;
+        let p2wpkh = ScriptBuf::new_v0_p2wpkh(&wpkh);
+        match kind {
+            InputKind::P2wpkh if *script == p2wpkh => {}
+            InputKind::P2shP2wpkh if *script == ScriptBuf::new_p2sh(&p2wpkh.script_hash()) => {
+                psbt.inputs[index].redeem_script = Some(p2wpkh);
+            }
+            InputKind::P2pkh if *script == ScriptBuf::new_p2pkh(&pubkey.pubkey_hash()) => {}
+            _ => return Err(failed("it pays another key".to_string())),
+        }
+        psbt.inputs[index].sighash_type = Some(PsbtSighashType::from(sighash));
+        let (msg, hash_ty) = psbt
+            .sighash_ecdsa(index, &mut cache)
+            .map_err(|e| failed(format!("cannot compute its sighash: {}", e)))?;
+        psbt.inputs[index].partial_sigs.insert(
+            pubkey,
+            ecdsa::Signature {
+                sig: secp.sign_ecdsa(&msg, &key.inner),
+                hash_ty,
+            },
+        );
+    }
+    Ok(())
+}
+
+/// Fails with [`Error::SellerSigning`] unless every P2TR input of `psbt`, as
+/// signed by a node wallet, carries a key-path signature with the taproot
+/// counterpart of `sighash`. A wallet treating the input as ECDSA would leave
+/// a partial signature the buyer can't finalize.
+fn check_taproot_signatures(psbt: &Psbt, sighash: EcdsaSighashType) -> Result<()> {
+    for (index, txin) in psbt.unsigned_tx.input.iter().enumerate() {
+        let is_taproot = psbt
+            .spend_utxo(index)
+            .is_ok_and(|prevout| prevout.script_pubkey.is_v1_p2tr());
+        if !is_taproot {
+            continue;
+        }
+        let failed = |reason: String| Error::SellerSigning(txin.previous_output, reason);
+        let input = &psbt.inputs[index];
+        let Some(sig) = input.tap_key_sig else {
+            return Err(failed(if input.partial_sigs.is_empty() {
+                "the wallet left its taproot key signature out".to_string()
+            } else {
+                "the wallet signed it with ECDSA instead of a taproot key signature".to_string()
+            }));
+        };
+        if PsbtSighashType::from(sig.hash_ty).to_u32() != sighash.to_u32() {
+            return Err(failed(format!(
+                "the wallet signed it with {} instead of {}",
+                sig.hash_ty, sighash
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Checks `royalty` against the listing and picks the seller's smallest
+/// spendable UTXO without an inscription worth at least the royalty to pair
+/// its output with, returning it with the transaction that created it.
+/// Dropping the pair then never saves the buyer anything, since it hands
+/// them back no more than the royalty they would no longer pay.
+fn royalty_funding(
+    ctx: &MarketplaceContext,
+    inscription_utxos: &[OutPoint],
+    price: Amount,
+    sighash: EcdsaSighashType,
+    royalty: &Royalty,
+) -> Result<(ListUnspentResultEntry, Transaction)> {
+    if sighash != EcdsaSighashType::SinglePlusAnyoneCanPay {
+        return Err(Error::UnsupportedSighash(
+            sighash,
+            "a royalty output needs SINGLE|ANYONECANPAY to be paired with its own input",
+        ));
+    }
+    if royalty.amount >= price {
+        return Err(Error::RoyaltyTooHigh {
+            royalty: royalty.amount,
+            price,
+        });
+    }
+    check_dust(&TxOut {
+        value: royalty.amount.to_sat(),
+        script_pubkey: royalty.recipient.script_pubkey(),
+    })?;
+
+    let funding_utxo = seller_spendable_utxos(ctx, inscription_utxos)?
+        .into_iter()
+        .find(|utxo| utxo.amount >= royalty.amount)
+        .ok_or(Error::NoRoyaltyFunding(royalty.amount))?;
+    let funding_tx = ctx.retry.run(|| {
+        ctx.seller_node
+            .get_raw_transaction(&funding_utxo.txid, None)
+    })?;
+    Ok((funding_utxo, funding_tx))
+}
+
+/// The seller's confirmed UTXOs other than `exclude` that carry no
+/// inscription, smallest first.
+fn seller_spendable_utxos(
+    ctx: &MarketplaceContext,
+    exclude: &[OutPoint],
+) -> Result<Vec<ListUnspentResultEntry>> {
+    let unspent = ctx
+        .retry
+        .run(|| {
+            ctx.seller_node
+                .list_unspent(Some(1), None, None, Some(false), None)
+        })?
+        .into_iter()
+        .filter(|utxo| !exclude.contains(&OutPoint::new(utxo.txid, utxo.vout)))
+        .collect::<Vec<_>>();
+    let inscribed = ctx.inscription_outputs(&unspent)?;
+    let mut spendable = unspent
+        .into_iter()
+        .filter(|utxo| !inscribed.contains(&OutPoint::new(utxo.txid, utxo.vout)))
+        .collect::<Vec<_>>();
+    spendable.sort_by_key(|utxo| utxo.amount);
+    Ok(spendable)
+}
+
+/// Cancels every outstanding listing of the inscription at
+/// `inscription_utxo` by spending it to `to`, usually back to the seller,
+/// which leaves the listings' signatures spending a gone output. The
+/// inscription output keeps its value, so the inscription stays at its
+/// offset; the fee comes from the seller's smallest UTXO that covers it and
+/// non-dust change. Signed like a listing, by the context's seller key or
+/// else the seller wallet. A purchase already in the mempool signals
+/// replace-by-fee through the buyer's inputs, so the cancellation replaces it
+/// if it pays more. Returns the cancelling transaction's txid, recorded
+/// instead of broadcast on a dry run.
+pub fn cancel_listing(
+    ctx: &MarketplaceContext,
+    inscription_utxo: OutPoint,
+    to: &Address,
+) -> Result<Txid> {
+    if ctx.no_sign {
+        return Err(Error::SigningDisabled("cancelling a listing"));
+    }
+    let inscription_tx = ctx.full_node_transaction(inscription_utxo.txid)?;
+    let inscription_output = inscription_tx
+        .output
+        .get(inscription_utxo.vout as usize)
+        .cloned()
+        .ok_or(Error::MissingOutput(inscription_utxo))?;
+    check_unspent(ctx, inscription_utxo, &inscription_output)?;
+
+    let mut cancel_tx = Transaction {
+        version: 2,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: inscription_utxo,
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            witness: Witness::default(),
+        }],
+        output: vec![TxOut {
+            value: inscription_output.value,
+            script_pubkey: to.script_pubkey(),
+        }],
+    };
+    check_dust(&cancel_tx.output[0])?;
+
+    let fee_rate = estimate_fee_rate(ctx.seller_node.as_ref(), &ctx.fee)?;
+    let mut funding = None;
+    let mut largest = Amount::ZERO;
+    for utxo in seller_spendable_utxos(ctx, &[inscription_utxo])? {
+        let change_address = utxo_address(&utxo, ctx.config.network)?;
+        let mut tx = cancel_tx.clone();
+        tx.input.push(TxIn {
+            previous_output: OutPoint::new(utxo.txid, utxo.vout),
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            witness: Witness::default(),
+        });
+        tx.output.push(TxOut {
+            value: 0,
+            script_pubkey: change_address.script_pubkey(),
+        });
+        let input_kinds = [
+            InputKind::from_script(&inscription_output.script_pubkey),
+            InputKind::from_script(&utxo.script_pub_key),
+        ];
+        let fee = fee_for_vsize(fee_rate, estimate_vsize(&tx, &input_kinds))?;
+        let dust = dust_limit(&change_address.script_pubkey());
+        largest = largest.max(utxo.amount);
+        if utxo.amount >= checked_sum([fee, dust])? {
+            tx.output[1].value = (utxo.amount - fee).to_sat();
+            funding = Some((utxo, tx, fee));
+            break;
+        }
+    }
+    let Some((funding_utxo, tx, fee)) = funding else {
+        // what the smallest cancellation would need from its funding input
+        let required = checked_sum([
+            fee_for_vsize(fee_rate, estimate_vsize(&cancel_tx, &[]))?,
+            dust_limit(&to.script_pubkey()),
+        ])?;
+        return Err(Error::insufficient_funds(required, largest));
+    };
+    cancel_tx = tx;
+
+    let funding_tx = ctx.retry.run(|| {
+        ctx.seller_node
+            .get_raw_transaction(&funding_utxo.txid, None)
+    })?;
+    let mut psbt = Psbt::from_unsigned_tx(cancel_tx)?;
+    prepare_seller_input(
+        ctx,
+        &mut psbt.inputs[0],
+        &inscription_tx,
+        inscription_utxo.vout,
+        &inscription_output.script_pubkey,
+        EcdsaSighashType::All,
+    )?;
+    prepare_seller_input(
+        ctx,
+        &mut psbt.inputs[1],
+        &funding_tx,
+        funding_utxo.vout,
+        &funding_utxo.script_pub_key,
+        EcdsaSighashType::All,
+    )?;
+    match &ctx.seller_key {
+        Some(key) => sign_seller_psbt_with_key(&mut psbt, key, EcdsaSighashType::All)?,
+        None => {
+            (psbt, _) = process_psbt(
+                ctx,
+                ctx.seller_node.as_ref(),
+                &psbt,
+                Some(SigHashType::from(EcdsaSighashType::All)),
+            )?;
+        }
+    }
+    let tx = match finalize(&mut psbt) {
+        Ok(tx) => tx,
+        Err(Error::CannotFinalize(_, _)) => {
+            let raw_tx = ctx
+                .seller_node
+                .finalize_psbt(&psbt.to_string(), None)?
+                .hex
+                .ok_or(Error::PsbtNotFinalized)?;
+            consensus::deserialize(&raw_tx)?
+        }
+        Err(e) => return Err(e),
+    };
+
+    let txid = tx.txid();
+    if ctx.dry_run {
+        log::info!(
+            "dry run: not broadcasting cancel tx txid={} hex={}",
+            txid,
+            consensus::encode::serialize_hex(&tx)
+        );
+        ctx.record_simulated(tx);
+        return Ok(txid);
+    }
+    ctx.seller_node
+        .send_raw_transaction(&consensus::serialize(&tx))?;
+    log::info!(
+        "cancelled listing inscription={} txid={} fee={}",
+        inscription_utxo,
+        txid,
+        fee.to_sat()
+    );
+    Ok(txid)
+}
+
+/// Makes sure the seller PSBT can't be mined on its own. Its input is the
+/// inscription output, whose value is the inscription's postage, and its
+/// output pays `price`. With the price at or below the postage, the PSBT
+/// would be a valid transaction anyone could broadcast, paying the seller
+/// back less than they put in and sending the sats past `price`, possibly
+/// the inscribed one, to the fee. The price has to exceed the postage by at
+/// least the dust limit.
+///
+/// The purchase keeps the postage with the inscription: its first output
+/// pays the buyer the dummy plus every inscription output's value, so the
+/// sats of the dummy and of each inscription land there in input order and
+/// the inscribed sat keeps its offset behind the dummy's. The seller output
+/// that follows is paid `price` from the buyer's funds.
+fn check_postage(price: Amount, inscription_output: &TxOut) -> Result<()> {
+    let minimum = checked_sum([
+        Amount::from_sat(inscription_output.value),
+        dust_limit(&inscription_output.script_pubkey),
+    ])?;
+    if price < minimum {
+        return Err(Error::PriceBelowPostage { price, minimum });
+    }
+    Ok(())
+}
+
+/// Makes sure `inscription_utxo` is still unspent, mempool included, and that
+/// the node's UTXO set agrees with `inscription_output`, so a sold or moved
+/// inscription isn't listed again. Returns its confirmations, 0 while in
+/// the mempool.
+fn check_unspent(
+    ctx: &MarketplaceContext,
+    inscription_utxo: OutPoint,
+    inscription_output: &TxOut,
+) -> Result<u32> {
+    let utxo = ctx
+        .retry
+        .run(|| {
+            ctx.full_node
+                .get_tx_out(&inscription_utxo.txid, inscription_utxo.vout, Some(true))
+        })?
+        .ok_or(Error::InscriptionAlreadySpent(inscription_utxo))?;
+    if utxo.value.to_sat() != inscription_output.value
+        || utxo.script_pub_key.hex != inscription_output.script_pubkey.as_bytes()
+    {
+        return Err(Error::UtxoMismatch(inscription_utxo));
+    }
+    Ok(utxo.confirmations)
+}
+
+fn check_seller_sighash(sighash: EcdsaSighashType) -> Result<()> {
+    match sighash {
+        EcdsaSighashType::SinglePlusAnyoneCanPay | EcdsaSighashType::AllPlusAnyoneCanPay => Ok(()),
+        EcdsaSighashType::None | EcdsaSighashType::NonePlusAnyoneCanPay => {
+            Err(Error::UnsupportedSighash(
+                sighash,
+                "it commits to no output, so the buyer could take the inscription without paying",
+            ))
+        }
+        EcdsaSighashType::All | EcdsaSighashType::Single => Err(Error::UnsupportedSighash(
+            sighash,
+            "without ANYONECANPAY the buyer cannot add inputs",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buyer::{create_buyer_psbt_multi, ChangePolicy, SortPolicy};
+    use crate::finalize::finalize;
+    use crate::rpc::mock::{
+        config, funding_tx, key, p2tr, p2wpkh, Market, INSCRIPTION_VALUE, PRICE,
+    };
+    use crate::verify::verify_seller_signature;
+    use bitcoin::key::XOnlyPublicKey;
+
+    #[test]
+    fn bundle_pairs_each_inscription_with_its_payment() {
+        let market = Market::new();
+        let second = market.add_inscription(10, 6);
+        market.fund_buyer(11, 100_000, 6);
+        let inscriptions = [market.inscription_utxo, second];
+        let bundle = create_seller_psbts(
+            &market.ctx,
+            &inscriptions,
+            PRICE,
+            DEFAULT_SELLER_SIGHASH,
+            None,
+            true,
+        )
+        .unwrap();
+
+        let SellerPsbts::Bundle(psbt, _) = &bundle else {
+            panic!("expected a bundle");
+        };
+        let tx = &psbt.unsigned_tx;
+        assert_eq!(tx.input.len(), 2);
+        assert_eq!(tx.output.len(), 2);
+        for (i, inscription_utxo) in inscriptions.iter().enumerate() {
+            assert_eq!(tx.input[i].previous_output, *inscription_utxo);
+            assert_eq!(tx.output[i].value, PRICE.to_sat());
+            assert_eq!(
+                psbt.spend_utxo(i).unwrap().script_pubkey,
+                tx.output[i].script_pubkey
+            );
+        }
+
+        // split into single pairs, the bundle buys like separate listings
+        let listings = bundle.into_listings().unwrap();
+        assert_eq!(listings.len(), 2);
+        let buyer_psbt = create_buyer_psbt_multi(
+            &market.ctx,
+            listings,
+            None,
+            false,
+            ChangePolicy::default(),
+            SortPolicy::default(),
+        )
+        .unwrap();
+        let purchase = &buyer_psbt.unsigned_tx;
+        for (i, inscription_utxo) in inscriptions.iter().enumerate() {
+            assert_eq!(purchase.input[i + 1].previous_output, *inscription_utxo);
+            assert_eq!(purchase.output[i + 1].value, PRICE.to_sat());
+        }
+        assert_eq!(
+            purchase.output[0].value,
+            market.ctx.dummy_value.to_sat() + 2 * INSCRIPTION_VALUE
+        );
+    }
+
+    #[test]
+    fn bundle_needs_single_anyonecanpay() {
+        let market = Market::new();
+        let second = market.add_inscription(10, 6);
+        let result = create_seller_psbts(
+            &market.ctx,
+            &[market.inscription_utxo, second],
+            PRICE,
+            EcdsaSighashType::AllPlusAnyoneCanPay,
+            None,
+            true,
+        );
+        assert!(matches!(result, Err(Error::UnsupportedSighash(_, _))));
+    }
+
+    #[test]
+    fn spent_inscription_is_not_listed() {
+        let market = Market::new();
+        market.full.state().spent.insert(market.inscription_utxo);
+        let result = create_seller_psbt(
+            &market.ctx,
+            market.inscription_utxo,
+            PRICE,
+            DEFAULT_SELLER_SIGHASH,
+            None,
+            None,
+        );
+        assert!(matches!(
+            result,
+            Err(Error::InscriptionAlreadySpent(outpoint)) if outpoint == market.inscription_utxo
+        ));
+    }
+
+    #[test]
+    fn royalty_output_reaches_the_final_transaction() {
+        let market = Market::new();
+        let seller_address = &market.ctx.config.seller_address;
+        market
+            .seller
+            .add_unspent(&funding_tx(20, &[(6_000, seller_address)]), 0, 6);
+        let royalty = Royalty {
+            recipient: p2wpkh(4),
+            amount: Amount::from_sat(5_000),
+        };
+        let listing = create_seller_psbt(
+            &market.ctx,
+            market.inscription_utxo,
+            PRICE,
+            DEFAULT_SELLER_SIGHASH,
+            None,
+            Some(&royalty),
+        )
+        .unwrap();
+
+        let mut buyer_psbt = market.buy(listing).unwrap();
+        let tx = finalize(&mut buyer_psbt).unwrap();
+        assert_eq!(
+            tx.output[2],
+            TxOut {
+                value: royalty.amount.to_sat(),
+                script_pubkey: royalty.recipient.script_pubkey(),
+            }
+        );
+        // the seller gets the price less the royalty, plus the funding back
+        assert_eq!(tx.output[1].value, PRICE.to_sat() - 5_000 + 6_000);
+    }
+
+    #[test]
+    fn royalty_needs_funding_worth_at_least_the_royalty() {
+        let market = Market::new();
+        let seller_address = &market.ctx.config.seller_address;
+        market
+            .seller
+            .add_unspent(&funding_tx(20, &[(4_000, seller_address)]), 0, 6);
+        let royalty = Royalty {
+            recipient: p2wpkh(4),
+            amount: Amount::from_sat(5_000),
+        };
+        let result = create_seller_psbt(
+            &market.ctx,
+            market.inscription_utxo,
+            PRICE,
+            DEFAULT_SELLER_SIGHASH,
+            None,
+            Some(&royalty),
+        );
+        assert!(matches!(
+            result,
+            Err(Error::NoRoyaltyFunding(amount)) if amount == royalty.amount
+        ));
+    }
+
+    #[test]
+    fn price_must_cover_postage_and_dust() {
+        let market = Market::new();
+        let list = |price| {
+            create_seller_psbt(
+                &market.ctx,
+                market.inscription_utxo,
+                price,
+                DEFAULT_SELLER_SIGHASH,
+                None,
+                None,
+            )
+        };
+        // the inscription's value plus a P2WPKH output's dust limit
+        let minimum = Amount::from_sat(INSCRIPTION_VALUE + 294);
+        assert!(list(minimum).is_ok());
+        assert!(matches!(
+            list(minimum - Amount::from_sat(1)),
+            Err(Error::PriceBelowPostage { minimum: m, .. }) if m == minimum
+        ));
+    }
+
+    /// An unsigned listing of an inscription on `p2wpkh(1)` for [`PRICE`].
+    fn unsigned_listing() -> (Psbt, TxOut) {
+        let inscription_tx = funding_tx(10, &[(INSCRIPTION_VALUE, &p2wpkh(1))]);
+        let payment = TxOut {
+            value: PRICE.to_sat(),
+            script_pubkey: p2wpkh(1).script_pubkey(),
+        };
+        let tx = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(inscription_tx.txid(), 0),
+                ..Default::default()
+            }],
+            output: vec![payment.clone()],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(inscription_tx.output[0].clone());
+        (psbt, payment)
+    }
+
+    #[test]
+    fn raw_key_signature_verifies() {
+        let (mut psbt, payment) = unsigned_listing();
+        sign_seller_psbt_with_key(&mut psbt, &key(1), DEFAULT_SELLER_SIGHASH).unwrap();
+        assert_eq!(psbt.inputs[0].partial_sigs.len(), 1);
+        verify_seller_signature(&psbt, &payment).unwrap();
+
+        let (mut psbt, _) = unsigned_listing();
+        assert!(matches!(
+            sign_seller_psbt_with_key(&mut psbt, &key(2), DEFAULT_SELLER_SIGHASH),
+            Err(Error::SellerSigning(..))
+        ));
+    }
+
+    /// Cancels the listing of `market`'s inscription back to the seller,
+    /// funding the fee from a fresh seller UTXO, and checks every signature
+    /// of the broadcast transaction against its prevouts.
+    fn cancel_and_verify(market: &Market) -> Transaction {
+        let seller_address = market.ctx.config.seller_address.clone();
+        let funding = funding_tx(20, &[(20_000, &seller_address)]);
+        market.seller.add_unspent(&funding, 0, 6);
+
+        let txid = cancel_listing(&market.ctx, market.inscription_utxo, &seller_address).unwrap();
+        let sent = market.seller.state().sent.clone();
+        assert_eq!(sent.len(), 1);
+        let tx = sent[0].clone();
+        assert_eq!(tx.txid(), txid);
+        assert_eq!(tx.input[0].previous_output, market.inscription_utxo);
+        assert_eq!(
+            tx.input[1].previous_output,
+            OutPoint::new(funding.txid(), 0)
+        );
+        assert_eq!(
+            tx.output[0],
+            TxOut {
+                value: INSCRIPTION_VALUE,
+                script_pubkey: seller_address.script_pubkey(),
+            }
+        );
+
+        let prevouts = [
+            market.full.state().txs[&market.inscription_utxo.txid].output[0].clone(),
+            funding.output[0].clone(),
+        ];
+        let fee = prevouts.iter().map(|prevout| prevout.value).sum::<u64>()
+            - tx.output.iter().map(|output| output.value).sum::<u64>();
+        assert!(fee > 0);
+
+        let secp = Secp256k1::verification_only();
+        let mut cache = SighashCache::new(&tx);
+        for (index, prevout) in prevouts.iter().enumerate() {
+            let witness = tx.input[index].witness.to_vec();
+            if prevout.script_pubkey.is_v1_p2tr() {
+                let sig = taproot::Signature::from_slice(&witness[0]).unwrap();
+                let msg = cache
+                    .taproot_key_spend_signature_hash(index, &Prevouts::All(&prevouts), sig.hash_ty)
+                    .unwrap();
+                let output_key =
+                    XOnlyPublicKey::from_slice(&prevout.script_pubkey.as_bytes()[2..]).unwrap();
+                secp.verify_schnorr(&sig.sig, &Message::from(msg), &output_key)
+                    .unwrap();
+            } else {
+                let sig = ecdsa::Signature::from_slice(&witness[0]).unwrap();
+                assert_eq!(sig.hash_ty, EcdsaSighashType::All);
+                let pubkey = bitcoin::PublicKey::from_slice(&witness[1]).unwrap();
+                let script_code = prevout.script_pubkey.p2wpkh_script_code().unwrap();
+                let msg = cache
+                    .segwit_signature_hash(index, &script_code, prevout.value, sig.hash_ty)
+                    .unwrap();
+                secp.verify_ecdsa(&Message::from(msg), &sig.sig, &pubkey.inner)
+                    .unwrap();
+            }
+        }
+        tx
+    }
+
+    #[test]
+    fn cancel_is_a_valid_self_spend() {
+        cancel_and_verify(&Market::new());
+    }
+
+    #[test]
+    fn taproot_cancel_signs_over_every_prevout() {
+        let mut config = config();
+        config.seller_address = p2tr(1);
+        let tx = cancel_and_verify(&Market::with_config(config));
+        assert_eq!(tx.input[0].witness.len(), 1);
+        // SIGHASH_ALL, unlike the default, is spelled out after the signature
+        assert_eq!(tx.input[0].witness.to_vec()[0].len(), 65);
+    }
+
+    #[test]
+    fn taproot_seller_gets_a_key_path_signature() {
+        let mut config = config();
+        config.seller_address = p2tr(1);
+        let market = Market::with_config(config);
+        let (psbt, _) = market.list();
+
+        let input = &psbt.inputs[0];
+        assert!(input.partial_sigs.is_empty());
+        assert_eq!(
+            input.tap_key_sig.map(|sig| sig.hash_ty),
+            Some(TapSighashType::SinglePlusAnyoneCanPay)
+        );
+        assert_eq!(
+            input.sighash_type,
+            Some(PsbtSighashType::from(
+                TapSighashType::SinglePlusAnyoneCanPay
+            ))
+        );
+        verify_seller_signature(&psbt, &psbt.unsigned_tx.output[0]).unwrap();
+    }
+
+    #[test]
+    fn unconfirmed_inscription_is_not_listed() {
+        let mut market = Market::new();
+        let unconfirmed = market.add_inscription(10, 0);
+        let list = |ctx: &MarketplaceContext| {
+            create_seller_psbt(ctx, unconfirmed, PRICE, DEFAULT_SELLER_SIGHASH, None, None)
+        };
+
+        assert!(matches!(
+            list(&market.ctx),
+            Err(Error::InscriptionUnconfirmed {
+                outpoint,
+                confirmations: 0,
+                required: 1,
+            }) if outpoint == unconfirmed
+        ));
+
+        market.ctx.seller_min_conf = 0;
+        list(&market.ctx).unwrap();
+    }
+
+    #[test]
+    fn proceeds_follow_who_pays_the_fee() {
+        let mut config = config();
+        config.marketplace_fee_bps = Some(200);
+        let royalty = Royalty {
+            recipient: p2wpkh(4),
+            amount: Amount::from_sat(3_000),
+        };
+
+        let buyer_paid = seller_proceeds(&config, PRICE, None).unwrap();
+        assert_eq!(buyer_paid.marketplace_fee, Amount::from_sat(1_000));
+        assert_eq!(buyer_paid.net, PRICE);
+
+        config.fee_payer = FeePayer::Seller;
+        let seller_paid = seller_proceeds(&config, PRICE, Some(&royalty)).unwrap();
+        assert_eq!(seller_paid.marketplace_fee, Amount::from_sat(1_000));
+        assert_eq!(seller_paid.royalty, royalty.amount);
+        assert_eq!(seller_paid.net, Amount::from_sat(46_000));
+    }
+}
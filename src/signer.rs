@@ -0,0 +1,196 @@
+use crate::rpc::BitcoinRpc;
+use crate::{Error, MarketplaceContext, Result};
+use bitcoin::bip32::Fingerprint;
+use bitcoin::psbt::Psbt;
+use bitcoin::Network;
+use bitcoincore_rpc::json::SigHashType;
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::process::Command;
+use std::str::FromStr;
+
+/// Signs PSBTs outside the nodes' wallets, e.g. on a hardware wallet. Any
+/// `Fn(&Psbt) -> Result<Psbt>` is one, so tests can plug in a signer that
+/// returns a presigned PSBT.
+pub trait PsbtSigner: Send + Sync {
+    /// Returns `psbt` with signatures added for the inputs the signer holds
+    /// keys for, found through their BIP 32 derivations.
+    fn sign_psbt(&self, psbt: &Psbt) -> Result<Psbt>;
+}
+
+impl<F: Fn(&Psbt) -> Result<Psbt> + Send + Sync> PsbtSigner for F {
+    fn sign_psbt(&self, psbt: &Psbt) -> Result<Psbt> {
+        self(psbt)
+    }
+}
+
+/// A hardware wallet driven through the `hwi` command line tool.
+#[derive(Debug, Clone)]
+pub struct Hwi {
+    binary: PathBuf,
+    fingerprint: Fingerprint,
+    network: Network,
+}
+
+/// What `hwi signtx` prints.
+#[derive(Debug, Deserialize)]
+struct HwiResponse {
+    psbt: Option<String>,
+    error: Option<String>,
+}
+
+impl Hwi {
+    /// The device with master key `fingerprint`, signing for `network` with
+    /// the `hwi` found on the `PATH`.
+    pub fn new(fingerprint: Fingerprint, network: Network) -> Self {
+        Hwi {
+            binary: PathBuf::from("hwi"),
+            fingerprint,
+            network,
+        }
+    }
+
+    /// Runs the `hwi` at `binary` instead.
+    pub fn with_binary(mut self, binary: impl Into<PathBuf>) -> Self {
+        self.binary = binary.into();
+        self
+    }
+}
+
+impl PsbtSigner for Hwi {
+    fn sign_psbt(&self, psbt: &Psbt) -> Result<Psbt> {
+        let output = Command::new(&self.binary)
+            .arg("--fingerprint")
+            .arg(self.fingerprint.to_string())
+            .arg("--chain")
+            .arg(self.network.to_core_arg())
+            .arg("signtx")
+            .arg(psbt.to_string())
+            .output()?;
+        let response = serde_json::from_slice::<HwiResponse>(&output.stdout).map_err(|_| {
+            Error::ExternalSigner(format!(
+                "hwi exited with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))
+        })?;
+        match (response.error, response.psbt) {
+            (Some(error), _) => Err(Error::ExternalSigner(error)),
+            (None, Some(psbt)) => Ok(Psbt::from_str(&psbt)?),
+            (None, None) => Err(Error::ExternalSigner("hwi returned no psbt".to_string())),
+        }
+    }
+}
+
+/// Has `node`'s wallet fill in `psbt` with UTXOs and key origins and sign it
+/// with `sighash`, returning the result and whether the wallet reports it
+/// complete. With the context's `signer` the wallet only fills it in and the
+/// signer signs; with `no_sign` nothing signs.
+pub fn process_psbt(
+    ctx: &MarketplaceContext,
+    node: &dyn BitcoinRpc,
+    psbt: &Psbt,
+    sighash: Option<SigHashType>,
+) -> Result<(Psbt, bool)> {
+    let external = ctx.signer.as_ref().filter(|_| !ctx.no_sign);
+    let processed = node.wallet_process_psbt(
+        &psbt.to_string(),
+        Some(!ctx.no_sign && external.is_none()),
+        sighash,
+        // the signer finds its keys through the derivations
+        external.map(|_| true),
+    )?;
+    let mut processed_psbt = Psbt::from_str(&processed.psbt)?;
+    if ctx.no_sign || external.is_some() {
+        // whatever key origins the wallet dropped, the signer still needs
+        processed_psbt.combine(psbt.clone())?;
+    }
+    let psbt = processed_psbt;
+    match external {
+        Some(signer) => {
+            log::debug!("signing psbt externally txid={}", psbt.unsigned_tx.txid());
+            Ok((signer.sign_psbt(&psbt)?, false))
+        }
+        None => Ok((psbt, processed.complete)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::mock::{key, Market};
+    use bitcoin::bip32::DerivationPath;
+    use bitcoin::secp256k1::Secp256k1;
+    use bitcoin::Witness;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Has the buyer wallet report `key(2)`, the buyer's key, with a key
+    /// origin, returning the origin.
+    fn buyer_key_origin(market: &Market) -> (Fingerprint, DerivationPath) {
+        let origin = (
+            Fingerprint::from_str("d34db33f").unwrap(),
+            DerivationPath::from_str("m/84'/1'/0'/0/0").unwrap(),
+        );
+        market.buyer.state().address_info.insert(
+            market.ctx.config.buyer_address.to_string(),
+            json!({
+                "pubkey": key(2).public_key(&Secp256k1::new()).to_string(),
+                "hdmasterfingerprint": origin.0.to_string(),
+                "hdkeypath": origin.1.to_string(),
+            }),
+        );
+        origin
+    }
+
+    #[test]
+    fn no_sign_fills_in_the_inputs_without_signing() {
+        let mut market = Market::new();
+        let origin = buyer_key_origin(&market);
+        let listing = market.list();
+        market.ctx.no_sign = true;
+
+        let psbt = market.buy(listing).unwrap();
+        let pubkey = key(2).public_key(&Secp256k1::new()).inner;
+        for index in [0, 2] {
+            let input = &psbt.inputs[index];
+            assert!(input.partial_sigs.is_empty());
+            assert!(input.final_script_witness.is_none());
+            assert!(input.witness_utxo.is_some());
+            assert_eq!(input.bip32_derivation.get(&pubkey), Some(&origin));
+        }
+        assert_eq!(market.buyer.state().sent.len(), 0);
+    }
+
+    #[test]
+    fn external_signer_signature_is_used() {
+        let mut market = Market::new();
+        let origin = buyer_key_origin(&market);
+        let listing = market.list();
+        let presigned = Witness::from_slice(&[vec![1; 72], vec![2; 33]]);
+        let calls = Arc::new(AtomicUsize::new(0));
+        market.ctx.signer = Some(Box::new({
+            let (presigned, calls) = (presigned.clone(), calls.clone());
+            move |psbt: &Psbt| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                let mut signed = psbt.clone();
+                for index in [0, 2] {
+                    // the wallet filled in what the signer looks its keys up by
+                    let input = &mut signed.inputs[index];
+                    assert!(input.bip32_derivation.values().any(|o| *o == origin));
+                    assert!(input.final_script_witness.is_none());
+                    input.final_script_witness = Some(presigned.clone());
+                }
+                Ok(signed)
+            }
+        }));
+
+        let mut psbt = market.buy(listing).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        let tx = crate::finalize::finalize(&mut psbt).unwrap();
+        assert_eq!(tx.input[0].witness, presigned);
+        assert_eq!(tx.input[2].witness, presigned);
+        assert_eq!(tx.input[1].witness.len(), 2);
+    }
+}
@@ -0,0 +1,296 @@
+use crate::input::InputKind;
+use bitcoin::psbt::Psbt;
+use bitcoin::{Address, Amount, Network, Transaction, TxOut};
+use std::fmt::{self, Write};
+
+/// What an output of a purchase transaction is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputRole {
+    /// The dummy plus the bought inscriptions, sent to the buyer.
+    Inscription,
+    /// A seller's price.
+    SellerPayment,
+    /// The marketplace's cut.
+    ServiceFee,
+    /// The buyer's dummy for the next purchase.
+    Dummy,
+    /// The buyer's change.
+    Change,
+    /// The transaction doesn't follow the purchase layout.
+    Unknown,
+}
+
+impl fmt::Display for OutputRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let role = match self {
+            OutputRole::Inscription => "inscription",
+            OutputRole::SellerPayment => "seller payment",
+            OutputRole::ServiceFee => "service fee",
+            OutputRole::Dummy => "dummy",
+            OutputRole::Change => "change",
+            OutputRole::Unknown => "unknown",
+        };
+        f.write_str(role)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputSummary {
+    /// `None` for scripts without an address form.
+    pub address: Option<Address>,
+    pub value: Amount,
+    pub role: OutputRole,
+}
+
+/// The amounts moved by a transaction, for review before it is broadcast.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransactionSummary {
+    pub total_input: Amount,
+    pub total_output: Amount,
+    pub fee: Amount,
+    pub vsize: usize,
+    /// `fee` per virtual byte, in sats.
+    pub fee_rate: f64,
+    pub outputs: Vec<OutputSummary>,
+}
+
+impl fmt::Display for TransactionSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, output) in self.outputs.iter().enumerate() {
+            let address = output
+                .address
+                .as_ref()
+                .map_or_else(|| "<non-standard>".to_string(), Address::to_string);
+            writeln!(
+                f,
+                "output {}: {} to {} ({})",
+                i, output.value, address, output.role
+            )?;
+        }
+        writeln!(f, "inputs:  {}", self.total_input)?;
+        writeln!(f, "outputs: {}", self.total_output)?;
+        write!(
+            f,
+            "fee:     {} ({:.2} sat/vB over {} vB)",
+            self.fee, self.fee_rate, self.vsize
+        )
+    }
+}
+
+/// Summarizes `tx` spending `prevouts`, given in input order. Output roles
+/// follow the purchase layout built by the buyer and are all
+/// [`OutputRole::Unknown`] for transactions that don't match it.
+pub fn summarize_transaction(
+    tx: &Transaction,
+    prevouts: &[TxOut],
+    network: Network,
+) -> TransactionSummary {
+    let total_input = prevouts
+        .iter()
+        .map(|prevout| Amount::from_sat(prevout.value))
+        .sum::<Amount>();
+    let total_output = tx
+        .output
+        .iter()
+        .map(|output| Amount::from_sat(output.value))
+        .sum::<Amount>();
+    let fee = total_input
+        .checked_sub(total_output)
+        .unwrap_or(Amount::ZERO);
+    let vsize = tx.vsize();
+    let roles = purchase_output_roles(tx, prevouts);
+
+    TransactionSummary {
+        total_input,
+        total_output,
+        fee,
+        vsize,
+        fee_rate: fee.to_sat() as f64 / vsize as f64,
+        outputs: tx
+            .output
+            .iter()
+            .zip(roles)
+            .map(|(output, role)| OutputSummary {
+                address: Address::from_script(&output.script_pubkey, network).ok(),
+                value: Amount::from_sat(output.value),
+                role,
+            })
+            .collect(),
+    }
+}
+
+/// Output 0 collects the dummy and every seller input in front of the
+/// payment inputs, so the number of listings is however many prevouts after
+/// the dummy add up to its value. The seller payments follow, then the
+/// service fee outputs, the next dummy and optionally change back to the
+/// buyer.
+fn purchase_output_roles(tx: &Transaction, prevouts: &[TxOut]) -> Vec<OutputRole> {
+    let unknown = vec![OutputRole::Unknown; tx.output.len()];
+    let Some(first) = tx.output.first() else {
+        return unknown;
+    };
+    let mut collected = 0;
+    let Some(listings) = prevouts.iter().position(|prevout| {
+        collected += prevout.value;
+        collected == first.value
+    }) else {
+        return unknown;
+    };
+    if listings == 0 || tx.output.len() < listings + 3 {
+        return unknown;
+    }
+
+    let mut roles = vec![OutputRole::Inscription];
+    roles.extend(std::iter::repeat_n(OutputRole::SellerPayment, listings));
+    roles.push(OutputRole::ServiceFee);
+    // with fee splits every recipient gets an output before the buyer's
+    let fee_outputs = tx.output[listings + 2..]
+        .iter()
+        .take_while(|output| output.script_pubkey != first.script_pubkey)
+        .count();
+    roles.extend(std::iter::repeat_n(OutputRole::ServiceFee, fee_outputs));
+    let buyer_outputs = listings + 2 + fee_outputs;
+    for output in &tx.output[buyer_outputs..] {
+        let role = if output.script_pubkey != first.script_pubkey {
+            OutputRole::Unknown
+        } else if roles.len() == buyer_outputs {
+            OutputRole::Dummy
+        } else {
+            OutputRole::Change
+        };
+        roles.push(role);
+    }
+    roles
+}
+
+/// Renders what `psbt` holds, for debugging a handoff: each input's prevout,
+/// the UTXO data attached to it, its sighash type and the signatures present,
+/// each output's address and value, and the fee when every prevout is known.
+pub fn describe_psbt(psbt: &Psbt, network: Network) -> String {
+    let tx = &psbt.unsigned_tx;
+    let mut out = format!(
+        "psbt {} (version {}, locktime {})\n",
+        tx.txid(),
+        tx.version,
+        tx.lock_time
+    );
+
+    for (i, (txin, input)) in tx.input.iter().zip(&psbt.inputs).enumerate() {
+        let _ = writeln!(
+            out,
+            "input {}: {} sequence {:#x}",
+            i,
+            txin.previous_output,
+            txin.sequence.to_consensus_u32()
+        );
+        let utxo = match (&input.witness_utxo, &input.non_witness_utxo) {
+            (Some(_), _) => "witness_utxo",
+            (None, Some(_)) => "non_witness_utxo",
+            (None, None) => "missing",
+        };
+        match psbt.spend_utxo(i) {
+            Ok(prevout) => {
+                let _ = writeln!(
+                    out,
+                    "  utxo: {} {:?} of {}",
+                    utxo,
+                    InputKind::from_script(&prevout.script_pubkey),
+                    Amount::from_sat(prevout.value)
+                );
+            }
+            Err(_) => {
+                let _ = writeln!(out, "  utxo: {}", utxo);
+            }
+        }
+        let sighash = input
+            .sighash_type
+            .map_or_else(|| "default".to_string(), |sighash| sighash.to_string());
+        let _ = writeln!(out, "  sighash: {}", sighash);
+
+        let mut signatures = input
+            .partial_sigs
+            .iter()
+            .map(|(pubkey, sig)| format!("ecdsa {} by {}", sig.hash_ty, pubkey))
+            .collect::<Vec<_>>();
+        if let Some(sig) = input.tap_key_sig {
+            signatures.push(format!("taproot key path {}", sig.hash_ty));
+        }
+        if input.final_script_sig.is_some() || input.final_script_witness.is_some() {
+            signatures.push("finalized".to_string());
+        }
+        if signatures.is_empty() {
+            signatures.push("none".to_string());
+        }
+        let _ = writeln!(out, "  signatures: {}", signatures.join(", "));
+    }
+
+    for (i, txout) in tx.output.iter().enumerate() {
+        let address = Address::from_script(&txout.script_pubkey, network).map_or_else(
+            |_| "<non-standard>".to_string(),
+            |address| address.to_string(),
+        );
+        let _ = writeln!(
+            out,
+            "output {}: {} to {}",
+            i,
+            Amount::from_sat(txout.value),
+            address
+        );
+    }
+
+    let total_input = (0..tx.input.len())
+        .map(|i| {
+            psbt.spend_utxo(i)
+                .map(|prevout| Amount::from_sat(prevout.value))
+        })
+        .sum::<std::result::Result<Amount, _>>();
+    let total_output = tx
+        .output
+        .iter()
+        .map(|txout| Amount::from_sat(txout.value))
+        .sum::<Amount>();
+    match total_input {
+        Ok(total_input) if total_input >= total_output => {
+            let _ = write!(out, "fee: {}", total_input - total_output);
+        }
+        Ok(_) => out.push_str("fee: outputs exceed inputs, more inputs to come"),
+        Err(_) => out.push_str("fee: unknown, some prevouts are missing"),
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::mock::{Market, NETWORK};
+
+    #[test]
+    fn fee_is_inputs_minus_outputs() {
+        let market = Market::new();
+        let buyer_psbt = market.buy(market.list()).unwrap();
+        let prevouts = (0..buyer_psbt.inputs.len())
+            .map(|i| buyer_psbt.spend_utxo(i).unwrap().clone())
+            .collect::<Vec<_>>();
+        let tx = &buyer_psbt.unsigned_tx;
+
+        let summary = summarize_transaction(tx, &prevouts, NETWORK);
+        let inputs = prevouts.iter().map(|prevout| prevout.value).sum::<u64>();
+        let outputs = tx.output.iter().map(|output| output.value).sum::<u64>();
+        assert_eq!(summary.fee, Amount::from_sat(inputs - outputs));
+        assert_eq!(summary.fee, summary.total_input - summary.total_output);
+        assert_eq!(
+            summary
+                .outputs
+                .iter()
+                .map(|output| output.role)
+                .collect::<Vec<_>>(),
+            [
+                OutputRole::Inscription,
+                OutputRole::SellerPayment,
+                OutputRole::ServiceFee,
+                OutputRole::Dummy,
+                OutputRole::Change,
+            ]
+        );
+    }
+}
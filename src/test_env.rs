@@ -0,0 +1,13 @@
+//! Serializes the tests that change the process environment, which the test
+//! harness would otherwise run on parallel threads. Shared by the library's
+//! and the binary's tests.
+
+use std::sync::{Mutex, MutexGuard};
+
+static ENV: Mutex<()> = Mutex::new(());
+
+/// Held by every test changing the process environment, for as long as it
+/// does.
+pub(crate) fn lock_env() -> MutexGuard<'static, ()> {
+    ENV.lock().unwrap_or_else(|e| e.into_inner())
+}
@@ -0,0 +1,660 @@
+use crate::explorer::explorer_endpoint;
+use crate::fee::{
+    check_dust, checked_sum, dust_limit, estimate_fee_rate, estimate_vsize, estimate_weight,
+    fee_for_vsize, output_weight,
+};
+use crate::input::{set_input_utxo, InputKind};
+use crate::rpc::BitcoinRpc;
+use crate::selection::select_payment_utxos;
+use crate::signer::process_psbt;
+use crate::{Error, MarketplaceContext, Result};
+use bitcoin::absolute::LockTime;
+use bitcoin::address::NetworkUnchecked;
+use bitcoin::consensus;
+use bitcoin::psbt::Psbt;
+use bitcoin::{
+    Address, Amount, Network, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness,
+};
+use bitcoincore_rpc::json::ListUnspentResultEntry;
+use reqwest::blocking::Client;
+use reqwest::header::{ACCEPT, CONTENT_TYPE};
+use reqwest::{StatusCode, Url};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Value of the dummy outputs that pad a purchase when `DUMMY_UTXO_SAT` is
+/// unset.
+pub const DEFAULT_DUMMY_VALUE: Amount = Amount::from_sat(1000);
+
+/// How long to wait for a freshly broadcast dummy when
+/// `DUMMY_WAIT_TIMEOUT_SECS` is unset.
+pub const DEFAULT_DUMMY_WAIT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often [`wait_for_utxo`] asks the node again.
+const UTXO_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The part of ord's `/output/<outpoint>` and `/outputs` JSON responses we
+/// care about.
+#[derive(Debug, Deserialize)]
+pub(crate) struct OrdOutput {
+    #[serde(default)]
+    pub(crate) inscriptions: Vec<String>,
+}
+
+/// A buyer UTXO with what decides what it may be spent for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpendableUtxo {
+    pub outpoint: OutPoint,
+    pub amount: Amount,
+    pub script_pubkey: ScriptBuf,
+    /// `None` when the script has no address form.
+    pub address: Option<Address<NetworkUnchecked>>,
+    pub confirmations: u32,
+    /// Whether the inscription index reports an inscription on it.
+    pub is_inscription: bool,
+}
+
+impl SpendableUtxo {
+    /// `utxo` as the wallet lists it, with what the inscription index says
+    /// about it.
+    pub fn new(utxo: &ListUnspentResultEntry, is_inscription: bool) -> Self {
+        SpendableUtxo {
+            outpoint: OutPoint::new(utxo.txid, utxo.vout),
+            amount: utxo.amount,
+            script_pubkey: utxo.script_pub_key.clone(),
+            address: utxo.address.clone(),
+            confirmations: utxo.confirmations,
+            is_inscription,
+        }
+    }
+
+    /// Whether it can pad a purchase as its dummy: it carries no inscription
+    /// and is worth at most `dummy_value`.
+    pub fn is_dummy_candidate(&self, dummy_value: Amount) -> bool {
+        !self.is_inscription && self.amount <= dummy_value
+    }
+
+    /// Whether it can fund a payment: it carries no inscription and has at
+    /// least `min_conf` confirmations.
+    pub fn is_payment_candidate(&self, min_conf: usize) -> bool {
+        !self.is_inscription && self.confirmations as usize >= min_conf
+    }
+}
+
+pub fn utxo_address(utxo: &ListUnspentResultEntry, network: Network) -> Result<Address> {
+    let outpoint = OutPoint::new(utxo.txid, utxo.vout);
+    Ok(utxo
+        .address
+        .clone()
+        .ok_or(Error::MissingAddress(outpoint))?
+        .require_network(network)?)
+}
+
+/// Fetches the output `outpoint` refers to from the context's full node, or
+/// its block explorer.
+pub fn fetch_prevout(ctx: &MarketplaceContext, outpoint: OutPoint) -> Result<TxOut> {
+    ctx.full_node_transaction(outpoint.txid)?
+        .output
+        .get(outpoint.vout as usize)
+        .cloned()
+        .ok_or(Error::MissingOutput(outpoint))
+}
+
+/// Lists the UTXOs at the buyer's addresses with at least the context's
+/// `buyer_min_conf` confirmations that carry no inscription, smallest first.
+pub fn get_buyer_spendable_utxos(ctx: &MarketplaceContext) -> Result<Vec<SpendableUtxo>> {
+    let buyer_addresses = ctx.config.buyer_addresses();
+    let unspent_utxos = ctx.retry.run(|| {
+        ctx.buyer_node.list_unspent(
+            Some(ctx.buyer_min_conf),
+            None,
+            Some(&buyer_addresses),
+            Some(true),
+            None,
+        )
+    })?;
+
+    let inscription_outputs = ctx.inscription_outputs(&unspent_utxos)?;
+    let mut sorted_spendable_utxos = unspent_utxos
+        .iter()
+        .map(|x| {
+            let is_inscription = inscription_outputs.contains(&OutPoint::new(x.txid, x.vout));
+            SpendableUtxo::new(x, is_inscription)
+        })
+        .filter(|x| x.is_payment_candidate(ctx.buyer_min_conf))
+        .collect::<Vec<_>>();
+    sorted_spendable_utxos.sort_by_key(|x| x.amount);
+    Ok(sorted_spendable_utxos)
+}
+
+/// Looks up all `outpoints` with a single POST to ord's `/outputs` endpoint
+/// and returns the ones that carry at least one inscription.
+pub fn fetch_inscription_outputs(
+    client: &Client,
+    explorer: &Url,
+    outpoints: &[OutPoint],
+) -> Result<HashSet<OutPoint>> {
+    if outpoints.is_empty() {
+        return Ok(HashSet::new());
+    }
+
+    let resp = client
+        .post(explorer_endpoint(explorer, "outputs")?)
+        .header(ACCEPT, "application/json")
+        .header(CONTENT_TYPE, "application/json")
+        .body(outputs_request(outpoints)?)
+        .send()?
+        .error_for_status()?;
+    inscribed_outputs(outpoints, &resp.text()?)
+}
+
+/// Body of an ord `/outputs` request for `outpoints`.
+pub(crate) fn outputs_request(outpoints: &[OutPoint]) -> Result<String> {
+    Ok(serde_json::to_string(
+        &outpoints
+            .iter()
+            .map(|outpoint| outpoint.to_string())
+            .collect::<Vec<_>>(),
+    )?)
+}
+
+/// Picks the inscribed ones out of `outpoints` given ord's `/outputs`
+/// answer for them.
+pub(crate) fn inscribed_outputs(outpoints: &[OutPoint], body: &str) -> Result<HashSet<OutPoint>> {
+    // ord answers in request order
+    let outputs: Vec<OrdOutput> = serde_json::from_str(body)?;
+    // an outpoint left unanswered must not pass for uninscribed
+    if outputs.len() != outpoints.len() {
+        return Err(Error::OrdOutputCount {
+            requested: outpoints.len(),
+            returned: outputs.len(),
+        });
+    }
+
+    Ok(outpoints
+        .iter()
+        .zip(outputs)
+        .filter(|(_, output)| !output.inscriptions.is_empty())
+        .map(|(&outpoint, _)| outpoint)
+        .collect())
+}
+
+/// Asks the ord explorer rooted at `explorer` whether `outpoint` carries an
+/// inscription.
+pub fn is_utxo_inscription(client: &Client, explorer: &Url, outpoint: OutPoint) -> Result<bool> {
+    let resp = client
+        .get(explorer_endpoint(
+            explorer,
+            &format!("output/{}", outpoint),
+        )?)
+        .header(ACCEPT, "application/json")
+        .send()?;
+    // ord doesn't know the output yet, so nothing can be inscribed on it
+    if resp.status() == StatusCode::NOT_FOUND {
+        return Ok(false);
+    }
+    let output: OrdOutput = serde_json::from_str(&resp.error_for_status()?.text()?)?;
+    Ok(!output.inscriptions.is_empty())
+}
+
+/// Picks a dummy among `utxos` that carries no inscription, preferring one
+/// of exactly the context's `dummy_value` at the dummy address, e.g. the next
+/// dummy a previous purchase left behind, over any other output of at most
+/// that value.
+pub(crate) fn find_dummy_utxo(
+    ctx: &MarketplaceContext,
+    utxos: &[SpendableUtxo],
+) -> Option<SpendableUtxo> {
+    let dummy_value = ctx.dummy_value;
+    let dummy_script = ctx.config.dummy_address.script_pubkey();
+    let mut candidates = utxos
+        .iter()
+        .filter(|utxo| utxo.is_dummy_candidate(dummy_value));
+    let exact = candidates
+        .clone()
+        .find(|utxo| utxo.amount == dummy_value && utxo.script_pubkey == dummy_script);
+    exact.or_else(|| candidates.next()).cloned()
+}
+
+/// Returns a UTXO of at most the context's `dummy_value` to pad the purchase,
+/// reusing an existing one when possible and only splitting the smallest
+/// spendable UTXO that covers the dummy, fee and non-dust change into a fresh
+/// dummy when there is none. A dummy still unconfirmed, e.g. split off by an
+/// earlier run that failed after broadcasting it, counts as existing.
+pub fn retrieve_dummy_utxo(
+    ctx: &MarketplaceContext,
+    utxos: &[SpendableUtxo],
+) -> Result<SpendableUtxo> {
+    if let Some(dummy_utxo) = find_dummy_utxo(ctx, utxos) {
+        log::debug!(
+            "reusing dummy utxo outpoint={} value={}",
+            dummy_utxo.outpoint,
+            dummy_utxo.amount.to_sat()
+        );
+        return Ok(dummy_utxo);
+    }
+    if let Some(dummy_utxo) = find_pending_dummy(ctx)? {
+        log::info!(
+            "reusing unconfirmed dummy utxo outpoint={} value={}",
+            dummy_utxo.outpoint,
+            dummy_utxo.amount.to_sat()
+        );
+        return Ok(dummy_utxo);
+    }
+    if ctx.no_sign {
+        return Err(Error::SigningDisabled("splitting off a dummy utxo"));
+    }
+
+    let fee_rate = estimate_fee_rate(ctx.buyer_node.as_ref(), &ctx.fee)?;
+    let dummy_output = TxOut {
+        value: ctx.dummy_value.to_sat(),
+        script_pubkey: ctx.config.dummy_address.script_pubkey(),
+    };
+    check_dust(&dummy_output)?;
+    let change_script = ctx.config.change_address.script_pubkey();
+    let mut split = None;
+    for utxo in utxos {
+        let split_tx = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: utxo.outpoint,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::default(),
+            }],
+            output: vec![
+                dummy_output.clone(),
+                TxOut {
+                    value: 0,
+                    script_pubkey: change_script.clone(),
+                },
+            ],
+        };
+        let input_kind = InputKind::from_script(&utxo.script_pubkey);
+        let fee = fee_for_vsize(fee_rate, estimate_vsize(&split_tx, &[input_kind]))?;
+        let dust = dust_limit(&change_script);
+        // `utxos` is sorted by value, so this is the smallest that can be
+        // split without its change turning into dust
+        if utxo.amount >= checked_sum([ctx.dummy_value, fee, dust])? {
+            split = Some((utxo, split_tx, input_kind, fee));
+            break;
+        }
+    }
+    let Some((split_utxo, mut split_tx, input_kind, fee)) = split else {
+        return Err(Error::CannotCreateDummy(ctx.dummy_value));
+    };
+    split_tx.output[1].value = (split_utxo.amount - ctx.dummy_value - fee).to_sat();
+
+    let mut dummy_psbt = Psbt::from_unsigned_tx(split_tx)?;
+
+    set_input_utxo(
+        &mut dummy_psbt.inputs[0],
+        &ctx.retry.run(|| {
+            ctx.buyer_node
+                .get_raw_transaction(&split_utxo.outpoint.txid, None)
+        })?,
+        split_utxo.outpoint.vout,
+        input_kind,
+    );
+
+    let (processed_dummy_psbt, _) = process_psbt(ctx, ctx.buyer_node.as_ref(), &dummy_psbt, None)?;
+    let dummy_raw_tx = ctx
+        .buyer_node
+        .finalize_psbt(&processed_dummy_psbt.to_string(), None)?
+        .hex
+        .ok_or(Error::PsbtNotFinalized)?;
+
+    if ctx.dry_run {
+        return simulate_dummy_utxo(ctx, &dummy_raw_tx);
+    }
+
+    let dummy_txid = ctx.buyer_node.send_raw_transaction(&dummy_raw_tx)?;
+    log::info!(
+        "created dummy utxo txid={} split_from={}",
+        dummy_txid,
+        split_utxo.outpoint
+    );
+    // the dummy is the split's first output
+    let dummy_utxo = wait_for_utxo(
+        ctx.buyer_node.as_ref(),
+        OutPoint::new(dummy_txid, 0),
+        ctx.dummy_wait_timeout,
+    )?;
+    // split off a UTXO without inscriptions, it carries none either
+    Ok(SpendableUtxo::new(&dummy_utxo, false))
+}
+
+/// Looks for an unconfirmed output of exactly the context's `dummy_value` at
+/// the dummy address that the buyer's wallet created itself and so trusts,
+/// which `buyer_min_conf` keeps out of the spendable UTXOs. Without it a
+/// purchase failing after its dummy split was broadcast would have the next
+/// attempt split off yet another one.
+fn find_pending_dummy(ctx: &MarketplaceContext) -> Result<Option<SpendableUtxo>> {
+    let dummy_address = &ctx.config.dummy_address;
+    let pending = ctx.retry.run(|| {
+        ctx.buyer_node
+            .list_unspent(Some(0), Some(0), Some(&[dummy_address]), Some(false), None)
+    })?;
+    let inscription_outputs = ctx.inscription_outputs(&pending)?;
+    Ok(pending
+        .iter()
+        .filter(|utxo| utxo.safe && utxo.amount == ctx.dummy_value)
+        .map(|utxo| {
+            let is_inscription = inscription_outputs.contains(&OutPoint::new(utxo.txid, utxo.vout));
+            SpendableUtxo::new(utxo, is_inscription)
+        })
+        .find(|utxo| {
+            utxo.is_dummy_candidate(ctx.dummy_value) && !ctx.is_simulated_spent(utxo.outpoint)
+        }))
+}
+
+/// Creates `count` dummies of `value` at `address` in one transaction funded
+/// from the buyer's spendable UTXOs, with change to the change address,
+/// so later purchases find a dummy without a split of their own. Returns the
+/// dummies' outpoints, which lead the transaction's outputs.
+pub fn prepare_dummies(
+    ctx: &MarketplaceContext,
+    address: &Address,
+    count: usize,
+    value: Amount,
+) -> Result<Vec<OutPoint>> {
+    if ctx.no_sign {
+        return Err(Error::SigningDisabled("preparing dummy utxos"));
+    }
+    let dummy_output = TxOut {
+        value: value.to_sat(),
+        script_pubkey: address.script_pubkey(),
+    };
+    check_dust(&dummy_output)?;
+    let dummies_value = value.checked_mul(count as u64).ok_or(Error::Overflow)?;
+
+    let mut split_tx = Transaction {
+        version: 2,
+        lock_time: LockTime::ZERO,
+        input: Vec::new(),
+        output: vec![dummy_output; count],
+    };
+    let fee_rate = estimate_fee_rate(ctx.buyer_node.as_ref(), &ctx.fee)?;
+    let fixed_fee = fee_for_vsize(fee_rate, estimate_vsize(&split_tx, &[]))?;
+    let candidates = get_buyer_spendable_utxos(ctx)?
+        .into_iter()
+        // spending existing dummies to make new ones would be pointless
+        .filter(|utxo| utxo.amount > value)
+        .filter(|utxo| !ctx.is_simulated_spent(utxo.outpoint))
+        .collect::<Vec<_>>();
+    let change_script = ctx.config.change_address.script_pubkey();
+    let max_input_weight = ctx
+        .max_tx_weight
+        .saturating_sub(estimate_weight(&split_tx, &[]) + output_weight(&change_script));
+    let funding = select_payment_utxos(
+        &candidates,
+        checked_sum([dummies_value, fixed_fee])?,
+        fee_rate,
+        max_input_weight,
+        ctx.coin_selection,
+    )?;
+
+    let mut input_kinds = Vec::with_capacity(funding.len());
+    for utxo in &funding {
+        split_tx.input.push(TxIn {
+            previous_output: utxo.outpoint,
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::default(),
+        });
+        input_kinds.push(InputKind::from_script(&utxo.script_pubkey));
+    }
+    let funding_value = checked_sum(funding.iter().map(|utxo| utxo.amount))?;
+
+    // change goes last, dust change is left to the miner
+    split_tx.output.push(TxOut {
+        value: 0,
+        script_pubkey: change_script.clone(),
+    });
+    let fee = fee_for_vsize(fee_rate, estimate_vsize(&split_tx, &input_kinds))?;
+    match funding_value.checked_sub(checked_sum([dummies_value, fee])?) {
+        Some(change) if change >= dust_limit(&change_script).max(ctx.min_change) => {
+            split_tx
+                .output
+                .last_mut()
+                .expect("change output was pushed")
+                .value = change.to_sat();
+        }
+        _ => {
+            split_tx.output.pop();
+            let fee = fee_for_vsize(fee_rate, estimate_vsize(&split_tx, &input_kinds))?;
+            let required = checked_sum([dummies_value, fee])?;
+            if funding_value < required {
+                return Err(Error::insufficient_funds(required, funding_value));
+            }
+            // the surplus raises the fee rate past the estimate
+            ctx.fee.check_fee(
+                funding_value - dummies_value,
+                estimate_vsize(&split_tx, &input_kinds),
+            )?;
+        }
+    }
+
+    let mut split_psbt = Psbt::from_unsigned_tx(split_tx)?;
+    for (i, utxo) in funding.iter().enumerate() {
+        set_input_utxo(
+            &mut split_psbt.inputs[i],
+            &ctx.buyer_transaction(utxo.outpoint.txid)?,
+            utxo.outpoint.vout,
+            input_kinds[i],
+        );
+    }
+    let (processed_split_psbt, _) = process_psbt(ctx, ctx.buyer_node.as_ref(), &split_psbt, None)?;
+    let split_raw_tx = ctx
+        .buyer_node
+        .finalize_psbt(&processed_split_psbt.to_string(), None)?
+        .hex
+        .ok_or(Error::PsbtNotFinalized)?;
+
+    let split_tx: Transaction = consensus::deserialize(&split_raw_tx)?;
+    let split_txid = split_tx.txid();
+    if ctx.dry_run {
+        log::info!(
+            "dry run: not broadcasting dummies tx txid={} hex={}",
+            split_txid,
+            consensus::encode::serialize_hex(&split_tx)
+        );
+        ctx.record_simulated(split_tx);
+    } else {
+        ctx.buyer_node.send_raw_transaction(&split_raw_tx)?;
+        log::info!(
+            "created dummy utxos txid={} count={} value={}",
+            split_txid,
+            count,
+            value.to_sat()
+        );
+    }
+    Ok((0..count as u32)
+        .map(|vout| OutPoint::new(split_txid, vout))
+        .collect())
+}
+
+/// Records the signed dummy split instead of broadcasting it and returns its
+/// dummy output as the wallet would list it once seen.
+fn simulate_dummy_utxo(ctx: &MarketplaceContext, dummy_raw_tx: &[u8]) -> Result<SpendableUtxo> {
+    let dummy_tx: Transaction = consensus::deserialize(dummy_raw_tx)?;
+    let dummy_txid = dummy_tx.txid();
+    log::info!(
+        "dry run: not broadcasting dummy tx txid={} hex={}",
+        dummy_txid,
+        consensus::encode::serialize_hex(&dummy_tx)
+    );
+    let dummy_utxo = SpendableUtxo {
+        outpoint: OutPoint::new(dummy_txid, 0),
+        amount: Amount::from_sat(dummy_tx.output[0].value),
+        script_pubkey: dummy_tx.output[0].script_pubkey.clone(),
+        address: ctx.config.dummy_address.to_string().parse().ok(),
+        confirmations: 0,
+        is_inscription: false,
+    };
+    ctx.record_simulated(dummy_tx);
+    Ok(dummy_utxo)
+}
+
+/// Polls the wallet behind `client`, unconfirmed outputs included, until
+/// `outpoint` shows up as unspent or `timeout` passes.
+pub fn wait_for_utxo(
+    client: &dyn BitcoinRpc,
+    outpoint: OutPoint,
+    timeout: Duration,
+) -> Result<ListUnspentResultEntry> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let found = client
+            .list_unspent(Some(0), None, None, Some(true), None)?
+            .into_iter()
+            .find(|utxo| utxo.txid == outpoint.txid && utxo.vout == outpoint.vout);
+        if let Some(utxo) = found {
+            return Ok(utxo);
+        }
+        if Instant::now() >= deadline {
+            return Err(Error::UtxoTimeout(outpoint, timeout));
+        }
+        thread::sleep(UTXO_POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::mock::{funding_tx, p2wpkh, unspent_entry, Market};
+
+    #[test]
+    fn reuses_existing_dummy() {
+        let market = Market::new();
+        let spendable = get_buyer_spendable_utxos(&market.ctx).unwrap();
+        let dummy = retrieve_dummy_utxo(&market.ctx, &spendable).unwrap();
+        assert_eq!(dummy.outpoint, market.dummy_utxo);
+        assert!(market.buyer.state().sent.is_empty());
+    }
+
+    #[test]
+    fn never_reuses_an_inscribed_dummy() {
+        let market = Market::new();
+        market
+            .inscriptions
+            .lock()
+            .unwrap()
+            .insert(market.dummy_utxo);
+
+        let spendable = get_buyer_spendable_utxos(&market.ctx).unwrap();
+        let dummy = retrieve_dummy_utxo(&market.ctx, &spendable).unwrap();
+        assert_ne!(dummy.outpoint, market.dummy_utxo);
+        assert_eq!(dummy.amount, market.ctx.dummy_value);
+        assert_eq!(market.buyer.state().sent.len(), 1);
+    }
+
+    #[test]
+    fn unconfirmed_utxos_are_not_spendable_at_one_confirmation() {
+        let market = Market::new();
+        let unconfirmed = market.fund_buyer(10, 50_000, 0);
+        assert_eq!(market.ctx.buyer_min_conf, 1);
+
+        let spendable = get_buyer_spendable_utxos(&market.ctx).unwrap();
+        assert_eq!(spendable.len(), 2);
+        assert!(spendable.iter().all(|utxo| utxo.outpoint != unconfirmed));
+    }
+
+    #[test]
+    fn split_change_is_source_less_dummy_and_fee() {
+        let market = Market::new();
+        market.buyer.state().unspent.clear();
+        let source = market.fund_buyer(10, 100_000, 6);
+
+        let spendable = get_buyer_spendable_utxos(&market.ctx).unwrap();
+        retrieve_dummy_utxo(&market.ctx, &spendable).unwrap();
+        let split = market.buyer.state().sent[0].clone();
+        assert_eq!(split.input[0].previous_output, source);
+        let fee = fee_for_vsize(
+            Amount::from_sat(1),
+            estimate_vsize(&split, &[InputKind::P2wpkh]),
+        )
+        .unwrap();
+        assert_eq!(
+            split.output[1].value,
+            100_000 - market.ctx.dummy_value.to_sat() - fee.to_sat()
+        );
+    }
+
+    #[test]
+    fn spendable_utxos_follow_the_injected_index() {
+        let mut market = Market::new();
+        let payment = market.fund_buyer(10, 50_000, 6);
+        market.ctx.inscription_index = Box::new(move |outpoint: OutPoint| Ok(outpoint == payment));
+
+        let spendable = get_buyer_spendable_utxos(&market.ctx).unwrap();
+        assert_eq!(spendable.len(), 2);
+        assert!(spendable.iter().all(|utxo| utxo.outpoint != payment));
+    }
+
+    #[test]
+    fn prepares_count_equal_dummies() {
+        let market = Market::new();
+        let address = market.ctx.config.dummy_address.clone();
+        let value = Amount::from_sat(600);
+
+        let dummies = prepare_dummies(&market.ctx, &address, 5, value).unwrap();
+        let sent = market.buyer.state().sent.clone();
+        assert_eq!(sent.len(), 1);
+        let split = &sent[0];
+        assert_eq!(dummies.len(), 5);
+        for (vout, dummy) in dummies.iter().enumerate() {
+            assert_eq!(*dummy, OutPoint::new(split.txid(), vout as u32));
+            assert_eq!(
+                split.output[vout],
+                TxOut {
+                    value: value.to_sat(),
+                    script_pubkey: address.script_pubkey(),
+                }
+            );
+        }
+        // plus change
+        assert_eq!(split.output.len(), 6);
+    }
+
+    #[test]
+    fn utxos_too_small_to_split_give_no_dummy() {
+        let market = Market::new();
+        market.buyer.state().unspent.clear();
+        // each covers the dummy and the split's fee, but not non-dust change too
+        market.fund_buyer(10, 1_200, 6);
+        market.fund_buyer(11, 1_300, 6);
+
+        let spendable = get_buyer_spendable_utxos(&market.ctx).unwrap();
+        assert!(matches!(
+            retrieve_dummy_utxo(&market.ctx, &spendable),
+            Err(Error::CannotCreateDummy(value)) if value == market.ctx.dummy_value
+        ));
+        assert!(market.buyer.state().sent.is_empty());
+    }
+
+    fn spendable(amount: u64, confirmations: u32, is_inscription: bool) -> SpendableUtxo {
+        let tx = funding_tx(10, &[(amount, &p2wpkh(2))]);
+        SpendableUtxo::new(&unspent_entry(&tx, 0, confirmations), is_inscription)
+    }
+
+    #[test]
+    fn dummy_candidates_are_small_and_uninscribed() {
+        let dummy_value = Amount::from_sat(1_000);
+        assert!(spendable(1_000, 6, false).is_dummy_candidate(dummy_value));
+        assert!(spendable(600, 0, false).is_dummy_candidate(dummy_value));
+        assert!(!spendable(1_001, 6, false).is_dummy_candidate(dummy_value));
+        assert!(!spendable(1_000, 6, true).is_dummy_candidate(dummy_value));
+    }
+
+    #[test]
+    fn payment_candidates_are_confirmed_and_uninscribed() {
+        assert!(spendable(50_000, 1, false).is_payment_candidate(1));
+        assert!(spendable(50_000, 0, false).is_payment_candidate(0));
+        assert!(!spendable(50_000, 0, false).is_payment_candidate(1));
+        assert!(!spendable(50_000, 6, true).is_payment_candidate(1));
+    }
+}
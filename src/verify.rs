@@ -0,0 +1,270 @@
+use crate::fee::checked_sum;
+use crate::{Error, Result};
+use bitcoin::key::XOnlyPublicKey;
+use bitcoin::psbt::Psbt;
+use bitcoin::secp256k1::{Message, Secp256k1};
+use bitcoin::sighash::{EcdsaSighashType, Prevouts, SighashCache, TapSighashType};
+use bitcoin::{Amount, OutPoint, Transaction, TxOut};
+
+/// Index of the output in a seller PSBT that pays the seller for the
+/// inscription its input 0 spends. It reuses the inscription's script but
+/// carries the price; the inscription itself never lands there.
+pub const SELLER_PAYMENT_OUTPUT: usize = 0;
+
+/// Index of the purchase output that carries the inscriptions to the buyer.
+/// Inputs are assigned to outputs first in first out, so the dummy's and the
+/// inscription outputs' value, the postage, has to fill this output before
+/// the seller payments that follow it.
+pub const INSCRIPTION_CARRIER_OUTPUT: usize = 0;
+
+/// Checks that the seller PSBT pays `expected_output` and that the signatures
+/// on its inputs, ECDSA partial signatures or a taproot key-path signature,
+/// are valid SINGLE|ANYONECANPAY ones, so the seller inputs can be moved into
+/// the buyer's transaction.
+pub fn verify_seller_signature(psbt: &Psbt, expected_output: &TxOut) -> Result<()> {
+    if psbt.inputs.is_empty() {
+        return Err(Error::InvalidSellerSignature(
+            "seller psbt has no inputs".to_string(),
+        ));
+    }
+    if psbt.unsigned_tx.output.get(SELLER_PAYMENT_OUTPUT) != Some(expected_output) {
+        return Err(Error::InvalidSellerSignature(
+            "seller psbt does not pay the expected output".to_string(),
+        ));
+    }
+    let mut cache = SighashCache::new(&psbt.unsigned_tx);
+    for index in 0..psbt.inputs.len() {
+        verify_seller_input(psbt, index, &mut cache)?;
+    }
+    Ok(())
+}
+
+/// Checks the signatures on seller input `index`, see
+/// [`verify_seller_signature`].
+fn verify_seller_input(
+    psbt: &Psbt,
+    index: usize,
+    cache: &mut SighashCache<&Transaction>,
+) -> Result<()> {
+    let invalid = |reason: String| Error::InvalidSellerSignature(reason);
+
+    let input = &psbt.inputs[index];
+    let prevout = psbt
+        .spend_utxo(index)
+        .map_err(|e| invalid(format!("cannot find the seller's prevout: {}", e)))?;
+    let secp = Secp256k1::verification_only();
+
+    if prevout.script_pubkey.is_v1_p2tr() {
+        let sig = input
+            .tap_key_sig
+            .ok_or_else(|| invalid("seller input is not signed".to_string()))?;
+        if sig.hash_ty != TapSighashType::SinglePlusAnyoneCanPay {
+            return Err(invalid(format!(
+                "seller input uses {} instead of SINGLE|ANYONECANPAY",
+                sig.hash_ty
+            )));
+        }
+        let sighash = cache
+            .taproot_key_spend_signature_hash(index, &Prevouts::One(index, prevout), sig.hash_ty)
+            .map_err(|e| invalid(format!("cannot compute seller sighash: {}", e)))?;
+        // the witness program of a P2TR output is its tweaked output key
+        let output_key = XOnlyPublicKey::from_slice(&prevout.script_pubkey.as_bytes()[2..])
+            .map_err(|e| invalid(format!("invalid taproot output key: {}", e)))?;
+        return secp
+            .verify_schnorr(&sig.sig, &Message::from(sighash), &output_key)
+            .map_err(|e| invalid(format!("taproot signature does not verify: {}", e)));
+    }
+
+    if input.partial_sigs.is_empty() {
+        return Err(invalid("seller input is not signed".to_string()));
+    }
+    let (msg, sighash_type) = psbt
+        .sighash_ecdsa(index, cache)
+        .map_err(|e| invalid(format!("cannot compute seller sighash: {}", e)))?;
+    if sighash_type != EcdsaSighashType::SinglePlusAnyoneCanPay {
+        return Err(invalid(format!(
+            "seller input uses {} instead of SINGLE|ANYONECANPAY",
+            sighash_type
+        )));
+    }
+    for (pubkey, sig) in &input.partial_sigs {
+        if sig.hash_ty != EcdsaSighashType::SinglePlusAnyoneCanPay {
+            return Err(invalid(format!(
+                "signature by {} uses {} instead of SINGLE|ANYONECANPAY",
+                pubkey, sig.hash_ty
+            )));
+        }
+        secp.verify_ecdsa(&msg, &sig.sig, &pubkey.inner)
+            .map_err(|e| invalid(format!("signature by {} does not verify: {}", pubkey, e)))?;
+    }
+    Ok(())
+}
+
+/// What the buyer pays for a seller PSBT: its price output plus a royalty if
+/// there is one, less the seller UTXO handed back for funding the royalty.
+pub fn listing_price(psbt: &Psbt) -> Result<Amount> {
+    let tx = &psbt.unsigned_tx;
+    let outputs = checked_sum(
+        tx.output
+            .iter()
+            .map(|output| Amount::from_sat(output.value)),
+    )?;
+    let funding = (1..tx.input.len())
+        .map(|i| {
+            psbt.spend_utxo(i)
+                .map(|prevout| Amount::from_sat(prevout.value))
+                .map_err(|_| Error::MissingOutput(tx.input[i].previous_output))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    outputs.checked_sub(checked_sum(funding)?).ok_or_else(|| {
+        Error::SellerPsbtMismatch("royalty funding exceeds the seller's outputs".to_string())
+    })
+}
+
+/// Checks that the seller PSBT lists exactly the advertised inscription: an
+/// input spending `inscription_utxo`, whose prevout is `inscription_tx_out`,
+/// paired with an output to the inscription's script, at most a royalty pair
+/// besides, and a [`listing_price`] of `price`.
+///
+/// The buyer sizes the carrier output and traces the inscription's sats with
+/// `inscription_tx_out`, so a listing understating its value would let part
+/// of the inscription spill past the carrier into the seller's payment.
+pub fn validate_seller_listing(
+    psbt: &Psbt,
+    inscription_utxo: OutPoint,
+    inscription_tx_out: &TxOut,
+    price: Amount,
+) -> Result<()> {
+    let tx = &psbt.unsigned_tx;
+    // the inscription's pair, optionally followed by a royalty's
+    if tx.input.is_empty() || tx.input.len() > 2 || tx.input.len() != tx.output.len() {
+        return Err(Error::SellerPsbtMismatch(format!(
+            "expected one or two input/output pairs, got {} inputs and {} outputs",
+            tx.input.len(),
+            tx.output.len()
+        )));
+    }
+    if tx.input[0].previous_output != inscription_utxo {
+        return Err(Error::SellerPsbtMismatch(format!(
+            "seller input spends {} instead of {}",
+            tx.input[0].previous_output, inscription_utxo
+        )));
+    }
+    let prevout = psbt.spend_utxo(0).map_err(|_| {
+        Error::SellerPsbtMismatch("seller psbt lacks the inscription's prevout".to_string())
+    })?;
+    if prevout != inscription_tx_out {
+        return Err(Error::SellerPsbtMismatch(format!(
+            "seller input spends {} sat instead of the listed inscription output's {} sat",
+            prevout.value, inscription_tx_out.value
+        )));
+    }
+    if tx.output[SELLER_PAYMENT_OUTPUT].script_pubkey != inscription_tx_out.script_pubkey {
+        return Err(Error::SellerPsbtMismatch(
+            "seller output does not pay the inscription's script".to_string(),
+        ));
+    }
+    let listing_price = listing_price(psbt)?;
+    if listing_price != price {
+        return Err(Error::SellerPsbtMismatch(format!(
+            "seller asks {} instead of {}",
+            listing_price, price
+        )));
+    }
+    Ok(())
+}
+
+/// Traces sat `inscription_sat_offset` of input `inscription_input_index`
+/// through `purchase_tx` in first-in first-out order and checks it lands in
+/// [`INSCRIPTION_CARRIER_OUTPUT`], the buyer's, and not in a seller payment.
+/// `prevouts[i]` is the output spent by input `i`; only those up to the
+/// inscription input are needed.
+/// Catches inputs added in front of the inscription shifting its sat into
+/// the seller's payment, or past every output into the fee.
+pub fn verify_sat_offset(
+    purchase_tx: &Transaction,
+    prevouts: &[TxOut],
+    inscription_input_index: usize,
+    inscription_sat_offset: u64,
+) -> Result<()> {
+    let misplaced = |landed| Error::InscriptionSatMisplaced {
+        input: inscription_input_index,
+        landed,
+    };
+    let inscription_input = prevouts
+        .get(inscription_input_index)
+        .filter(|_| inscription_input_index < purchase_tx.input.len())
+        .ok_or_else(|| misplaced(None))?;
+    if inscription_sat_offset >= inscription_input.value {
+        return Err(misplaced(None));
+    }
+    let preceding = checked_sum(
+        prevouts[..inscription_input_index]
+            .iter()
+            .map(|prevout| Amount::from_sat(prevout.value)),
+    )?;
+    let position = preceding
+        .to_sat()
+        .checked_add(inscription_sat_offset)
+        .ok_or(Error::Overflow)?;
+
+    let mut end: u64 = 0;
+    for (index, output) in purchase_tx.output.iter().enumerate() {
+        end = end.checked_add(output.value).ok_or(Error::Overflow)?;
+        if position < end {
+            return match index {
+                INSCRIPTION_CARRIER_OUTPUT => Ok(()),
+                _ => Err(misplaced(Some(index))),
+            };
+        }
+    }
+    Err(misplaced(None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::mock::{funding_tx, p2wpkh};
+
+    /// A purchase spending a 1000-sat dummy and a 10,000-sat inscription
+    /// output into a carrier of `carrier` sats and a 50,000-sat payment.
+    fn purchase(carrier: u64) -> (Transaction, Vec<TxOut>) {
+        let prevouts = funding_tx(10, &[(1_000, &p2wpkh(2)), (10_000, &p2wpkh(1))]).output;
+        let mut tx = funding_tx(
+            11,
+            &[
+                (carrier, &p2wpkh(2)),
+                (50_000, &p2wpkh(1)),
+                (5_000, &p2wpkh(2)),
+            ],
+        );
+        tx.input.push(tx.input[0].clone());
+        (tx, prevouts)
+    }
+
+    #[test]
+    fn sat_at_a_known_offset_reaches_the_carrier() {
+        let (tx, prevouts) = purchase(11_000);
+        // sat 4,000 of the inscription is sat 5,000 of the purchase
+        verify_sat_offset(&tx, &prevouts, 1, 4_000).unwrap();
+        verify_sat_offset(&tx, &prevouts, 1, 9_999).unwrap();
+
+        // a carrier just short of the inscription's last sat
+        let (tx, prevouts) = purchase(10_999);
+        verify_sat_offset(&tx, &prevouts, 1, 9_998).unwrap();
+        assert!(matches!(
+            verify_sat_offset(&tx, &prevouts, 1, 9_999),
+            Err(Error::InscriptionSatMisplaced {
+                input: 1,
+                landed: Some(1)
+            })
+        ));
+        assert!(matches!(
+            verify_sat_offset(&tx, &prevouts, 1, 10_000),
+            Err(Error::InscriptionSatMisplaced {
+                input: 1,
+                landed: None
+            })
+        ));
+    }
+}